@@ -0,0 +1,1171 @@
+use core::time::Duration;
+
+use heapless::Vec;
+
+use access_db::{CardRecord, Digest, Role};
+use uart_protocol::MainMessage;
+
+//Cards read within this long of an access decision are ignored, so a card left on the reader
+//doesn't immediately trigger another
+pub const HOLDOFF: Duration = Duration::from_secs(2);
+//How long the denied LED stays on for
+pub const DENIED_DISPLAY: Duration = Duration::from_secs(2);
+
+//Most actions a single input produces
+pub const MAX_ACTIONS: usize = 8;
+pub type Actions = Vec<Action, MAX_ACTIONS>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LatchMode {
+    Latching,        //Stays enabled until another card is read to sign out
+    Timed(Duration), //Stays enabled for <time>, then disables again
+    //Two different valid cards must be read within <window> - one with at least <supervisor>'s
+    //role, if set - then stays enabled as for Latching
+    TwoPerson {
+        window: Duration,
+        supervisor: Option<Role>,
+    },
+}
+
+//Which cards can sign out a latching session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SignOutPolicy {
+    AnyCard,                    //Any card - even an unknown one
+    SameCard,                   //Only the signed in card
+    SameCardOrSupervisor(Role), //The signed in card, or a valid card with at least this role
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub latch_mode: LatchMode,
+    pub sign_out: SignOutPolicy,
+    pub handover: bool, //Another valid card takes over a latching session, instead of signing out
+    pub max_session: Option<Duration>, //Latching sessions are ended after this long, if set
+    pub session_warning: Duration, //Warning given before max_session ends a session
+}
+
+//What the database said about a card - mirrors the database task's reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Found(Digest, CardRecord),
+    NotFound(Digest),
+    OutsideSchedule(Digest, CardRecord), //Known card, used outside its schedule
+}
+
+impl Lookup {
+    pub fn hash(&self) -> &Digest {
+        match self {
+            Lookup::Found(hash, _) | Lookup::NotFound(hash) | Lookup::OutsideSchedule(hash, _) => {
+                hash
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Timer {
+    Holdoff, //Card reads are ignored while this runs
+    Session, //Ends a timed session or two-person window, or warns of (then ends) max_session
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    CardPresented,       //A (non-admin) card was read
+    Lookup(Lookup),      //Reply to Action::Lookup
+    TimerExpired(Timer), //A timer started by Action::StartTimer ran out
+}
+
+//The status LEDs - one shows at a time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Led {
+    #[default]
+    Off,
+    Allowed,
+    Denied,
+    Warning,        //Flashing - the session is about to be ended
+    AwaitingSecond, //Allowed LED flashing - two-person mode, waiting for the second card
+}
+
+//Why a session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Reason {
+    SignedOut, //A card was read
+    Timeout,   //max_session reached
+    Handover,  //Another member took over the session
+}
+
+//Why a card was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Denial {
+    NotFound(Digest),
+    OutsideSchedule(Digest, CardRecord),
+    NoSupervisor(Digest, CardRecord), //Second card of a two-person pair, neither a supervisor
+}
+
+//Events to log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Activated(Digest, CardRecord),
+    TwoPersonActivated(Digest, CardRecord, Digest), //First card (and details), then second
+    Deactivated(Digest, CardRecord, Reason),
+    Denied(Denial),
+    SignOutRejected(Digest, Digest), //The card read, and the signed in card it couldn't sign out
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Lookup,      //Look the card up, and pass the reply back as Input::Lookup
+    Relay(bool), //Switch the relay on or off
+    Led(Led),
+    Remote(MainMessage), //Send to the remote cardreader, if there is one
+    Log(Event),
+    StartTimer(Timer, Duration), //(Re)start a timer - its expiry is passed back as an Input
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Idle,
+    Denied, //Showing the denied LED
+    Timed,  //Enabled, until the session timer runs out
+    //Two-person mode - the first card has been read, until the session timer runs out
+    AwaitingSecond {
+        hash: Digest,
+        record: CardRecord,
+    },
+    //Signed in - the card hash (and details) are kept for the log. Two-person sessions also
+    //keep the second card, which can sign out too. warning is set once the session is about
+    //to reach max_session
+    Latched {
+        hash: Digest,
+        record: CardRecord,
+        partner: Option<Digest>,
+        warning: bool,
+    },
+}
+
+pub struct AccessController {
+    config: Config,
+    state: State,
+    holdoff: bool, //Holdoff timer running
+    relay: bool,
+    led: Led,
+}
+
+impl AccessController {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            state: State::Idle,
+            holdoff: false,
+            relay: false,
+            led: Led::Off,
+        }
+    }
+
+    pub fn relay_on(&self) -> bool {
+        self.relay
+    }
+
+    //The LED that should be showing
+    pub fn led(&self) -> Led {
+        self.led
+    }
+
+    pub fn handle(&mut self, input: Input) -> Actions {
+        let mut actions = Actions::new();
+        match input {
+            Input::CardPresented => self.card_presented(&mut actions),
+            Input::Lookup(lookup) => self.card_looked_up(lookup, &mut actions),
+            Input::TimerExpired(timer) => self.timer_expired(timer, &mut actions),
+        }
+        actions
+    }
+
+    fn card_presented(&mut self, actions: &mut Actions) {
+        if self.holdoff {
+            return;
+        }
+        match self.state {
+            State::Idle | State::AwaitingSecond { .. } | State::Latched { .. } => {
+                push(actions, Action::Lookup)
+            }
+            //Timed sessions run their course, and the denied LED shows for its full time
+            State::Timed | State::Denied => {}
+        }
+    }
+
+    fn card_looked_up(&mut self, lookup: Lookup, actions: &mut Actions) {
+        match core::mem::replace(&mut self.state, State::Idle) {
+            State::Idle => match lookup {
+                Lookup::Found(hash, record) => match self.config.latch_mode {
+                    LatchMode::TwoPerson { window, .. } => {
+                        self.set_led(Led::AwaitingSecond, actions);
+                        push(actions, Action::Remote(MainMessage::AwaitingSecondCard));
+                        push(actions, Action::StartTimer(Timer::Session, window));
+                        self.state = State::AwaitingSecond { hash, record };
+                        self.start_holdoff(actions);
+                    }
+                    _ => self.grant(hash, record, actions),
+                },
+                lookup => self.deny(lookup, actions),
+            },
+            State::AwaitingSecond { hash, record } => match lookup {
+                //Still the first card
+                Lookup::Found(second, _) if second == hash => {
+                    self.state = State::AwaitingSecond { hash, record }
+                }
+                Lookup::Found(second, second_record) => {
+                    let supervised = match self.config.latch_mode {
+                        LatchMode::TwoPerson {
+                            supervisor: Some(role),
+                            ..
+                        } => record.role >= role || second_record.role >= role,
+                        _ => true,
+                    };
+                    if supervised {
+                        self.grant_two_person(hash, record, second, actions);
+                    } else {
+                        self.deny(Lookup::Found(second, second_record), actions);
+                    }
+                }
+                //An invalid second card ends the attempt
+                lookup => self.deny(lookup, actions),
+            },
+            //The signed in card, still valid, extends a session that's about to time out
+            State::Latched {
+                hash,
+                record,
+                partner,
+                warning: true,
+            } if matches!(&lookup, Lookup::Found(found, _)
+                if *found == hash || Some(*found) == partner) =>
+            {
+                self.set_led(Led::Allowed, actions);
+                push(actions, Action::Remote(MainMessage::AccessGranted));
+                self.start_session(actions);
+                self.state = State::Latched {
+                    hash,
+                    record,
+                    partner,
+                    warning: false,
+                };
+                self.start_holdoff(actions);
+            }
+            State::Latched {
+                hash,
+                record,
+                partner,
+                warning,
+            } => match lookup {
+                //Handed over to another member - the relay stays on. Two-person sessions can't
+                //be handed to one person
+                Lookup::Found(new_hash, new_record)
+                    if self.config.handover && partner.is_none() && new_hash != hash =>
+                {
+                    self.hand_over(hash, record, new_hash, new_record, actions)
+                }
+                lookup if self.may_sign_out(&hash, partner, &lookup) => {
+                    self.sign_out(hash, record, Reason::SignedOut, actions)
+                }
+                //The session carries on - the denied LED shows, then the session's again
+                lookup => {
+                    self.set_led(Led::Denied, actions);
+                    push(actions, Action::Remote(MainMessage::AccessDenied));
+                    push(
+                        actions,
+                        Action::Log(Event::SignOutRejected(*lookup.hash(), hash)),
+                    );
+                    self.holdoff = true;
+                    push(actions, Action::StartTimer(Timer::Holdoff, DENIED_DISPLAY));
+                    self.state = State::Latched {
+                        hash,
+                        record,
+                        partner,
+                        warning,
+                    };
+                }
+            },
+            //Nothing was looked up in these states
+            state => self.state = state,
+        }
+    }
+
+    fn timer_expired(&mut self, timer: Timer, actions: &mut Actions) {
+        match timer {
+            Timer::Holdoff => {
+                self.holdoff = false;
+                match self.state {
+                    State::Denied => {
+                        self.state = State::Idle;
+                        self.set_led(Led::Off, actions);
+                        push(actions, Action::Remote(MainMessage::AwaitingCard));
+                        self.start_holdoff(actions);
+                    }
+                    //A rejected sign out has been shown - the card may still be on the reader
+                    State::Latched { warning, .. } if self.led == Led::Denied => {
+                        let (led, message) = match warning {
+                            true => (Led::Warning, MainMessage::SessionExpiring),
+                            false => (Led::Allowed, MainMessage::AccessGranted),
+                        };
+                        self.set_led(led, actions);
+                        push(actions, Action::Remote(message));
+                        self.start_holdoff(actions);
+                    }
+                    _ => {}
+                }
+            }
+            Timer::Session => match core::mem::replace(&mut self.state, State::Idle) {
+                State::Timed => {
+                    self.end_session(actions);
+                    self.start_holdoff(actions);
+                }
+                //No second card in time - start again
+                State::AwaitingSecond { .. } => {
+                    self.set_led(Led::Off, actions);
+                    push(actions, Action::Remote(MainMessage::AwaitingCard));
+                }
+                //Without a max_session, this is a two-person window's timer left running
+                State::Latched {
+                    hash,
+                    record,
+                    partner,
+                    warning: false,
+                } if self.config.max_session.is_some() => {
+                    self.set_led(Led::Warning, actions);
+                    push(actions, Action::Remote(MainMessage::SessionExpiring));
+                    push(
+                        actions,
+                        Action::StartTimer(Timer::Session, self.config.session_warning),
+                    );
+                    self.state = State::Latched {
+                        hash,
+                        record,
+                        partner,
+                        warning: true,
+                    };
+                }
+                State::Latched {
+                    hash,
+                    record,
+                    warning: true,
+                    ..
+                } => self.sign_out(hash, record, Reason::Timeout, actions),
+                state => self.state = state,
+            },
+        }
+    }
+
+    fn grant(&mut self, hash: Digest, record: CardRecord, actions: &mut Actions) {
+        self.set_relay(true, actions);
+        self.set_led(Led::Allowed, actions);
+        push(actions, Action::Remote(MainMessage::AccessGranted));
+        push(actions, Action::Log(Event::Activated(hash, record.clone())));
+        match self.config.latch_mode {
+            LatchMode::Timed(time) => {
+                self.state = State::Timed;
+                push(actions, Action::StartTimer(Timer::Session, time));
+            }
+            _ => {
+                self.start_session(actions);
+                self.state = State::Latched {
+                    hash,
+                    record,
+                    partner: None,
+                    warning: false,
+                };
+                self.start_holdoff(actions);
+            }
+        }
+    }
+
+    fn grant_two_person(
+        &mut self,
+        hash: Digest,
+        record: CardRecord,
+        second: Digest,
+        actions: &mut Actions,
+    ) {
+        self.set_relay(true, actions);
+        self.set_led(Led::Allowed, actions);
+        push(actions, Action::Remote(MainMessage::AccessGranted));
+        push(
+            actions,
+            Action::Log(Event::TwoPersonActivated(hash, record.clone(), second)),
+        );
+        self.start_session(actions);
+        self.state = State::Latched {
+            hash,
+            record,
+            partner: Some(second),
+            warning: false,
+        };
+        self.start_holdoff(actions);
+    }
+
+    fn deny(&mut self, lookup: Lookup, actions: &mut Actions) {
+        let denial = match lookup {
+            Lookup::NotFound(hash) => Denial::NotFound(hash),
+            Lookup::OutsideSchedule(hash, record) => Denial::OutsideSchedule(hash, record),
+            //Only a valid card that can't complete a two-person pair is denied
+            Lookup::Found(hash, record) => Denial::NoSupervisor(hash, record),
+        };
+        self.state = State::Denied;
+        self.set_led(Led::Denied, actions);
+        push(actions, Action::Remote(MainMessage::AccessDenied));
+        push(actions, Action::Log(Event::Denied(denial)));
+        self.holdoff = true;
+        push(actions, Action::StartTimer(Timer::Holdoff, DENIED_DISPLAY));
+    }
+
+    //Whether the card looked up can end the signed in card's (or two-person pair's) session
+    fn may_sign_out(&self, signed_in: &Digest, partner: Option<Digest>, lookup: &Lookup) -> bool {
+        //The signed in card can always sign out, even if it's no longer valid
+        let same_card = lookup.hash() == signed_in || Some(*lookup.hash()) == partner;
+        match self.config.sign_out {
+            SignOutPolicy::AnyCard => true,
+            SignOutPolicy::SameCard => same_card,
+            SignOutPolicy::SameCardOrSupervisor(role) => {
+                same_card || matches!(lookup, Lookup::Found(_, record) if record.role >= role)
+            }
+        }
+    }
+
+    //Start the countdown to a latching session's warning, if it has a max_session
+    fn start_session(&mut self, actions: &mut Actions) {
+        if let Some(max_session) = self.config.max_session {
+            let until_warning = max_session.saturating_sub(self.config.session_warning);
+            push(actions, Action::StartTimer(Timer::Session, until_warning));
+        }
+    }
+
+    //End one member's session and start another's, without switching the relay off
+    fn hand_over(
+        &mut self,
+        hash: Digest,
+        record: CardRecord,
+        new_hash: Digest,
+        new_record: CardRecord,
+        actions: &mut Actions,
+    ) {
+        self.set_led(Led::Allowed, actions);
+        push(actions, Action::Remote(MainMessage::AccessGranted));
+        push(
+            actions,
+            Action::Log(Event::Deactivated(hash, record, Reason::Handover)),
+        );
+        push(
+            actions,
+            Action::Log(Event::Activated(new_hash, new_record.clone())),
+        );
+        self.start_session(actions);
+        self.state = State::Latched {
+            hash: new_hash,
+            record: new_record,
+            partner: None,
+            warning: false,
+        };
+        self.start_holdoff(actions);
+    }
+
+    fn sign_out(
+        &mut self,
+        hash: Digest,
+        record: CardRecord,
+        reason: Reason,
+        actions: &mut Actions,
+    ) {
+        self.end_session(actions);
+        push(
+            actions,
+            Action::Log(Event::Deactivated(hash, record, reason)),
+        );
+        self.start_holdoff(actions);
+    }
+
+    fn end_session(&mut self, actions: &mut Actions) {
+        self.set_relay(false, actions);
+        self.set_led(Led::Off, actions);
+        push(actions, Action::Remote(MainMessage::AwaitingCard));
+    }
+
+    fn start_holdoff(&mut self, actions: &mut Actions) {
+        self.holdoff = true;
+        push(actions, Action::StartTimer(Timer::Holdoff, HOLDOFF));
+    }
+
+    fn set_relay(&mut self, on: bool, actions: &mut Actions) {
+        self.relay = on;
+        push(actions, Action::Relay(on));
+    }
+
+    fn set_led(&mut self, led: Led, actions: &mut Actions) {
+        self.led = led;
+        push(actions, Action::Led(led));
+    }
+}
+
+fn push(actions: &mut Actions, action: Action) {
+    //MAX_ACTIONS covers the most any input produces
+    assert!(actions.push(action).is_ok(), "Too many actions");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMED: Duration = Duration::from_secs(5);
+    const MAX_SESSION: Duration = Duration::from_secs(3600);
+    const WARNING: Duration = Duration::from_secs(60);
+
+    fn hash(n: u8) -> Digest {
+        [n; 16]
+    }
+
+    fn record(member: &str) -> CardRecord {
+        CardRecord {
+            member: member.into(),
+            ..Default::default()
+        }
+    }
+
+    fn controller(latch_mode: LatchMode) -> AccessController {
+        AccessController::new(Config {
+            latch_mode,
+            sign_out: SignOutPolicy::AnyCard,
+            handover: false,
+            max_session: None,
+            session_warning: WARNING,
+        })
+    }
+
+    fn limited_controller() -> AccessController {
+        AccessController::new(Config {
+            latch_mode: LatchMode::Latching,
+            sign_out: SignOutPolicy::AnyCard,
+            handover: false,
+            max_session: Some(MAX_SESSION),
+            session_warning: WARNING,
+        })
+    }
+
+    //Sign in to a latching controller with the given sign out policy
+    fn signed_in_controller(sign_out: SignOutPolicy) -> AccessController {
+        let mut controller = AccessController::new(Config {
+            latch_mode: LatchMode::Latching,
+            sign_out,
+            handover: false,
+            max_session: None,
+            session_warning: WARNING,
+        });
+        present(&mut controller, Lookup::Found(hash(1), record("alice")));
+        expire(&mut controller, Timer::Holdoff);
+        controller
+    }
+
+    fn supervisor(member: &str) -> CardRecord {
+        CardRecord {
+            role: Role::Maintainer,
+            ..record(member)
+        }
+    }
+
+    //Sign in with a limited session, running down to its warning
+    fn warned_controller() -> AccessController {
+        let mut controller = limited_controller();
+        present(&mut controller, Lookup::Found(hash(1), record("alice")));
+        expire(&mut controller, Timer::Holdoff);
+        assert_eq!(
+            expire(&mut controller, Timer::Session).as_slice(),
+            &[
+                Action::Led(Led::Warning),
+                Action::Remote(MainMessage::SessionExpiring),
+                Action::StartTimer(Timer::Session, WARNING),
+            ]
+        );
+        controller
+    }
+
+    //Present a card, and answer the lookup it asks for
+    fn present(controller: &mut AccessController, lookup: Lookup) -> Actions {
+        assert_eq!(
+            controller.handle(Input::CardPresented).as_slice(),
+            &[Action::Lookup]
+        );
+        controller.handle(Input::Lookup(lookup))
+    }
+
+    fn expire(controller: &mut AccessController, timer: Timer) -> Actions {
+        controller.handle(Input::TimerExpired(timer))
+    }
+
+    #[test]
+    fn latching_signs_in_and_out() {
+        let mut controller = controller(LatchMode::Latching);
+        assert_eq!(
+            present(&mut controller, Lookup::Found(hash(1), record("alice"))).as_slice(),
+            &[
+                Action::Relay(true),
+                Action::Led(Led::Allowed),
+                Action::Remote(MainMessage::AccessGranted),
+                Action::Log(Event::Activated(hash(1), record("alice"))),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        assert!(controller.relay_on());
+        assert!(expire(&mut controller, Timer::Holdoff).is_empty());
+
+        //Any card signs out - and the log names who was signed in
+        assert_eq!(
+            present(&mut controller, Lookup::NotFound(hash(2))).as_slice(),
+            &[
+                Action::Relay(false),
+                Action::Led(Led::Off),
+                Action::Remote(MainMessage::AwaitingCard),
+                Action::Log(Event::Deactivated(
+                    hash(1),
+                    record("alice"),
+                    Reason::SignedOut
+                )),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        assert!(!controller.relay_on());
+        assert_eq!(controller.led(), Led::Off);
+    }
+
+    #[test]
+    fn max_session_warns_then_times_out() {
+        let mut controller = limited_controller();
+        assert_eq!(
+            present(&mut controller, Lookup::Found(hash(1), record("alice"))).as_slice(),
+            &[
+                Action::Relay(true),
+                Action::Led(Led::Allowed),
+                Action::Remote(MainMessage::AccessGranted),
+                Action::Log(Event::Activated(hash(1), record("alice"))),
+                Action::StartTimer(Timer::Session, MAX_SESSION - WARNING),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        expire(&mut controller, Timer::Holdoff);
+
+        let mut controller = warned_controller();
+        assert!(controller.relay_on());
+        assert_eq!(controller.led(), Led::Warning);
+        assert_eq!(
+            expire(&mut controller, Timer::Session).as_slice(),
+            &[
+                Action::Relay(false),
+                Action::Led(Led::Off),
+                Action::Remote(MainMessage::AwaitingCard),
+                Action::Log(Event::Deactivated(
+                    hash(1),
+                    record("alice"),
+                    Reason::Timeout
+                )),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        assert!(!controller.relay_on());
+    }
+
+    #[test]
+    fn same_card_extends_a_warned_session() {
+        let mut controller = warned_controller();
+        assert_eq!(
+            present(&mut controller, Lookup::Found(hash(1), record("alice"))).as_slice(),
+            &[
+                Action::Led(Led::Allowed),
+                Action::Remote(MainMessage::AccessGranted),
+                Action::StartTimer(Timer::Session, MAX_SESSION - WARNING),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        assert!(controller.relay_on());
+        expire(&mut controller, Timer::Holdoff);
+
+        //The extended session warns again, rather than timing out
+        assert_eq!(
+            expire(&mut controller, Timer::Session).as_slice(),
+            &[
+                Action::Led(Led::Warning),
+                Action::Remote(MainMessage::SessionExpiring),
+                Action::StartTimer(Timer::Session, WARNING),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_cards_sign_out_a_warned_session() {
+        for lookup in [
+            Lookup::Found(hash(2), record("bob")),
+            Lookup::NotFound(hash(2)),
+            //The signed in card, no longer valid
+            Lookup::OutsideSchedule(hash(1), record("alice")),
+        ] {
+            let mut controller = warned_controller();
+            let actions = present(&mut controller, lookup);
+            assert!(actions.contains(&Action::Log(Event::Deactivated(
+                hash(1),
+                record("alice"),
+                Reason::SignedOut
+            ))));
+            assert!(!controller.relay_on());
+        }
+    }
+
+    #[test]
+    fn same_card_signs_out_before_the_warning() {
+        let mut controller = limited_controller();
+        present(&mut controller, Lookup::Found(hash(1), record("alice")));
+        expire(&mut controller, Timer::Holdoff);
+        let actions = present(&mut controller, Lookup::Found(hash(1), record("alice")));
+        assert!(actions.contains(&Action::Relay(false)));
+
+        //The session's timer is stale once signed out
+        assert!(expire(&mut controller, Timer::Session).is_empty());
+    }
+
+    #[test]
+    fn timed_logs_activation_when_granted() {
+        let mut controller = controller(LatchMode::Timed(TIMED));
+        assert_eq!(
+            present(&mut controller, Lookup::Found(hash(1), record("bob"))).as_slice(),
+            &[
+                Action::Relay(true),
+                Action::Led(Led::Allowed),
+                Action::Remote(MainMessage::AccessGranted),
+                Action::Log(Event::Activated(hash(1), record("bob"))),
+                Action::StartTimer(Timer::Session, TIMED),
+            ]
+        );
+
+        //Reads during the session are ignored
+        assert!(controller.handle(Input::CardPresented).is_empty());
+
+        assert_eq!(
+            expire(&mut controller, Timer::Session).as_slice(),
+            &[
+                Action::Relay(false),
+                Action::Led(Led::Off),
+                Action::Remote(MainMessage::AwaitingCard),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        assert!(controller.handle(Input::CardPresented).is_empty());
+        assert!(expire(&mut controller, Timer::Holdoff).is_empty());
+        assert_eq!(
+            controller.handle(Input::CardPresented).as_slice(),
+            &[Action::Lookup]
+        );
+    }
+
+    #[test]
+    fn denies_unknown_and_out_of_schedule_cards() {
+        for latch_mode in [LatchMode::Latching, LatchMode::Timed(TIMED)] {
+            for (lookup, denial) in [
+                (Lookup::NotFound(hash(3)), Denial::NotFound(hash(3))),
+                (
+                    Lookup::OutsideSchedule(hash(4), record("carol")),
+                    Denial::OutsideSchedule(hash(4), record("carol")),
+                ),
+            ] {
+                let mut controller = controller(latch_mode);
+                assert_eq!(
+                    present(&mut controller, lookup).as_slice(),
+                    &[
+                        Action::Led(Led::Denied),
+                        Action::Remote(MainMessage::AccessDenied),
+                        Action::Log(Event::Denied(denial)),
+                        Action::StartTimer(Timer::Holdoff, DENIED_DISPLAY),
+                    ]
+                );
+                assert!(!controller.relay_on());
+                assert_eq!(controller.led(), Led::Denied);
+                assert!(controller.handle(Input::CardPresented).is_empty());
+
+                //The LED goes out, then the usual holdoff
+                assert_eq!(
+                    expire(&mut controller, Timer::Holdoff).as_slice(),
+                    &[
+                        Action::Led(Led::Off),
+                        Action::Remote(MainMessage::AwaitingCard),
+                        Action::StartTimer(Timer::Holdoff, HOLDOFF),
+                    ]
+                );
+                assert!(controller.handle(Input::CardPresented).is_empty());
+                assert!(expire(&mut controller, Timer::Holdoff).is_empty());
+                assert_eq!(
+                    controller.handle(Input::CardPresented).as_slice(),
+                    &[Action::Lookup]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn holdoff_ignores_reads_after_sign_in() {
+        let mut controller = controller(LatchMode::Latching);
+        present(&mut controller, Lookup::Found(hash(1), record("alice")));
+        //The same card, still on the reader, doesn't sign straight back out
+        assert!(controller.handle(Input::CardPresented).is_empty());
+        assert!(controller.relay_on());
+    }
+
+    #[test]
+    fn same_card_policy_only_lets_the_signed_in_card_sign_out() {
+        for lookup in [
+            Lookup::Found(hash(2), record("bob")),
+            Lookup::Found(hash(2), supervisor("bob")),
+            Lookup::NotFound(hash(2)),
+        ] {
+            let mut controller = signed_in_controller(SignOutPolicy::SameCard);
+            assert_eq!(
+                present(&mut controller, lookup).as_slice(),
+                &[
+                    Action::Led(Led::Denied),
+                    Action::Remote(MainMessage::AccessDenied),
+                    Action::Log(Event::SignOutRejected(hash(2), hash(1))),
+                    Action::StartTimer(Timer::Holdoff, DENIED_DISPLAY),
+                ]
+            );
+            assert!(controller.relay_on());
+
+            //Back to showing the session
+            assert_eq!(
+                expire(&mut controller, Timer::Holdoff).as_slice(),
+                &[
+                    Action::Led(Led::Allowed),
+                    Action::Remote(MainMessage::AccessGranted),
+                    Action::StartTimer(Timer::Holdoff, HOLDOFF),
+                ]
+            );
+        }
+
+        //Even if the signed in card is no longer valid
+        let mut controller = signed_in_controller(SignOutPolicy::SameCard);
+        let actions = present(&mut controller, Lookup::NotFound(hash(1)));
+        assert!(actions.contains(&Action::Log(Event::Deactivated(
+            hash(1),
+            record("alice"),
+            Reason::SignedOut
+        ))));
+        assert!(!controller.relay_on());
+    }
+
+    #[test]
+    fn holdoff_ignores_reads_after_a_rejected_sign_out() {
+        let mut controller = signed_in_controller(SignOutPolicy::SameCard);
+        present(&mut controller, Lookup::Found(hash(2), record("bob")));
+        assert!(controller.handle(Input::CardPresented).is_empty());
+        expire(&mut controller, Timer::Holdoff);
+        //The rejected card, still on the reader, isn't looked up (and logged) again straight away
+        assert!(controller.handle(Input::CardPresented).is_empty());
+        assert!(expire(&mut controller, Timer::Holdoff).is_empty());
+        assert_eq!(
+            controller.handle(Input::CardPresented).as_slice(),
+            &[Action::Lookup]
+        );
+        assert!(controller.relay_on());
+    }
+
+    #[test]
+    fn supervisors_can_sign_out() {
+        let policy = SignOutPolicy::SameCardOrSupervisor(Role::Inductor);
+        for (lookup, allowed) in [
+            (Lookup::Found(hash(1), record("alice")), true),
+            (Lookup::Found(hash(2), supervisor("carol")), true),
+            (
+                Lookup::Found(
+                    hash(2),
+                    CardRecord {
+                        role: Role::Inductor,
+                        ..record("carol")
+                    },
+                ),
+                true,
+            ),
+            (Lookup::Found(hash(2), record("bob")), false),
+            //Supervisors' cards must still be valid
+            (Lookup::OutsideSchedule(hash(2), supervisor("carol")), false),
+        ] {
+            let mut controller = signed_in_controller(policy);
+            present(&mut controller, lookup);
+            assert_eq!(controller.relay_on(), !allowed);
+        }
+    }
+
+    #[test]
+    fn rejected_sign_out_during_the_warning_keeps_warning() {
+        let mut controller = AccessController::new(Config {
+            latch_mode: LatchMode::Latching,
+            sign_out: SignOutPolicy::SameCard,
+            handover: false,
+            max_session: Some(MAX_SESSION),
+            session_warning: WARNING,
+        });
+        present(&mut controller, Lookup::Found(hash(1), record("alice")));
+        expire(&mut controller, Timer::Holdoff);
+        expire(&mut controller, Timer::Session);
+
+        let actions = present(&mut controller, Lookup::Found(hash(2), record("bob")));
+        assert!(actions.contains(&Action::Log(Event::SignOutRejected(hash(2), hash(1)))));
+        assert_eq!(
+            expire(&mut controller, Timer::Holdoff).as_slice(),
+            &[
+                Action::Led(Led::Warning),
+                Action::Remote(MainMessage::SessionExpiring),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        //...and still times out
+        let actions = expire(&mut controller, Timer::Session);
+        assert!(actions.contains(&Action::Log(Event::Deactivated(
+            hash(1),
+            record("alice"),
+            Reason::Timeout
+        ))));
+    }
+
+    fn handover_controller(sign_out: SignOutPolicy) -> AccessController {
+        let mut controller = AccessController::new(Config {
+            latch_mode: LatchMode::Latching,
+            sign_out,
+            handover: true,
+            max_session: Some(MAX_SESSION),
+            session_warning: WARNING,
+        });
+        present(&mut controller, Lookup::Found(hash(1), record("alice")));
+        expire(&mut controller, Timer::Holdoff);
+        controller
+    }
+
+    #[test]
+    fn handover_keeps_the_relay_on() {
+        let mut controller = handover_controller(SignOutPolicy::SameCard);
+        assert_eq!(
+            present(&mut controller, Lookup::Found(hash(2), record("bob"))).as_slice(),
+            &[
+                Action::Led(Led::Allowed),
+                Action::Remote(MainMessage::AccessGranted),
+                Action::Log(Event::Deactivated(
+                    hash(1),
+                    record("alice"),
+                    Reason::Handover
+                )),
+                Action::Log(Event::Activated(hash(2), record("bob"))),
+                Action::StartTimer(Timer::Session, MAX_SESSION - WARNING),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        assert!(controller.relay_on());
+        expire(&mut controller, Timer::Holdoff);
+
+        //The session is now bob's - alice can't sign out, bob can
+        let actions = present(&mut controller, Lookup::NotFound(hash(1)));
+        assert!(actions.contains(&Action::Log(Event::SignOutRejected(hash(1), hash(2)))));
+        expire(&mut controller, Timer::Holdoff);
+        expire(&mut controller, Timer::Holdoff);
+        let actions = present(&mut controller, Lookup::Found(hash(2), record("bob")));
+        assert!(actions.contains(&Action::Log(Event::Deactivated(
+            hash(2),
+            record("bob"),
+            Reason::SignedOut
+        ))));
+        assert!(!controller.relay_on());
+    }
+
+    #[test]
+    fn handover_during_the_warning_starts_a_new_session() {
+        let mut controller = handover_controller(SignOutPolicy::AnyCard);
+        expire(&mut controller, Timer::Session);
+        assert_eq!(controller.led(), Led::Warning);
+        let actions = present(&mut controller, Lookup::Found(hash(2), record("bob")));
+        assert!(actions.contains(&Action::Log(Event::Activated(hash(2), record("bob")))));
+        assert_eq!(controller.led(), Led::Allowed);
+        expire(&mut controller, Timer::Holdoff);
+
+        //A full session before the next warning
+        assert_eq!(
+            expire(&mut controller, Timer::Session).as_slice(),
+            &[
+                Action::Led(Led::Warning),
+                Action::Remote(MainMessage::SessionExpiring),
+                Action::StartTimer(Timer::Session, WARNING),
+            ]
+        );
+    }
+
+    #[test]
+    fn handover_needs_a_valid_card() {
+        //Invalid cards fall back to the sign out policy
+        let mut controller = handover_controller(SignOutPolicy::AnyCard);
+        let actions = present(&mut controller, Lookup::NotFound(hash(2)));
+        assert!(actions.contains(&Action::Log(Event::Deactivated(
+            hash(1),
+            record("alice"),
+            Reason::SignedOut
+        ))));
+        assert!(!controller.relay_on());
+
+        let mut controller = handover_controller(SignOutPolicy::SameCard);
+        let actions = present(
+            &mut controller,
+            Lookup::OutsideSchedule(hash(2), record("bob")),
+        );
+        assert!(actions.contains(&Action::Log(Event::SignOutRejected(hash(2), hash(1)))));
+        assert!(controller.relay_on());
+
+        //And the signed in card still signs out
+        let mut controller = handover_controller(SignOutPolicy::SameCard);
+        present(&mut controller, Lookup::Found(hash(1), record("alice")));
+        assert!(!controller.relay_on());
+    }
+
+    const WINDOW: Duration = Duration::from_secs(30);
+
+    fn two_person_controller(supervisor: Option<Role>) -> AccessController {
+        AccessController::new(Config {
+            latch_mode: LatchMode::TwoPerson {
+                window: WINDOW,
+                supervisor,
+            },
+            sign_out: SignOutPolicy::SameCard,
+            handover: true,
+            max_session: None,
+            session_warning: WARNING,
+        })
+    }
+
+    //Read the first card of a pair
+    fn first_card(controller: &mut AccessController, lookup: Lookup) {
+        assert_eq!(
+            present(controller, lookup).as_slice(),
+            &[
+                Action::Led(Led::AwaitingSecond),
+                Action::Remote(MainMessage::AwaitingSecondCard),
+                Action::StartTimer(Timer::Session, WINDOW),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        assert!(!controller.relay_on());
+        expire(controller, Timer::Holdoff);
+    }
+
+    #[test]
+    fn two_person_needs_two_cards() {
+        let mut controller = two_person_controller(None);
+        first_card(&mut controller, Lookup::Found(hash(1), record("alice")));
+
+        //The first card again doesn't count
+        assert!(present(&mut controller, Lookup::Found(hash(1), record("alice"))).is_empty());
+        assert!(!controller.relay_on());
+
+        assert_eq!(
+            present(&mut controller, Lookup::Found(hash(2), record("bob"))).as_slice(),
+            &[
+                Action::Relay(true),
+                Action::Led(Led::Allowed),
+                Action::Remote(MainMessage::AccessGranted),
+                Action::Log(Event::TwoPersonActivated(hash(1), record("alice"), hash(2))),
+                Action::StartTimer(Timer::Holdoff, HOLDOFF),
+            ]
+        );
+        expire(&mut controller, Timer::Holdoff);
+
+        //The window's timer running out doesn't affect the session
+        assert!(expire(&mut controller, Timer::Session).is_empty());
+        assert!(controller.relay_on());
+
+        //Neither member can hand the session to a third, but either can sign out
+        let actions = present(&mut controller, Lookup::Found(hash(3), record("carol")));
+        assert!(actions.contains(&Action::Log(Event::SignOutRejected(hash(3), hash(1)))));
+        expire(&mut controller, Timer::Holdoff);
+        expire(&mut controller, Timer::Holdoff);
+        let actions = present(&mut controller, Lookup::Found(hash(2), record("bob")));
+        assert!(actions.contains(&Action::Log(Event::Deactivated(
+            hash(1),
+            record("alice"),
+            Reason::SignedOut
+        ))));
+        assert!(!controller.relay_on());
+    }
+
+    #[test]
+    fn two_person_window_runs_out() {
+        let mut controller = two_person_controller(None);
+        first_card(&mut controller, Lookup::Found(hash(1), record("alice")));
+        assert_eq!(
+            expire(&mut controller, Timer::Session).as_slice(),
+            &[
+                Action::Led(Led::Off),
+                Action::Remote(MainMessage::AwaitingCard),
+            ]
+        );
+
+        //bob is now the first card of a new pair
+        first_card(&mut controller, Lookup::Found(hash(2), record("bob")));
+    }
+
+    #[test]
+    fn two_person_invalid_cards_are_denied() {
+        let mut controller = two_person_controller(None);
+        present(&mut controller, Lookup::NotFound(hash(1)));
+        assert_eq!(controller.led(), Led::Denied);
+
+        let mut controller = two_person_controller(None);
+        first_card(&mut controller, Lookup::Found(hash(1), record("alice")));
+        assert_eq!(
+            present(&mut controller, Lookup::NotFound(hash(2))).as_slice(),
+            &[
+                Action::Led(Led::Denied),
+                Action::Remote(MainMessage::AccessDenied),
+                Action::Log(Event::Denied(Denial::NotFound(hash(2)))),
+                Action::StartTimer(Timer::Holdoff, DENIED_DISPLAY),
+            ]
+        );
+        assert!(!controller.relay_on());
+    }
+
+    #[test]
+    fn two_person_with_a_supervisor() {
+        for (first, second, allowed) in [
+            (record("alice"), supervisor("bob"), true),
+            (supervisor("alice"), record("bob"), true),
+            (record("alice"), record("bob"), false),
+        ] {
+            let mut controller = two_person_controller(Some(Role::Inductor));
+            first_card(&mut controller, Lookup::Found(hash(1), first));
+            let actions = present(&mut controller, Lookup::Found(hash(2), second.clone()));
+            assert_eq!(controller.relay_on(), allowed);
+            if !allowed {
+                assert!(
+                    actions.contains(&Action::Log(Event::Denied(Denial::NoSupervisor(
+                        hash(2),
+                        second
+                    ))))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_stray_inputs() {
+        let mut latching = controller(LatchMode::Latching);
+        assert!(expire(&mut latching, Timer::Session).is_empty());
+        assert!(expire(&mut latching, Timer::Holdoff).is_empty());
+
+        let mut timed = controller(LatchMode::Timed(TIMED));
+        present(&mut timed, Lookup::Found(hash(1), record("bob")));
+        assert!(expire(&mut timed, Timer::Holdoff).is_empty());
+        assert!(timed
+            .handle(Input::Lookup(Lookup::NotFound(hash(2))))
+            .is_empty());
+        assert!(timed.relay_on());
+    }
+}
@@ -0,0 +1,13 @@
+#![no_std]
+
+//The controller's access decisions - what happens to the relay, LEDs, remote reader and log
+//when a card is read, looked up, or a timer runs out - as a state machine that main_task
+//drives. Nothing in here touches hardware (or waits on anything), so `cargo test` runs it on
+//the host.
+
+mod controller;
+
+pub use controller::{
+    AccessController, Action, Actions, Config, Denial, Event, Input, LatchMode, Led, Lookup,
+    Reason, SignOutPolicy, Timer, DENIED_DISPLAY, HOLDOFF, MAX_ACTIONS,
+};
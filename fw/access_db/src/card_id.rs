@@ -0,0 +1,236 @@
+//How card UIDs are turned into the digests used as database keys.
+//
+//UIDs are only 4-10 bytes, so a bare hash of one can be brute-forced by anyone holding the
+//database. The server says which algorithms it uses via the DB version endpoint:
+//  <version>[ <algorithm>[,<algorithm>...][ <serial>]]
+//e.g. "<24 character version> hmac-sha256,md5 1718000000". Algorithms are listed in order of
+//preference, and a card is looked up under each in turn - listing more than one allows a
+//migration period in which the database holds both old and new style keys. A bare version means
+//md5 only.
+//
+//The serial is a decimal u64 that increases with every database the server publishes (a unix
+//timestamp will do). It is covered by the download's signature, so a device holding a signed
+//database can refuse an older one being replayed to it.
+//
+//Every algorithm produces a 16 byte digest (the SHA-256 based ones are truncated), so the
+//database format is unchanged. The hashing itself is done by the firmware.
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{ParseError, RecordError};
+
+//Length of the DB version tag
+pub const VERSION_LEN: usize = 24;
+pub const MAX_CARD_ID_ALGORITHMS: usize = 3;
+//Largest encoded algorithm list
+pub const MAX_CARD_IDS_LEN: usize = MAX_CARD_ID_ALGORITHMS + 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CardIdAlgorithm {
+    Md5,        //MD5(uid) - the original scheme
+    Sha256,     //SHA-256(salt || uid), truncated
+    HmacSha256, //HMAC-SHA256(site secret, uid), truncated
+}
+
+impl CardIdAlgorithm {
+    //Name as used by the server
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CardIdAlgorithm::Md5 => "md5",
+            CardIdAlgorithm::Sha256 => "sha256",
+            CardIdAlgorithm::HmacSha256 => "hmac-sha256",
+        }
+    }
+
+    fn parse(field: &[u8]) -> Result<Self, ParseError> {
+        match field {
+            b"md5" => Ok(CardIdAlgorithm::Md5),
+            b"sha256" => Ok(CardIdAlgorithm::Sha256),
+            b"hmac-sha256" => Ok(CardIdAlgorithm::HmacSha256),
+            _ => Err(ParseError::InvalidCardId),
+        }
+    }
+}
+
+//Accepted algorithms, most preferred first
+pub type CardIds = Vec<CardIdAlgorithm, MAX_CARD_ID_ALGORITHMS>;
+
+//Algorithms used by databases that predate negotiation
+pub fn legacy_card_ids() -> CardIds {
+    let mut card_ids = CardIds::new();
+    card_ids.push(CardIdAlgorithm::Md5).ok();
+    card_ids
+}
+
+//Parse a comma separated algorithm list, e.g. "hmac-sha256,md5"
+pub fn parse_card_ids(field: &[u8]) -> Result<CardIds, ParseError> {
+    let mut card_ids = CardIds::new();
+    for name in field.split(|&byte| byte == b',') {
+        let algorithm = CardIdAlgorithm::parse(name)?;
+        if card_ids.contains(&algorithm) {
+            return Err(ParseError::InvalidCardId);
+        }
+        card_ids
+            .push(algorithm)
+            .map_err(|_| ParseError::InvalidCardId)?;
+    }
+    Ok(card_ids)
+}
+
+//The DB version endpoint's reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteVersion {
+    pub version: [u8; VERSION_LEN],
+    pub card_ids: CardIds,
+    pub serial: Option<u64>,
+}
+
+impl RemoteVersion {
+    //Whether this database replaces one stored with the given serial - only if strictly newer.
+    //Anything replaces a database stored without one, and a version without one replaces nothing
+    pub fn is_newer(&self, stored: Option<u64>) -> bool {
+        match (self.serial, stored) {
+            (Some(serial), Some(stored)) => serial > stored,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+fn parse_serial(field: &[u8]) -> Result<u64, ParseError> {
+    if let Some(&byte) = field.iter().find(|byte| !byte.is_ascii_digit()) {
+        return Err(ParseError::InvalidCharacter(byte));
+    }
+    core::str::from_utf8(field)
+        .ok()
+        .and_then(|field| field.parse().ok())
+        .ok_or(ParseError::InvalidSerial)
+}
+
+pub fn parse_version(body: &[u8]) -> Result<RemoteVersion, ParseError> {
+    let mut fields = body.trim_ascii().split(|byte| byte.is_ascii_whitespace());
+    let version = fields.next().unwrap_or_default();
+    let version = version
+        .try_into()
+        .map_err(|_| ParseError::InvalidLength(version.len()))?;
+    let card_ids = match fields.find(|field| !field.is_empty()) {
+        Some(field) => parse_card_ids(field)?,
+        None => legacy_card_ids(),
+    };
+    let serial = match fields.find(|field| !field.is_empty()) {
+        Some(field) => Some(parse_serial(field)?),
+        None => None,
+    };
+    if fields.any(|field| !field.is_empty()) {
+        return Err(ParseError::InvalidSerial);
+    }
+    Ok(RemoteVersion {
+        version,
+        card_ids,
+        serial,
+    })
+}
+
+//The algorithm list is stored in flash postcard encoded, alongside the database version
+pub fn encode_card_ids<'a>(
+    card_ids: &CardIds,
+    buf: &'a mut [u8],
+) -> Result<&'a mut [u8], RecordError> {
+    postcard::to_slice(card_ids, buf).map_err(|_| RecordError::Encode)
+}
+
+pub fn decode_card_ids(buf: &[u8]) -> Result<CardIds, RecordError> {
+    match postcard::from_bytes::<CardIds>(buf) {
+        Ok(card_ids) if !card_ids.is_empty() => Ok(card_ids),
+        _ => Err(RecordError::Decode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSION: &[u8; VERSION_LEN] = b"1B2M2Y8AsgTpgAmY7PhCfg==";
+
+    #[test]
+    fn bare_version_is_md5() {
+        let remote = parse_version(VERSION).unwrap();
+        assert_eq!(&remote.version, VERSION);
+        assert_eq!(remote.card_ids, legacy_card_ids());
+        assert_eq!(
+            parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg==\n").unwrap(),
+            remote
+        );
+    }
+
+    #[test]
+    fn parses_algorithms_in_order() {
+        let remote = parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== hmac-sha256,md5\n").unwrap();
+        assert_eq!(
+            remote.card_ids.as_slice(),
+            &[CardIdAlgorithm::HmacSha256, CardIdAlgorithm::Md5]
+        );
+        let remote = parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg==\nsha256").unwrap();
+        assert_eq!(remote.card_ids.as_slice(), &[CardIdAlgorithm::Sha256]);
+    }
+
+    #[test]
+    fn rejects_invalid_versions() {
+        assert_eq!(parse_version(b"short"), Err(ParseError::InvalidLength(5)));
+        assert_eq!(parse_version(b""), Err(ParseError::InvalidLength(0)));
+        assert_eq!(
+            parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== sha1"),
+            Err(ParseError::InvalidCardId)
+        );
+        assert_eq!(
+            parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== md5,md5"),
+            Err(ParseError::InvalidCardId)
+        );
+        assert_eq!(
+            parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== md5,"),
+            Err(ParseError::InvalidCardId)
+        );
+        assert_eq!(
+            parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== md5 extra"),
+            Err(ParseError::InvalidCharacter(b'e'))
+        );
+        assert_eq!(
+            parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== md5 18446744073709551616"),
+            Err(ParseError::InvalidSerial)
+        );
+        assert_eq!(
+            parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== md5 1 2"),
+            Err(ParseError::InvalidSerial)
+        );
+    }
+
+    #[test]
+    fn parses_the_serial() {
+        let remote = parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== md5 1718000000\n").unwrap();
+        assert_eq!(remote.card_ids, legacy_card_ids());
+        assert_eq!(remote.serial, Some(1718000000));
+        assert_eq!(parse_version(VERSION).unwrap().serial, None);
+    }
+
+    #[test]
+    fn only_newer_serials_replace_the_database() {
+        let mut remote = parse_version(b"1B2M2Y8AsgTpgAmY7PhCfg== md5 5").unwrap();
+        assert!(remote.is_newer(None));
+        assert!(remote.is_newer(Some(4)));
+        assert!(!remote.is_newer(Some(5)));
+        assert!(!remote.is_newer(Some(6)));
+        remote.serial = None;
+        assert!(!remote.is_newer(None));
+    }
+
+    #[test]
+    fn card_ids_round_trip() {
+        let card_ids = parse_card_ids(b"hmac-sha256,sha256,md5").unwrap();
+        let mut buf = [0x00u8; MAX_CARD_IDS_LEN];
+        let encoded = encode_card_ids(&card_ids, &mut buf).unwrap();
+        assert_eq!(decode_card_ids(encoded), Ok(card_ids));
+        assert_eq!(decode_card_ids(&[0x00]), Err(RecordError::Decode));
+    }
+}
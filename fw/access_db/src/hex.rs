@@ -0,0 +1,85 @@
+//Conversion between the ascii hex hashes used by the server, and the binary digests stored in flash
+
+use crate::{Digest, HexDigest, ParseError, HEX_DIGEST_LEN};
+
+fn nibble(byte: u8) -> Result<u8, ParseError> {
+    match byte {
+        b'0'..=b'9' => Ok(byte - b'0'),
+        b'a'..=b'f' => Ok(byte - b'a' + 10),
+        b'A'..=b'F' => Ok(byte - b'A' + 10),
+        _ => Err(ParseError::InvalidCharacter(byte)),
+    }
+}
+
+//Decode a 32 character hex hash (either case) into its 16 byte digest
+pub fn decode_hex(hash: &[u8]) -> Result<Digest, ParseError> {
+    decode_hex_bytes(hash)
+}
+
+//Decode exactly 2 * N hex characters (either case) into N bytes, e.g. a signature
+pub fn decode_hex_bytes<const N: usize>(hex: &[u8]) -> Result<[u8; N], ParseError> {
+    if hex.len() != 2 * N {
+        return Err(ParseError::InvalidLength(hex.len()));
+    }
+    let mut bytes = [0x00u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_chunks::<2>().0) {
+        *byte = nibble(pair[0])? << 4 | nibble(pair[1])?;
+    }
+    Ok(bytes)
+}
+
+//Encode a digest as 32 lowercase hex characters, as expected by the logging API
+pub fn encode_hex(digest: &Digest) -> HexDigest {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut hash = [0x00u8; HEX_DIGEST_LEN];
+    for (pair, byte) in hash.as_chunks_mut::<2>().0.iter_mut().zip(digest) {
+        pair[0] = HEX[(byte >> 4) as usize];
+        pair[1] = HEX[(byte & 0x0f) as usize];
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &[u8; 32] = b"d41d8cd98f00b204e9800998ecf8427e";
+    const DIGEST: Digest = [
+        0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8, 0x42,
+        0x7e,
+    ];
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(decode_hex(HASH), Ok(DIGEST));
+        assert_eq!(&encode_hex(&DIGEST), HASH);
+        assert_eq!(decode_hex(b"D41D8CD98F00B204E9800998ECF8427E"), Ok(DIGEST));
+    }
+
+    #[test]
+    fn rejects_invalid_hashes() {
+        assert_eq!(decode_hex(b"d41d"), Err(ParseError::InvalidLength(4)));
+        assert_eq!(
+            decode_hex(b"d41d8cd98f00b204e9800998ecf8427x"),
+            Err(ParseError::InvalidCharacter(b'x'))
+        );
+    }
+
+    #[test]
+    fn decodes_other_lengths() {
+        assert_eq!(decode_hex_bytes::<2>(b"00fF"), Ok([0x00, 0xff]));
+        assert_eq!(
+            decode_hex_bytes::<64>(HASH),
+            Err(ParseError::InvalidLength(32))
+        );
+    }
+
+    #[test]
+    fn ordering_is_preserved() {
+        //Lowercase hex sorts in the same order as the digests, so migrated keys stay sorted
+        let a = b"0f000000000000000000000000000000";
+        let b = b"a0000000000000000000000000000000";
+        assert!(a < b);
+        assert!(decode_hex(a).unwrap() < decode_hex(b).unwrap());
+    }
+}
@@ -0,0 +1,36 @@
+#![no_std]
+
+//Card database formats shared between the controller firmware and its host tests.
+//Nothing in here touches hardware, so `cargo test` runs it on the host.
+
+mod card_id;
+mod hex;
+mod parser;
+mod record;
+mod schedule;
+mod schema1;
+mod slots;
+
+pub use card_id::{
+    decode_card_ids, encode_card_ids, legacy_card_ids, parse_card_ids, parse_version,
+    CardIdAlgorithm, CardIds, RemoteVersion, MAX_CARD_IDS_LEN, MAX_CARD_ID_ALGORITHMS, VERSION_LEN,
+};
+pub use hex::{decode_hex, decode_hex_bytes, encode_hex};
+pub use parser::{CardEntry, Change, DeltaParser, Entry, HashListParser, ParseError};
+pub use record::{CardRecord, RecordError, Role, MAX_MEMBER_LEN, MAX_RECORD_LEN};
+pub use schedule::{
+    check_access, decode_schedule, encode_schedule, parse_schedule, schedule_allows, Access,
+    LocalTime, Schedule, Timezone, Window, MAX_SCHEDULE_LEN, MAX_WINDOWS,
+};
+pub use schema1::schema1_entry;
+pub use slots::newest_slot;
+
+//Cards are identified by a 16 byte digest of their UID (see card_id.rs) - the whole MD5, or the
+//first 16 of SHA-256's or HMAC-SHA256's 32 bytes. Stored in flash (and passed around) as the
+//raw bytes
+pub const DIGEST_LEN: usize = 16;
+pub type Digest = [u8; DIGEST_LEN];
+
+//A digest as the server sends it (and the logs report it) - 32 ascii hex characters
+pub const HEX_DIGEST_LEN: usize = 2 * DIGEST_LEN;
+pub type HexDigest = [u8; HEX_DIGEST_LEN];
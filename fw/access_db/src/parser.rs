@@ -0,0 +1,664 @@
+//Streaming parsers for the database bodies served by the backend.
+//
+//The body arrives in arbitrarily sized reads, so entries may be split across reads.
+//Entries are separated by ascii whitespace (historically a single space), and the
+//final entry need not have a trailing separator - call finish() at EOF to collect it.
+//
+//Each entry is a 32 character hex hash, optionally followed by comma separated card details:
+//  <hash>[,<member>[,<role>[,<valid_from>[,<valid_until>[,<schedule>]]]]]
+//Member is percent-encoded, so names may contain spaces or commas (e.g. Jo%20Bloggs). Role is
+//user (the default), inductor or maintainer, and the times are unix timestamps.
+//Empty fields take their defaults, and any further fields are ignored.
+//
+//An entry may instead be a role's default schedule, used by cards without their own:
+//  @<role>,<schedule>
+//See schedule.rs for the schedule format.
+
+use heapless::String;
+
+use crate::{
+    decode_hex, decode_hex_bytes, parse_schedule, CardRecord, Digest, Role, Schedule,
+    MAX_MEMBER_LEN,
+};
+
+//Longest entry accepted, including the card details
+const MAX_ENTRY_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    InvalidLength(usize), //Entry was not the expected number of characters
+    InvalidCharacter(u8), //Hash contained a non-hex character
+    InvalidChange(u8),    //Delta entry didn't start with '+' or '-'
+    InvalidMember,        //Member too long, badly percent-encoded, or containing '"' or '\'
+    InvalidRole,
+    InvalidTime,
+    InvalidSchedule,
+    InvalidCardId, //Unknown or repeated card id algorithm in the DB version
+    InvalidSerial, //DB version serial too large, or followed by another field
+}
+
+//A card from the database download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CardEntry {
+    pub digest: Digest,
+    pub record: CardRecord,
+}
+
+//An entry from the database download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Card(CardEntry),
+    Schedule(Role, Schedule), //Default schedule for cards with this role
+}
+
+//An entry from the delta endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Add(CardEntry), //Also used to update the details of an existing card
+    Remove(Digest),
+    Schedule(Role, Option<Schedule>), //Set, or with None clear, a role's default schedule
+}
+
+impl Change {
+    //The card this change applies to - None for role schedules
+    pub fn digest(&self) -> Option<&Digest> {
+        match self {
+            Change::Add(entry) => Some(&entry.digest),
+            Change::Remove(digest) => Some(digest),
+            Change::Schedule(..) => None,
+        }
+    }
+}
+
+//Splits the input into whitespace separated tokens of up to N bytes
+struct Tokenizer<const N: usize> {
+    buf: [u8; N],
+    len: usize, //May exceed N - the excess bytes are counted, but not stored
+}
+
+impl<const N: usize> Tokenizer<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0x00u8; N],
+            len: 0,
+        }
+    }
+
+    //Consume input up to the end of the next token
+    fn next(&mut self, input: &mut &[u8]) -> Option<Result<&[u8], ParseError>> {
+        while let Some((&byte, rest)) = input.split_first() {
+            *input = rest;
+            if byte.is_ascii_whitespace() {
+                if self.len > 0 {
+                    return Some(self.take());
+                }
+            } else {
+                if self.len < N {
+                    self.buf[self.len] = byte;
+                }
+                self.len += 1;
+            }
+        }
+        None
+    }
+
+    //Any partial token at EOF is the final entry
+    fn finish(&mut self) -> Option<Result<&[u8], ParseError>> {
+        if self.len > 0 {
+            Some(self.take())
+        } else {
+            None
+        }
+    }
+
+    fn take(&mut self) -> Result<&[u8], ParseError> {
+        let len = core::mem::take(&mut self.len);
+        if len > N {
+            Err(ParseError::InvalidLength(len))
+        } else {
+            Ok(&self.buf[..len])
+        }
+    }
+}
+
+fn parse_member(field: &[u8]) -> Result<String<MAX_MEMBER_LEN>, ParseError> {
+    //Decode the percent-encoding - a name can't contain the separators as they are
+    let mut buf = [0x00u8; MAX_MEMBER_LEN];
+    let mut len = 0usize;
+    let mut rest = field;
+    while let Some((&byte, tail)) = rest.split_first() {
+        let (byte, tail) = match (byte, tail) {
+            (b'%', [high, low, tail @ ..]) => {
+                let [byte] =
+                    decode_hex_bytes::<1>(&[*high, *low]).map_err(|_| ParseError::InvalidMember)?;
+                (byte, tail)
+            }
+            (b'%', _) => return Err(ParseError::InvalidMember),
+            _ => (byte, tail),
+        };
+        *buf.get_mut(len).ok_or(ParseError::InvalidMember)? = byte;
+        len += 1;
+        rest = tail;
+    }
+    let field = &buf[..len];
+
+    //Member IDs are included in the JSON log events, so must not need escaping
+    if field
+        .iter()
+        .any(|&byte| byte == b'"' || byte == b'\\' || byte.is_ascii_control())
+    {
+        return Err(ParseError::InvalidMember);
+    }
+    let member = core::str::from_utf8(field).map_err(|_| ParseError::InvalidMember)?;
+    let mut result = String::new();
+    result
+        .push_str(member)
+        .map_err(|_| ParseError::InvalidMember)?;
+    Ok(result)
+}
+
+fn parse_role(field: &[u8]) -> Result<Role, ParseError> {
+    match field {
+        b"" | b"user" => Ok(Role::User),
+        b"inductor" => Ok(Role::Inductor),
+        b"maintainer" => Ok(Role::Maintainer),
+        _ => Err(ParseError::InvalidRole),
+    }
+}
+
+fn parse_time(field: &[u8]) -> Result<Option<u64>, ParseError> {
+    if field.is_empty() {
+        return Ok(None);
+    }
+    core::str::from_utf8(field)
+        .ok()
+        .and_then(|time| time.parse().ok())
+        .map(Some)
+        .ok_or(ParseError::InvalidTime)
+}
+
+fn parse_card(token: &[u8]) -> Result<CardEntry, ParseError> {
+    let mut fields = token.split(|&byte| byte == b',');
+    let digest = decode_hex(fields.next().unwrap_or_default())?;
+    let mut field = || fields.next().unwrap_or_default();
+    let record = CardRecord {
+        member: parse_member(field())?,
+        role: parse_role(field())?,
+        valid_from: parse_time(field())?,
+        valid_until: parse_time(field())?,
+        schedule: match field() {
+            b"" => None,
+            schedule => Some(parse_schedule(schedule)?),
+        },
+    };
+    Ok(CardEntry { digest, record })
+}
+
+//<role>,<schedule> - the role must be given explicitly
+fn parse_role_schedule(token: &[u8]) -> Result<(Role, Schedule), ParseError> {
+    let comma = token
+        .iter()
+        .position(|&byte| byte == b',')
+        .ok_or(ParseError::InvalidSchedule)?;
+    let role = match &token[..comma] {
+        b"" => return Err(ParseError::InvalidRole),
+        role => parse_role(role)?,
+    };
+    Ok((role, parse_schedule(&token[comma + 1..])?))
+}
+
+fn parse_entry(token: &[u8]) -> Result<Entry, ParseError> {
+    match token.split_first() {
+        Some((b'@', schedule)) => {
+            let (role, schedule) = parse_role_schedule(schedule)?;
+            Ok(Entry::Schedule(role, schedule))
+        }
+        _ => Ok(Entry::Card(parse_card(token)?)),
+    }
+}
+
+fn parse_change(token: &[u8]) -> Result<Change, ParseError> {
+    match token.split_first() {
+        Some((b'+', [b'@', schedule @ ..])) => {
+            let (role, schedule) = parse_role_schedule(schedule)?;
+            Ok(Change::Schedule(role, Some(schedule)))
+        }
+        Some((b'-', [b'@'])) => Err(ParseError::InvalidRole),
+        Some((b'-', [b'@', role @ ..])) => Ok(Change::Schedule(parse_role(role)?, None)),
+        Some((b'+', card)) => Ok(Change::Add(parse_card(card)?)),
+        Some((b'-', hash)) => Ok(Change::Remove(decode_hex(hash)?)),
+        Some((&byte, _)) => Err(ParseError::InvalidChange(byte)),
+        None => Err(ParseError::InvalidLength(0)),
+    }
+}
+
+//Parser for the full hash list - each entry is a card or role schedule, as described above
+pub struct HashListParser {
+    tokens: Tokenizer<MAX_ENTRY_LEN>,
+}
+
+impl HashListParser {
+    pub const fn new() -> Self {
+        Self {
+            tokens: Tokenizer::new(),
+        }
+    }
+
+    //Returns the next complete entry, advancing input past it. None means input is exhausted.
+    //A malformed entry is reported as an error, and parsing may continue with the next one.
+    pub fn parse(&mut self, input: &mut &[u8]) -> Option<Result<Entry, ParseError>> {
+        self.tokens.next(input).map(|token| parse_entry(token?))
+    }
+
+    //Call at EOF, to return the final entry if it had no trailing separator
+    pub fn finish(&mut self) -> Option<Result<Entry, ParseError>> {
+        self.tokens.finish().map(|token| parse_entry(token?))
+    }
+}
+
+impl Default for HashListParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//Parser for the delta endpoint - each entry is '+' followed by a card, or '-' followed by a hash.
+//Role schedules are set with '+@<role>,<schedule>' and cleared with '-@<role>'.
+pub struct DeltaParser {
+    tokens: Tokenizer<{ MAX_ENTRY_LEN + 1 }>,
+}
+
+impl DeltaParser {
+    pub const fn new() -> Self {
+        Self {
+            tokens: Tokenizer::new(),
+        }
+    }
+
+    //Returns the next complete entry, advancing input past it. None means input is exhausted.
+    pub fn parse(&mut self, input: &mut &[u8]) -> Option<Result<Change, ParseError>> {
+        self.tokens.next(input).map(|token| parse_change(token?))
+    }
+
+    //Call at EOF, to return the final entry if it had no trailing separator
+    pub fn finish(&mut self) -> Option<Result<Change, ParseError>> {
+        self.tokens.finish().map(|token| parse_change(token?))
+    }
+}
+
+impl Default for DeltaParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::*;
+
+    const HASHES: [&[u8; 32]; 4] = [
+        b"0123456789abcdef0123456789abcdef",
+        b"ffffffffffffffffffffffffffffffff",
+        b"00000000000000000000000000000000",
+        b"d41d8cd98f00b204e9800998ecf8427e",
+    ];
+
+    //Small xorshift PRNG, so the random splits are repeatable
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    //Feed the body to the parser in randomly sized pieces, collecting every entry
+    fn parse_split(body: &[u8], rng: &mut Rng) -> Vec<Result<Entry, ParseError>> {
+        let mut parser = HashListParser::new();
+        let mut entries = Vec::new();
+        let mut remaining = body;
+        while !remaining.is_empty() {
+            let len = 1 + (rng.next() as usize % 70).min(remaining.len() - 1);
+            let (mut chunk, rest) = remaining.split_at(len);
+            remaining = rest;
+            while let Some(entry) = parser.parse(&mut chunk) {
+                entries.push(entry);
+            }
+            assert!(chunk.is_empty());
+        }
+        entries.extend(parser.finish());
+        entries
+    }
+
+    fn body(separator: &[u8], trailing: bool) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (index, hash) in HASHES.iter().enumerate() {
+            body.extend_from_slice(&hash[..]);
+            if trailing || index != HASHES.len() - 1 {
+                body.extend_from_slice(separator);
+            }
+        }
+        body
+    }
+
+    fn digest(hash: &[u8]) -> Digest {
+        decode_hex(hash).unwrap()
+    }
+
+    //A card with no details
+    fn card(hash: &[u8]) -> CardEntry {
+        CardEntry {
+            digest: digest(hash),
+            record: CardRecord::default(),
+        }
+    }
+
+    fn expected() -> Vec<Result<Entry, ParseError>> {
+        HASHES
+            .iter()
+            .map(|hash| Ok(Entry::Card(card(*hash))))
+            .collect()
+    }
+
+    #[test]
+    fn parses_whole_body() {
+        assert_eq!(parse_split(&body(b" ", true), &mut Rng(1)), expected());
+    }
+
+    #[test]
+    fn parses_randomly_split_bodies() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for separator in [&b" "[..], b"\n", b"\r\n", b"  \t"] {
+            for trailing in [true, false] {
+                let body = body(separator, trailing);
+                for _ in 0..500 {
+                    assert_eq!(parse_split(&body, &mut rng), expected());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn final_entry_without_separator() {
+        let mut parser = HashListParser::new();
+        let mut input = &HASHES[0][..];
+        assert_eq!(parser.parse(&mut input), None);
+        assert_eq!(parser.finish(), Some(Ok(Entry::Card(card(HASHES[0])))));
+        assert_eq!(parser.finish(), None);
+    }
+
+    #[test]
+    fn empty_body() {
+        assert!(parse_split(b"", &mut Rng(1)).is_empty());
+        assert!(parse_split(b" \n ", &mut Rng(1)).is_empty());
+    }
+
+    #[test]
+    fn accepts_uppercase() {
+        let mut parser = HashListParser::new();
+        let mut input = &b"D41D8CD98F00B204E9800998ECF8427E "[..];
+        assert_eq!(
+            parser.parse(&mut input),
+            Some(Ok(Entry::Card(card(HASHES[3]))))
+        );
+    }
+
+    #[test]
+    fn parses_card_details() {
+        let body = b"0123456789abcdef0123456789abcdef,M0042,inductor,1700000000,1800000000\n\
+ffffffffffffffffffffffffffffffff,Jo%20Bloggs\n\
+00000000000000000000000000000000,,maintainer,,1800000000,,extra\n\
+d41d8cd98f00b204e9800998ecf8427e,M0043,,,";
+        let expected = [
+            CardEntry {
+                digest: digest(HASHES[0]),
+                record: CardRecord {
+                    member: String::from("M0042"),
+                    role: Role::Inductor,
+                    valid_from: Some(1700000000),
+                    valid_until: Some(1800000000),
+                    schedule: None,
+                },
+            },
+            CardEntry {
+                digest: digest(HASHES[1]),
+                record: CardRecord {
+                    member: String::from("Jo Bloggs"),
+                    ..Default::default()
+                },
+            },
+            CardEntry {
+                digest: digest(HASHES[2]),
+                record: CardRecord {
+                    role: Role::Maintainer,
+                    valid_until: Some(1800000000),
+                    ..Default::default()
+                },
+            },
+            CardEntry {
+                digest: digest(HASHES[3]),
+                record: CardRecord {
+                    member: String::from("M0043"),
+                    ..Default::default()
+                },
+            },
+        ];
+        let mut rng = Rng(42);
+        for _ in 0..500 {
+            let entries = parse_split(body, &mut rng);
+            assert_eq!(entries[0], Ok(Entry::Card(expected[0].clone())));
+            assert_eq!(entries[1], Ok(Entry::Card(expected[1].clone())));
+            assert_eq!(entries[2], Ok(Entry::Card(expected[2].clone())));
+            assert_eq!(entries[3], Ok(Entry::Card(expected[3].clone())));
+            assert_eq!(entries.len(), 4);
+        }
+    }
+
+    #[test]
+    fn decodes_member_names() {
+        for (field, member) in [
+            (&b"Jo%20Bloggs"[..], "Jo Bloggs"),
+            (b"Bloggs%2c%20Jo", "Bloggs, Jo"),
+            (b"Zo%C3%AB", "Zo\u{eb}"),
+            (b"100%25", "100%"),
+        ] {
+            assert_eq!(parse_member(field), Ok(String::from(member)));
+        }
+        for field in [
+            &b"Jo%2"[..],
+            b"Jo%zz",
+            b"100%",
+            b"%22M1%22",
+            b"M%5c1",
+            b"Jo%09",
+        ] {
+            assert_eq!(parse_member(field), Err(ParseError::InvalidMember));
+        }
+        //Encoding doesn't count against the length limit
+        assert_eq!(
+            parse_member(b"M%20012345678901234567890123456789").map(|m| m.len()),
+            Ok(MAX_MEMBER_LEN)
+        );
+    }
+
+    #[test]
+    fn reports_invalid_card_details() {
+        let entries = parse_split(
+            b"ffffffffffffffffffffffffffffffff,M1,admin \
+ffffffffffffffffffffffffffffffff,M1,user,soon \
+ffffffffffffffffffffffffffffffff,M1,user,,-1 \
+ffffffffffffffffffffffffffffffff,\"M1\" \
+ffffffffffffffffffffffffffffffff,M012345678901234567890123456789012",
+            &mut Rng(5),
+        );
+        assert_eq!(
+            entries,
+            [
+                Err(ParseError::InvalidRole),
+                Err(ParseError::InvalidTime),
+                Err(ParseError::InvalidTime),
+                Err(ParseError::InvalidMember),
+                Err(ParseError::InvalidMember),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_malformed_entries() {
+        let mut long = [b'f'; MAX_ENTRY_LEN + 10];
+        long[32] = b',';
+        let mut body =
+            b"0123 0123456789abcdef0123456789abcdefff 0123456789abcdef0123456789abcdeg ".to_vec();
+        body.extend_from_slice(&long);
+        body.extend_from_slice(b" ffffffffffffffffffffffffffffffff");
+        assert_eq!(
+            parse_split(&body, &mut Rng(7)),
+            [
+                Err(ParseError::InvalidLength(4)),
+                Err(ParseError::InvalidLength(34)),
+                Err(ParseError::InvalidCharacter(b'g')),
+                Err(ParseError::InvalidLength(MAX_ENTRY_LEN + 10)),
+                Ok(Entry::Card(card(HASHES[1]))),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_non_utf8() {
+        let mut body = HASHES[0].to_vec();
+        body[5] = 0xc3;
+        assert_eq!(
+            parse_split(&body, &mut Rng(3)),
+            [Err(ParseError::InvalidCharacter(0xc3))]
+        );
+        assert_eq!(
+            parse_split(b"ffffffffffffffffffffffffffffffff,M\xc3", &mut Rng(3)),
+            [Err(ParseError::InvalidMember)]
+        );
+    }
+
+    #[test]
+    fn handles_more_than_32_hashes_per_read() {
+        let mut body = Vec::new();
+        for _ in 0..100 {
+            body.extend_from_slice(HASHES[3]);
+            body.push(b' ');
+        }
+        let mut parser = HashListParser::new();
+        let mut input = &body[..];
+        let mut count = 0;
+        while let Some(entry) = parser.parse(&mut input) {
+            assert_eq!(entry, Ok(Entry::Card(card(HASHES[3]))));
+            count += 1;
+        }
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let body = b"@user,Mon-Fri@09:00-22:00 \
+0123456789abcdef0123456789abcdef,M1,maintainer,,,* \
+ffffffffffffffffffffffffffffffff,M2,,,,Sat+Sun@10:00-18:00;Mon@18:00-20:00 \
+@,Mon@09:00-10:00 @user @inductor,Mon@09:00 \
+ffffffffffffffffffffffffffffffff,M3,,,,Mon";
+        let entries = parse_split(body, &mut Rng(11));
+        assert_eq!(
+            entries,
+            [
+                Ok(Entry::Schedule(
+                    Role::User,
+                    parse_schedule(b"Mon-Fri@09:00-22:00").unwrap()
+                )),
+                Ok(Entry::Card(CardEntry {
+                    digest: digest(HASHES[0]),
+                    record: CardRecord {
+                        member: String::from("M1"),
+                        role: Role::Maintainer,
+                        schedule: Some(parse_schedule(b"*").unwrap()),
+                        ..Default::default()
+                    }
+                })),
+                Ok(Entry::Card(CardEntry {
+                    digest: digest(HASHES[1]),
+                    record: CardRecord {
+                        member: String::from("M2"),
+                        schedule: Some(
+                            parse_schedule(b"Sat+Sun@10:00-18:00;Mon@18:00-20:00").unwrap()
+                        ),
+                        ..Default::default()
+                    }
+                })),
+                Err(ParseError::InvalidRole),
+                Err(ParseError::InvalidSchedule),
+                Err(ParseError::InvalidSchedule),
+                Err(ParseError::InvalidSchedule),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_schedule_changes() {
+        let mut parser = DeltaParser::new();
+        let mut input = &b"+@inductor,*\n-@maintainer\n-@\n-@admin\n"[..];
+        let mut entries = Vec::new();
+        while let Some(entry) = parser.parse(&mut input) {
+            entries.push(entry);
+        }
+        assert_eq!(
+            entries,
+            [
+                Ok(Change::Schedule(
+                    Role::Inductor,
+                    Some(parse_schedule(b"*").unwrap())
+                )),
+                Ok(Change::Schedule(Role::Maintainer, None)),
+                Err(ParseError::InvalidRole),
+                Err(ParseError::InvalidRole),
+            ]
+        );
+        assert_eq!(entries[1].as_ref().unwrap().digest(), None);
+    }
+
+    #[test]
+    fn parses_delta() {
+        let mut rng = Rng(99);
+        let body = b"+0123456789abcdef0123456789abcdef\n-ffffffffffffffffffffffffffffffff\n*00000000000000000000000000000000\n+d41d8cd98f00b204e9800998ecf8427e,M7,maintainer";
+        for _ in 0..200 {
+            let mut parser = DeltaParser::new();
+            let mut entries = Vec::new();
+            let mut remaining = &body[..];
+            while !remaining.is_empty() {
+                let len = 1 + (rng.next() as usize % 40).min(remaining.len() - 1);
+                let (mut chunk, rest) = remaining.split_at(len);
+                remaining = rest;
+                while let Some(entry) = parser.parse(&mut chunk) {
+                    entries.push(entry);
+                }
+            }
+            entries.extend(parser.finish());
+            assert_eq!(
+                entries,
+                [
+                    Ok(Change::Add(card(HASHES[0]))),
+                    Ok(Change::Remove(digest(HASHES[1]))),
+                    Err(ParseError::InvalidChange(b'*')),
+                    Ok(Change::Add(CardEntry {
+                        digest: digest(HASHES[3]),
+                        record: CardRecord {
+                            member: String::from("M7"),
+                            role: Role::Maintainer,
+                            ..Default::default()
+                        }
+                    })),
+                ]
+            );
+        }
+    }
+}
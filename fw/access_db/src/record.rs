@@ -0,0 +1,157 @@
+//Per-card metadata, stored as the ekv value for each card's digest.
+//
+//Records are stored postcard encoded, wrapped in a versioned enum - new formats are added
+//as new variants, so records written by older firmware can still be read.
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::Schedule;
+
+pub const MAX_MEMBER_LEN: usize = 32;
+//Largest possible encoded record
+pub const MAX_RECORD_LEN: usize = 96;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Role {
+    #[default]
+    User,
+    Inductor,   //Can induct (train) other members
+    Maintainer, //Responsible for the equipment
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::User, Role::Inductor, Role::Maintainer];
+
+    //Name as used by the server, in both the database download and log events
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Inductor => "inductor",
+            Role::Maintainer => "maintainer",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CardRecord {
+    pub member: String<MAX_MEMBER_LEN>, //Display name or member ID - may be empty
+    pub role: Role,
+    pub valid_from: Option<u64>, //Unix time (seconds) the card becomes valid
+    pub valid_until: Option<u64>, //Unix time (seconds) the card expires
+    pub schedule: Option<Schedule>, //Overrides the role's schedule, if set
+}
+
+//CardRecord as stored before schedules were added
+#[derive(Serialize, Deserialize)]
+struct CardRecordV1 {
+    member: String<MAX_MEMBER_LEN>,
+    role: Role,
+    valid_from: Option<u64>,
+    valid_until: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RecordError {
+    Encode, //Buffer too small
+    Decode, //Corrupt, or written by newer firmware
+}
+
+#[derive(Serialize, Deserialize)]
+enum StoredRecord {
+    //Databases written before records existed hold a single 0x00 byte for every card,
+    //which is exactly how postcard encodes this variant
+    Legacy,
+    V1(CardRecordV1),
+    V2(CardRecord),
+}
+
+impl CardRecord {
+    //Encode as a stored (versioned) record
+    pub fn encode<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], RecordError> {
+        postcard::to_slice(&StoredRecord::V2(self.clone()), buf).map_err(|_| RecordError::Encode)
+    }
+
+    //Decode a stored record, of any version
+    pub fn decode(buf: &[u8]) -> Result<Self, RecordError> {
+        match postcard::from_bytes(buf).map_err(|_| RecordError::Decode)? {
+            StoredRecord::Legacy => Ok(Self::default()),
+            StoredRecord::V1(record) => Ok(CardRecord {
+                member: record.member,
+                role: record.role,
+                valid_from: record.valid_from,
+                valid_until: record.valid_until,
+                schedule: None,
+            }),
+            StoredRecord::V2(record) => Ok(record),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_schedule;
+
+    fn record() -> CardRecord {
+        CardRecord {
+            member: String::from("M0123456789012345678901234567890"),
+            role: Role::Maintainer,
+            valid_from: Some(u64::MAX),
+            valid_until: Some(u64::MAX),
+            schedule: Some(
+                parse_schedule(
+                    b"Mon+Tue+Wed+Thu@00:00-24:00;Fri-Sun@23:59-00:01;Mon@01:00-02:00;Sun@22:00-23:00",
+                )
+                .unwrap(),
+            ),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0x00u8; MAX_RECORD_LEN];
+        let encoded = record().encode(&mut buf).unwrap();
+        assert_eq!(CardRecord::decode(encoded), Ok(record()));
+    }
+
+    #[test]
+    fn largest_record_fits() {
+        let mut buf = [0x00u8; MAX_RECORD_LEN];
+        assert!(record().encode(&mut buf).is_ok());
+    }
+
+    #[test]
+    fn legacy_value_decodes() {
+        assert_eq!(CardRecord::decode(&[0x00]), Ok(CardRecord::default()));
+    }
+
+    #[test]
+    fn v1_record_decodes() {
+        let v1 = CardRecordV1 {
+            member: String::from("M1"),
+            role: Role::Inductor,
+            valid_from: None,
+            valid_until: Some(1),
+        };
+        let mut buf = [0x00u8; MAX_RECORD_LEN];
+        let encoded = postcard::to_slice(&StoredRecord::V1(v1), &mut buf).unwrap();
+        assert_eq!(
+            CardRecord::decode(encoded),
+            Ok(CardRecord {
+                member: String::from("M1"),
+                role: Role::Inductor,
+                valid_until: Some(1),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_version_is_an_error() {
+        assert_eq!(CardRecord::decode(&[0x7f]), Err(RecordError::Decode));
+        assert_eq!(CardRecord::decode(&[]), Err(RecordError::Decode));
+    }
+}
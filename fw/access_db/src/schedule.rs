@@ -0,0 +1,485 @@
+//Time-of-day and day-of-week access schedules.
+//
+//A schedule is a list of windows, each covering a set of weekdays and a time range in local
+//time. Windows may run past midnight (e.g. 22:00-02:00), in which case the part after
+//midnight belongs to the following day.
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{CardRecord, ParseError, RecordError};
+
+pub const MAX_WINDOWS: usize = 4;
+//Largest possible encoded schedule
+pub const MAX_SCHEDULE_LEN: usize = 32;
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+const DAY_NAMES: [&[u8]; 7] = [b"Mon", b"Tue", b"Wed", b"Thu", b"Fri", b"Sat", b"Sun"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub days: u8,   //Bit 0 is Monday ... bit 6 is Sunday
+    pub start: u16, //Minutes after midnight
+    pub end: u16,   //Minutes after midnight, up to 24:00
+}
+
+pub type Schedule = Vec<Window, MAX_WINDOWS>;
+
+//Every day, all day - used for "*", e.g. for key holders who may use the equipment at any time
+pub const ALWAYS: Window = Window {
+    days: 0x7f,
+    start: 0,
+    end: MINUTES_PER_DAY,
+};
+
+//A local time of week
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub weekday: u8, //0 is Monday
+    pub minute: u16, //Minutes after midnight
+}
+
+//Local time zone, as a fixed offset from UTC with optional EU daylight saving rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timezone {
+    pub offset_minutes: i16,
+    pub eu_dst: bool, //+1 hour from 01:00 UTC on the last Sunday in March, to the last in October
+}
+
+impl Timezone {
+    pub const UTC: Timezone = Timezone {
+        offset_minutes: 0,
+        eu_dst: false,
+    };
+    pub const UK: Timezone = Timezone {
+        offset_minutes: 0,
+        eu_dst: true,
+    };
+
+    //Convert a unix time to local time of week
+    pub fn local(&self, unix: u64) -> LocalTime {
+        let mut offset = self.offset_minutes as i64 * 60;
+        if self.eu_dst && is_eu_dst(unix as i64) {
+            offset += 60 * 60;
+        }
+        let local = unix as i64 + offset;
+        let days = local.div_euclid(SECONDS_PER_DAY);
+        LocalTime {
+            //1970-01-01 was a Thursday
+            weekday: (days + 3).rem_euclid(7) as u8,
+            minute: (local.rem_euclid(SECONDS_PER_DAY) / 60) as u16,
+        }
+    }
+}
+
+impl Window {
+    fn contains(&self, time: LocalTime) -> bool {
+        let today = self.days & (1 << time.weekday) != 0;
+        if self.start < self.end {
+            today && time.minute >= self.start && time.minute < self.end
+        } else {
+            //Runs past midnight - the early part belongs to the previous day's window
+            let yesterday = self.days & (1 << ((time.weekday + 6) % 7)) != 0;
+            (today && time.minute >= self.start) || (yesterday && time.minute < self.end)
+        }
+    }
+}
+
+pub fn schedule_allows(schedule: &[Window], time: LocalTime) -> bool {
+    schedule.iter().any(|window| window.contains(time))
+}
+
+fn schedule_is_always(schedule: &[Window]) -> bool {
+    schedule.contains(&ALWAYS)
+}
+
+//Result of checking a card against its validity window and schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Access {
+    Granted,
+    OutsideSchedule, //Card is outside its (or its role's) schedule, or its validity window
+}
+
+//Decide whether a card may be used now. The card's own schedule takes precedence over its
+//role's schedule. `now` is None when the wall-clock time isn't known, in which case:
+// - the validity window can't be checked, so is ignored (expired cards are also removed by the server)
+// - a restrictive schedule denies access, as we can't show we are inside it
+pub fn check_access(
+    record: &CardRecord,
+    role_schedule: Option<&[Window]>,
+    now: Option<u64>,
+    timezone: &Timezone,
+) -> Access {
+    if let Some(now) = now {
+        if record.valid_from.is_some_and(|from| now < from)
+            || record.valid_until.is_some_and(|until| now >= until)
+        {
+            return Access::OutsideSchedule;
+        }
+    }
+
+    let schedule = match (&record.schedule, role_schedule) {
+        (Some(schedule), _) => schedule.as_slice(),
+        (None, Some(schedule)) => schedule,
+        (None, None) => return Access::Granted,
+    };
+
+    let allowed = if schedule_is_always(schedule) {
+        true
+    } else {
+        match now {
+            Some(now) => schedule_allows(schedule, timezone.local(now)),
+            None => false,
+        }
+    };
+
+    match allowed {
+        true => Access::Granted,
+        false => Access::OutsideSchedule,
+    }
+}
+
+//Role schedules are stored in flash postcard encoded, as their own keys
+pub fn encode_schedule<'a>(
+    schedule: &Schedule,
+    buf: &'a mut [u8],
+) -> Result<&'a mut [u8], RecordError> {
+    postcard::to_slice(schedule, buf).map_err(|_| RecordError::Encode)
+}
+
+pub fn decode_schedule(buf: &[u8]) -> Result<Schedule, RecordError> {
+    postcard::from_bytes(buf).map_err(|_| RecordError::Decode)
+}
+
+//Parse a schedule, e.g. "Mon-Fri@09:00-22:00;Sat+Sun@10:00-18:00", or "*" for always
+pub fn parse_schedule(field: &[u8]) -> Result<Schedule, ParseError> {
+    let mut schedule = Schedule::new();
+    if field == b"*" {
+        schedule.push(ALWAYS).ok();
+        return Ok(schedule);
+    }
+    for window in field.split(|&byte| byte == b';') {
+        schedule
+            .push(parse_window(window)?)
+            .map_err(|_| ParseError::InvalidSchedule)?;
+    }
+    Ok(schedule)
+}
+
+fn parse_window(field: &[u8]) -> Result<Window, ParseError> {
+    let at = field
+        .iter()
+        .position(|&byte| byte == b'@')
+        .ok_or(ParseError::InvalidSchedule)?;
+    let (days, times) = (&field[..at], &field[at + 1..]);
+
+    let mut mask = 0u8;
+    for range in days.split(|&byte| byte == b'+') {
+        mask |= parse_days(range)?;
+    }
+
+    let dash = times
+        .iter()
+        .position(|&byte| byte == b'-')
+        .ok_or(ParseError::InvalidSchedule)?;
+    let start = parse_minute(&times[..dash])?;
+    let end = parse_minute(&times[dash + 1..])?;
+    if start == end || start == MINUTES_PER_DAY {
+        return Err(ParseError::InvalidSchedule);
+    }
+    Ok(Window {
+        days: mask,
+        start,
+        end,
+    })
+}
+
+fn parse_day(name: &[u8]) -> Result<u8, ParseError> {
+    DAY_NAMES
+        .iter()
+        .position(|day| day.eq_ignore_ascii_case(name))
+        .map(|day| day as u8)
+        .ok_or(ParseError::InvalidSchedule)
+}
+
+//A single day, or an inclusive range of days (which may wrap, e.g. Fri-Mon)
+fn parse_days(range: &[u8]) -> Result<u8, ParseError> {
+    let (first, last) = match range.iter().position(|&byte| byte == b'-') {
+        Some(dash) => (parse_day(&range[..dash])?, parse_day(&range[dash + 1..])?),
+        None => {
+            let day = parse_day(range)?;
+            (day, day)
+        }
+    };
+    let mut mask = 0u8;
+    let mut day = first;
+    loop {
+        mask |= 1 << day;
+        if day == last {
+            return Ok(mask);
+        }
+        day = (day + 1) % 7;
+    }
+}
+
+//HH:MM, from 00:00 to 24:00
+fn parse_minute(time: &[u8]) -> Result<u16, ParseError> {
+    let digits = |field: &[u8]| -> Result<u16, ParseError> {
+        match field {
+            [tens @ b'0'..=b'9', units @ b'0'..=b'9'] => {
+                Ok((tens - b'0') as u16 * 10 + (units - b'0') as u16)
+            }
+            _ => Err(ParseError::InvalidSchedule),
+        }
+    };
+    match time {
+        [hours @ .., b':', _, _] if hours.len() == 2 => {
+            let (hours, minutes) = (digits(&time[..2])?, digits(&time[3..])?);
+            let minute = hours * 60 + minutes;
+            if minutes >= 60 || minute > MINUTES_PER_DAY {
+                return Err(ParseError::InvalidSchedule);
+            }
+            Ok(minute)
+        }
+        _ => Err(ParseError::InvalidSchedule),
+    }
+}
+
+//Days since 1970-01-01 of the given date (proleptic Gregorian)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn year_from_days(days: i64) -> i64 {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153; //March is 0
+    year_of_era + era * 400 + if month >= 10 { 1 } else { 0 }
+}
+
+//Unix time of 01:00 UTC on the last Sunday of the given month
+fn last_sunday_0100(year: i64, month: i64) -> i64 {
+    let last_day = days_from_civil(year, month, 31); //Both March and October have 31 days
+    let weekday = (last_day + 3).rem_euclid(7); //0 is Monday
+    let sunday = last_day - (weekday + 1) % 7;
+    sunday * SECONDS_PER_DAY + 60 * 60
+}
+
+fn is_eu_dst(unix: i64) -> bool {
+    let year = year_from_days(unix.div_euclid(SECONDS_PER_DAY));
+    unix >= last_sunday_0100(year, 3) && unix < last_sunday_0100(year, 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Role;
+
+    //Monday 2024-01-01 00:00 UTC
+    const MONDAY: u64 = 1704067200;
+    const HOUR: u64 = 60 * 60;
+    const DAY: u64 = 24 * HOUR;
+
+    fn schedule(text: &str) -> Schedule {
+        parse_schedule(text.as_bytes()).unwrap()
+    }
+
+    fn at(weekday: u8, hour: u16, minute: u16) -> LocalTime {
+        LocalTime {
+            weekday,
+            minute: hour * 60 + minute,
+        }
+    }
+
+    #[test]
+    fn parses_schedules() {
+        assert_eq!(
+            schedule("Mon-Fri@09:00-22:00").as_slice(),
+            [Window {
+                days: 0b0011111,
+                start: 9 * 60,
+                end: 22 * 60
+            }]
+        );
+        assert_eq!(
+            schedule("sat+Sun+Wed@10:30-24:00;Fri-Mon@22:00-02:00").as_slice(),
+            [
+                Window {
+                    days: 0b1100100,
+                    start: 10 * 60 + 30,
+                    end: 24 * 60
+                },
+                Window {
+                    days: 0b1110001,
+                    start: 22 * 60,
+                    end: 2 * 60
+                }
+            ]
+        );
+        assert_eq!(schedule("*").as_slice(), [ALWAYS]);
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for text in [
+            "",
+            "Mon",
+            "Mon@",
+            "Mon@09:00",
+            "Mon@9:00-10:00",
+            "Mon@09:60-10:00",
+            "Mon@09:00-24:01",
+            "Mon@24:00-01:00",
+            "Mon@09:00-09:00",
+            "Funday@09:00-10:00",
+            "Mon-@09:00-10:00",
+            "Mon@09:00-10:00;",
+            "Mon@01:00-02:00;Mon@01:00-02:00;Mon@01:00-02:00;Mon@01:00-02:00;Mon@01:00-02:00",
+        ] {
+            assert_eq!(
+                parse_schedule(text.as_bytes()),
+                Err(ParseError::InvalidSchedule),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn largest_schedule_round_trips() {
+        let largest = schedule("Mon@23:59-24:00;Mon@23:59-24:00;Mon@23:59-24:00;Mon@23:59-24:00");
+        let mut buf = [0x00u8; MAX_SCHEDULE_LEN];
+        let encoded = encode_schedule(&largest, &mut buf).unwrap();
+        assert_eq!(decode_schedule(encoded), Ok(largest));
+    }
+
+    #[test]
+    fn windows() {
+        let weekdays = schedule("Mon-Fri@09:00-22:00");
+        assert!(schedule_allows(&weekdays, at(0, 9, 0)));
+        assert!(schedule_allows(&weekdays, at(4, 21, 59)));
+        assert!(!schedule_allows(&weekdays, at(4, 22, 0)));
+        assert!(!schedule_allows(&weekdays, at(2, 8, 59)));
+        assert!(!schedule_allows(&weekdays, at(5, 12, 0)));
+
+        //Friday and Saturday nights, running past midnight
+        let late = schedule("Fri-Sat@22:00-02:00");
+        assert!(schedule_allows(&late, at(4, 23, 0)));
+        assert!(schedule_allows(&late, at(5, 1, 59)));
+        assert!(schedule_allows(&late, at(6, 1, 0)));
+        assert!(!schedule_allows(&late, at(6, 2, 0)));
+        assert!(!schedule_allows(&late, at(4, 1, 0)));
+        assert!(!schedule_allows(&late, at(6, 23, 0)));
+
+        for weekday in 0..7 {
+            assert!(schedule_allows(&[ALWAYS], at(weekday, 0, 0)));
+            assert!(schedule_allows(&[ALWAYS], at(weekday, 23, 59)));
+        }
+    }
+
+    #[test]
+    fn local_time() {
+        assert_eq!(Timezone::UTC.local(MONDAY), at(0, 0, 0));
+        assert_eq!(
+            Timezone::UTC.local(MONDAY + 6 * DAY + 23 * HOUR + 59 * 60),
+            at(6, 23, 59)
+        );
+        let cet = Timezone {
+            offset_minutes: 60,
+            eu_dst: false,
+        };
+        assert_eq!(cet.local(MONDAY - HOUR), at(0, 0, 0));
+        let behind = Timezone {
+            offset_minutes: -300,
+            eu_dst: false,
+        };
+        assert_eq!(behind.local(MONDAY), at(6, 19, 0));
+    }
+
+    #[test]
+    fn uk_daylight_saving() {
+        //2024: BST from 2024-03-31 01:00 UTC until 2024-10-27 01:00 UTC
+        let start = 1711846800;
+        let end = 1729990800;
+        assert_eq!(Timezone::UK.local(start - 1), at(6, 0, 59));
+        assert_eq!(Timezone::UK.local(start), at(6, 2, 0));
+        assert_eq!(Timezone::UK.local(end - 1), at(6, 1, 59));
+        assert_eq!(Timezone::UK.local(end), at(6, 1, 0));
+        assert_eq!(Timezone::UK.local(MONDAY), at(0, 0, 0));
+    }
+
+    #[test]
+    fn calendar() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2024, 1, 1), (MONDAY / DAY) as i64);
+        assert_eq!(year_from_days(0), 1970);
+        assert_eq!(year_from_days(days_from_civil(2024, 12, 31)), 2024);
+        assert_eq!(year_from_days(days_from_civil(2025, 1, 1)), 2025);
+        assert_eq!(year_from_days(days_from_civil(2100, 3, 1)), 2100);
+    }
+
+    #[test]
+    fn access() {
+        let user = CardRecord::default();
+        let key_holder = CardRecord {
+            role: Role::Maintainer,
+            schedule: Some(schedule("*")),
+            ..Default::default()
+        };
+        let expiring = CardRecord {
+            valid_from: Some(MONDAY),
+            valid_until: Some(MONDAY + DAY),
+            ..Default::default()
+        };
+        let role_schedule = schedule("Mon-Fri@09:00-22:00");
+        let tz = Timezone::UTC;
+        let noon = Some(MONDAY + 12 * HOUR);
+        let midnight = Some(MONDAY + DAY);
+
+        //No schedule
+        assert_eq!(check_access(&user, None, None, &tz), Access::Granted);
+        assert_eq!(check_access(&user, None, midnight, &tz), Access::Granted);
+
+        //Role schedule
+        let role = Some(role_schedule.as_slice());
+        assert_eq!(check_access(&user, role, noon, &tz), Access::Granted);
+        assert_eq!(
+            check_access(&user, role, midnight, &tz),
+            Access::OutsideSchedule
+        );
+        assert_eq!(
+            check_access(&user, role, None, &tz),
+            Access::OutsideSchedule
+        );
+
+        //Card schedule overrides the role schedule, and "always" needs no clock
+        assert_eq!(
+            check_access(&key_holder, role, midnight, &tz),
+            Access::Granted
+        );
+        assert_eq!(check_access(&key_holder, role, None, &tz), Access::Granted);
+
+        //Validity window
+        assert_eq!(check_access(&expiring, None, noon, &tz), Access::Granted);
+        assert_eq!(
+            check_access(&expiring, None, midnight, &tz),
+            Access::OutsideSchedule
+        );
+        assert_eq!(
+            check_access(&expiring, None, Some(MONDAY - 1), &tz),
+            Access::OutsideSchedule
+        );
+        assert_eq!(check_access(&expiring, None, None, &tz), Access::Granted);
+    }
+}
@@ -0,0 +1,61 @@
+//The original database format (schema 1) - each card was stored under its 32 character ascii
+//hex hash, with a single 0x00 byte as the value. Used to migrate it to binary digest keys
+
+use crate::{decode_hex, CardEntry, CardRecord, ParseError, HEX_DIGEST_LEN};
+
+//Convert a key/value pair from a schema 1 database into a card. Reserved keys (which are never
+//32 bytes long) aren't cards, so give Ok(None)
+pub fn schema1_entry(key: &[u8], value: &[u8]) -> Result<Option<CardEntry>, ParseError> {
+    if key.len() != HEX_DIGEST_LEN {
+        return Ok(None);
+    }
+    let digest = decode_hex(key)?;
+    //Only the original 0x00 byte is expected - anything unreadable is also a plain user card
+    let record = CardRecord::decode(value).unwrap_or_default();
+    Ok(Some(CardEntry { digest, record }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Digest, Role};
+
+    const DIGEST: Digest = [
+        0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8, 0x42,
+        0x7e,
+    ];
+
+    #[test]
+    fn migrates_a_baseline_store() {
+        //As written by the original firmware, in key order
+        let store: [(&[u8], &[u8]); 4] = [
+            (b"0123456789abcdef0123456789abcdef", &[0x00]),
+            (b"__DB_VERSION__", b"1B2M2Y8AsgTpgAmY7PhCfg=="),
+            (b"d41d8cd98f00b204e9800998ecf8427e", &[0x00]),
+            (b"ffffffffffffffffffffffffffffffff", &[0x00]),
+        ];
+        let mut cards = store
+            .iter()
+            .filter_map(|(key, value)| schema1_entry(key, value).unwrap());
+
+        let card = cards.next().unwrap();
+        assert_eq!(card.digest[..2], [0x01, 0x23]);
+        assert_eq!(card.record, CardRecord::default());
+        assert_eq!(card.record.role, Role::User);
+
+        let card = cards.next().unwrap();
+        assert_eq!(card.digest, DIGEST);
+        assert_eq!(card.record, CardRecord::default());
+
+        assert_eq!(cards.next().unwrap().digest, [0xff; 16]);
+        assert!(cards.next().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert_eq!(
+            schema1_entry(b"d41d8cd98f00b204e9800998ecf8427g", &[0x00]),
+            Err(ParseError::InvalidCharacter(b'g'))
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//Choosing between the controller's A/B database slots. Each slot is committed with a sequence
+//number one higher (wrapping) than the slot it replaces
+
+//The slot to make active, and its sequence, given each slot's committed sequence (None if the
+//slot doesn't hold a complete database). None if neither does
+pub fn newest_slot(committed: [Option<u32>; 2]) -> Option<(usize, u32)> {
+    match committed {
+        //Compared as a wrapping difference, so sequence 0 follows u32::MAX
+        [Some(a), Some(b)] if (b.wrapping_sub(a) as i32) > 0 => Some((1, b)),
+        [Some(a), _] => Some((0, a)),
+        [None, Some(b)] => Some((1, b)),
+        [None, None] => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_committed_slot() {
+        assert_eq!(newest_slot([Some(3), None]), Some((0, 3)));
+        assert_eq!(newest_slot([None, Some(4)]), Some((1, 4)));
+        assert_eq!(newest_slot([None, None]), None);
+    }
+
+    #[test]
+    fn picks_the_newest_slot() {
+        assert_eq!(newest_slot([Some(3), Some(4)]), Some((1, 4)));
+        assert_eq!(newest_slot([Some(5), Some(4)]), Some((0, 5)));
+        assert_eq!(newest_slot([Some(0), Some(0)]), Some((0, 0)));
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        assert_eq!(newest_slot([Some(u32::MAX), Some(0)]), Some((1, 0)));
+        assert_eq!(newest_slot([Some(0), Some(u32::MAX)]), Some((0, 0)));
+        assert_eq!(newest_slot([Some(1), Some(u32::MAX - 1)]), Some((0, 1)));
+    }
+}
@@ -0,0 +1,97 @@
+//IPv4 settings, as typed at the console - addresses in dotted decimal, returned as octets
+
+use core::net::Ipv4Addr;
+
+use heapless::Vec;
+
+use crate::CommandError;
+
+//Most DNS servers the network stack will use
+pub const MAX_DNS_SERVERS: usize = 3;
+
+//An address, e.g. 192.168.1.1
+pub fn parse_ipv4(value: &str) -> Result<[u8; 4], CommandError> {
+    value
+        .parse::<Ipv4Addr>()
+        .map(|address| address.octets())
+        .map_err(|_| CommandError::InvalidAddress)
+}
+
+//An address and prefix length, e.g. 192.168.1.50/24
+pub fn parse_ipv4_cidr(value: &str) -> Result<([u8; 4], u8), CommandError> {
+    let (address, prefix_len) = value.split_once('/').ok_or(CommandError::InvalidAddress)?;
+    let prefix_len = match prefix_len.parse() {
+        Ok(prefix_len @ 1..=32) => prefix_len,
+        _ => return Err(CommandError::InvalidAddress),
+    };
+    Ok((parse_ipv4(address)?, prefix_len))
+}
+
+//One to MAX_DNS_SERVERS addresses, separated by commas and/or spaces
+pub fn parse_dns_servers(value: &str) -> Result<Vec<[u8; 4], MAX_DNS_SERVERS>, CommandError> {
+    let mut servers = Vec::new();
+    for server in value
+        .split(|c: char| c == ',' || c.is_ascii_whitespace())
+        .filter(|server| !server.is_empty())
+    {
+        servers
+            .push(parse_ipv4(server)?)
+            .map_err(|_| CommandError::InvalidAddress)?;
+    }
+    if servers.is_empty() {
+        return Err(CommandError::InvalidAddress);
+    }
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse_ipv4("192.168.1.1"), Ok([192, 168, 1, 1]));
+        assert_eq!(parse_ipv4("192.168.1"), Err(CommandError::InvalidAddress));
+        assert_eq!(
+            parse_ipv4("192.168.1.256"),
+            Err(CommandError::InvalidAddress)
+        );
+        assert_eq!(parse_ipv4("router"), Err(CommandError::InvalidAddress));
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(parse_ipv4_cidr("10.0.5.20/16"), Ok(([10, 0, 5, 20], 16)));
+        assert_eq!(parse_ipv4_cidr("10.0.5.20/32"), Ok(([10, 0, 5, 20], 32)));
+        for value in [
+            "10.0.5.20",
+            "10.0.5.20/0",
+            "10.0.5.20/33",
+            "10.0.5/24",
+            "/24",
+        ] {
+            assert_eq!(parse_ipv4_cidr(value), Err(CommandError::InvalidAddress));
+        }
+    }
+
+    #[test]
+    fn parses_dns_servers() {
+        assert_eq!(
+            parse_dns_servers("1.1.1.1, 9.9.9.9").as_deref(),
+            Ok(&[[1, 1, 1, 1], [9, 9, 9, 9]][..])
+        );
+        assert_eq!(
+            parse_dns_servers("8.8.8.8").as_deref(),
+            Ok(&[[8, 8, 8, 8]][..])
+        );
+        assert_eq!(parse_dns_servers(" , "), Err(CommandError::InvalidAddress));
+        assert_eq!(
+            parse_dns_servers("1.1.1.1 dns.example.org"),
+            Err(CommandError::InvalidAddress)
+        );
+        assert_eq!(
+            parse_dns_servers("1.1.1.1,1.0.0.1,8.8.8.8,8.8.4.4"),
+            Err(CommandError::InvalidAddress)
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//Console commands. Each line is a command word, optionally followed by arguments - HELP
+//lists them.
+//
+//Config changes are staged, and only applied by a following confirm - any other command
+//abandons them.
+
+use heapless::String;
+
+use crate::address::{parse_dns_servers, parse_ipv4, parse_ipv4_cidr};
+
+//Command list shown by help
+pub const HELP: &str = "\
+help                        List the commands\r
+config                      Show the settings in use\r
+config set <key> <value>    Change a setting (the value is the rest of the line)\r
+config unset <key>          Revert a setting to the compiled-in default\r
+confirm / cancel            Apply or abandon a config change\r
+sync                        Sync the card database now\r
+db                          Show the database version and card count\r
+log                         Show the log events awaiting upload\r
+status                      Show the clock, network and retry status\r
+relay                       Briefly switch the relay on, to test it\r
+reboot                      Restart the controller\r
+\r
+Config keys: ssid, wifi_pw, ssid_2, wifi_pw_2, ssid_3, wifi_pw_3, ip_address, gateway,\r
+dns_servers, device_name, url_endpoint, latch_mode, api_token.\r
+Config changes take effect after a reboot.\r
+";
+
+//Longest config value
+pub const MAX_VALUE_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigKey {
+    Ssid,
+    WifiPw,
+    Ssid2, //Fallback networks, tried in turn if ssid can't be joined
+    WifiPw2,
+    Ssid3,
+    WifiPw3,
+    IpAddress, //Static address and prefix length, e.g. 192.168.1.50/24 - unset to use DHCP
+    Gateway,
+    DnsServers, //Comma separated
+    DeviceName,
+    UrlEndpoint,
+    LatchMode, //"latching", or the number of seconds a timed latch stays on for
+    ApiToken,
+}
+
+impl ConfigKey {
+    pub const ALL: [ConfigKey; 13] = [
+        ConfigKey::Ssid,
+        ConfigKey::WifiPw,
+        ConfigKey::Ssid2,
+        ConfigKey::WifiPw2,
+        ConfigKey::Ssid3,
+        ConfigKey::WifiPw3,
+        ConfigKey::IpAddress,
+        ConfigKey::Gateway,
+        ConfigKey::DnsServers,
+        ConfigKey::DeviceName,
+        ConfigKey::UrlEndpoint,
+        ConfigKey::LatchMode,
+        ConfigKey::ApiToken,
+    ];
+
+    //Name as typed at the console
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigKey::Ssid => "ssid",
+            ConfigKey::WifiPw => "wifi_pw",
+            ConfigKey::Ssid2 => "ssid_2",
+            ConfigKey::WifiPw2 => "wifi_pw_2",
+            ConfigKey::Ssid3 => "ssid_3",
+            ConfigKey::WifiPw3 => "wifi_pw_3",
+            ConfigKey::IpAddress => "ip_address",
+            ConfigKey::Gateway => "gateway",
+            ConfigKey::DnsServers => "dns_servers",
+            ConfigKey::DeviceName => "device_name",
+            ConfigKey::UrlEndpoint => "url_endpoint",
+            ConfigKey::LatchMode => "latch_mode",
+            ConfigKey::ApiToken => "api_token",
+        }
+    }
+
+    //Secrets are never echoed back
+    pub fn is_secret(&self) -> bool {
+        matches!(
+            self,
+            ConfigKey::WifiPw | ConfigKey::WifiPw2 | ConfigKey::WifiPw3 | ConfigKey::ApiToken
+        )
+    }
+
+    fn parse(name: &str) -> Result<Self, CommandError> {
+        ConfigKey::ALL
+            .into_iter()
+            .find(|key| key.as_str() == name)
+            .ok_or(CommandError::UnknownKey)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandError {
+    UnknownCommand,
+    UnknownKey,
+    MissingArgument,
+    UnexpectedArgument,
+    ValueTooLong,
+    InvalidLatchMode,
+    InvalidAddress,
+    NothingToConfirm, //confirm or cancel without a config change to apply
+}
+
+impl CommandError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandError::UnknownCommand => "unknown command - try 'help'",
+            CommandError::UnknownKey => "unknown config key",
+            CommandError::MissingArgument => "missing argument",
+            CommandError::UnexpectedArgument => "unexpected argument",
+            CommandError::ValueTooLong => "value too long",
+            CommandError::InvalidLatchMode => {
+                "latch_mode must be 'latching' or a number of seconds"
+            }
+            CommandError::InvalidAddress => {
+                "invalid address - e.g. 192.168.1.50/24 for ip_address, or 192.168.1.1"
+            }
+            CommandError::NothingToConfirm => "no config change to confirm",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    Help,
+    ShowConfig,
+    Set(ConfigKey, &'a str),
+    Unset(ConfigKey),
+    Confirm,
+    Cancel,
+    Sync,
+    Db,
+    Log,
+    Status,
+    RelayTest,
+    Reboot,
+}
+
+pub fn parse_command(line: &str) -> Result<Command<'_>, CommandError> {
+    let (word, args) = split_word(line.trim());
+    let command = match word {
+        "help" => Command::Help,
+        "config" => {
+            let (action, args) = split_word(args);
+            match action {
+                "" => Command::ShowConfig,
+                "set" => {
+                    let (key, value) = split_word(args);
+                    if value.is_empty() {
+                        return Err(CommandError::MissingArgument);
+                    }
+                    return Ok(Command::Set(ConfigKey::parse(key)?, value));
+                }
+                "unset" => {
+                    let (key, args) = split_word(args);
+                    if key.is_empty() {
+                        return Err(CommandError::MissingArgument);
+                    }
+                    if !args.is_empty() {
+                        return Err(CommandError::UnexpectedArgument);
+                    }
+                    return Ok(Command::Unset(ConfigKey::parse(key)?));
+                }
+                _ => return Err(CommandError::UnknownCommand),
+            }
+        }
+        "confirm" => Command::Confirm,
+        "cancel" => Command::Cancel,
+        "sync" => Command::Sync,
+        "db" => Command::Db,
+        "log" => Command::Log,
+        "status" => Command::Status,
+        "relay" => Command::RelayTest,
+        "reboot" => Command::Reboot,
+        _ => return Err(CommandError::UnknownCommand),
+    };
+    if !args.is_empty() {
+        return Err(CommandError::UnexpectedArgument);
+    }
+    Ok(command)
+}
+
+//Split off the first word, returning it and the (trimmed) rest of the line
+fn split_word(line: &str) -> (&str, &str) {
+    match line.split_once(|c: char| c.is_ascii_whitespace()) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (line, ""),
+    }
+}
+
+//A latch_mode value - None for latching, otherwise the timed latch in seconds
+pub fn parse_latch_mode(value: &str) -> Result<Option<u32>, CommandError> {
+    match value {
+        "latching" => Ok(None),
+        secs => secs
+            .parse()
+            .map(Some)
+            .map_err(|_| CommandError::InvalidLatchMode),
+    }
+}
+
+//A staged config change - a value of None reverts the setting to its default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingChange {
+    pub key: ConfigKey,
+    pub value: Option<String<MAX_VALUE_LEN>>,
+}
+
+//What the firmware should do in response to a line
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request<'a> {
+    Help,
+    ShowConfig,
+    Confirm(&'a PendingChange), //Ask for confirmation of this change
+    Apply(PendingChange),       //The change was confirmed
+    Cancelled,
+    Sync,
+    Db,
+    Log,
+    Status,
+    RelayTest,
+    Reboot,
+}
+
+#[derive(Default)]
+pub struct Console {
+    pending: Option<PendingChange>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle(&mut self, line: &str) -> Result<Request<'_>, CommandError> {
+        //Anything but a confirm abandons a staged change - including a mistyped command
+        let pending = self.pending.take();
+        let request = match parse_command(line)? {
+            Command::Help => Request::Help,
+            Command::ShowConfig => Request::ShowConfig,
+            Command::Set(key, value) => {
+                //Values the firmware will need to parse are checked up front
+                match key {
+                    ConfigKey::LatchMode => {
+                        parse_latch_mode(value)?;
+                    }
+                    ConfigKey::IpAddress => {
+                        parse_ipv4_cidr(value)?;
+                    }
+                    ConfigKey::Gateway => {
+                        parse_ipv4(value)?;
+                    }
+                    ConfigKey::DnsServers => {
+                        parse_dns_servers(value)?;
+                    }
+                    _ => (),
+                }
+                let mut string = String::new();
+                string
+                    .push_str(value)
+                    .map_err(|_| CommandError::ValueTooLong)?;
+                let change = self.pending.insert(PendingChange {
+                    key,
+                    value: Some(string),
+                });
+                Request::Confirm(change)
+            }
+            Command::Unset(key) => {
+                let change = self.pending.insert(PendingChange { key, value: None });
+                Request::Confirm(change)
+            }
+            Command::Confirm => Request::Apply(pending.ok_or(CommandError::NothingToConfirm)?),
+            Command::Cancel => {
+                pending.ok_or(CommandError::NothingToConfirm)?;
+                Request::Cancelled
+            }
+            Command::Sync => Request::Sync,
+            Command::Db => Request::Db,
+            Command::Log => Request::Log,
+            Command::Status => Request::Status,
+            Command::RelayTest => Request::RelayTest,
+            Command::Reboot => Request::Reboot,
+        };
+        Ok(request)
+    }
+
+    //Abandon a staged change, e.g. because the firmware rejected its value
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("help"), Ok(Command::Help));
+        assert_eq!(parse_command("  sync \t"), Ok(Command::Sync));
+        assert_eq!(parse_command("config"), Ok(Command::ShowConfig));
+        assert_eq!(parse_command("relay"), Ok(Command::RelayTest));
+        assert_eq!(parse_command("reboot"), Ok(Command::Reboot));
+        assert_eq!(parse_command("sink"), Err(CommandError::UnknownCommand));
+        assert_eq!(parse_command(""), Err(CommandError::UnknownCommand));
+        assert_eq!(
+            parse_command("db now"),
+            Err(CommandError::UnexpectedArgument)
+        );
+    }
+
+    #[test]
+    fn parses_config_changes() {
+        assert_eq!(
+            parse_command("config set ssid Makerspace"),
+            Ok(Command::Set(ConfigKey::Ssid, "Makerspace"))
+        );
+        //Values run to the end of the line, so may contain spaces
+        assert_eq!(
+            parse_command("config set wifi_pw  correct horse battery staple "),
+            Ok(Command::Set(
+                ConfigKey::WifiPw,
+                "correct horse battery staple"
+            ))
+        );
+        assert_eq!(
+            parse_command("config unset wifi_pw_3"),
+            Ok(Command::Unset(ConfigKey::WifiPw3))
+        );
+        assert_eq!(
+            parse_command("config unset latch_mode"),
+            Ok(Command::Unset(ConfigKey::LatchMode))
+        );
+        assert_eq!(
+            parse_command("config set ssid"),
+            Err(CommandError::MissingArgument)
+        );
+        assert_eq!(
+            parse_command("config set colour blue"),
+            Err(CommandError::UnknownKey)
+        );
+        assert_eq!(
+            parse_command("config unset"),
+            Err(CommandError::MissingArgument)
+        );
+        assert_eq!(
+            parse_command("config unset ssid now"),
+            Err(CommandError::UnexpectedArgument)
+        );
+        assert_eq!(
+            parse_command("config get ssid"),
+            Err(CommandError::UnknownCommand)
+        );
+    }
+
+    #[test]
+    fn parses_latch_modes() {
+        assert_eq!(parse_latch_mode("latching"), Ok(None));
+        assert_eq!(parse_latch_mode("30"), Ok(Some(30)));
+        assert_eq!(
+            parse_latch_mode("timed"),
+            Err(CommandError::InvalidLatchMode)
+        );
+        assert_eq!(parse_latch_mode("-1"), Err(CommandError::InvalidLatchMode));
+    }
+
+    #[test]
+    fn changes_need_confirmation() {
+        let mut console = Console::new();
+        let change = PendingChange {
+            key: ConfigKey::DeviceName,
+            value: Some(String::from("lathe")),
+        };
+        assert_eq!(
+            console.handle("config set device_name lathe"),
+            Ok(Request::Confirm(&change))
+        );
+        assert_eq!(console.handle("confirm"), Ok(Request::Apply(change)));
+        //Only once
+        assert_eq!(
+            console.handle("confirm"),
+            Err(CommandError::NothingToConfirm)
+        );
+    }
+
+    #[test]
+    fn other_commands_abandon_changes() {
+        let mut console = Console::new();
+        console.handle("config unset ssid").unwrap();
+        assert_eq!(console.handle("db"), Ok(Request::Db));
+        assert_eq!(
+            console.handle("confirm"),
+            Err(CommandError::NothingToConfirm)
+        );
+
+        console.handle("config unset ssid").unwrap();
+        assert_eq!(console.handle("cofnirm"), Err(CommandError::UnknownCommand));
+        assert_eq!(
+            console.handle("confirm"),
+            Err(CommandError::NothingToConfirm)
+        );
+
+        console.handle("config unset ssid").unwrap();
+        assert_eq!(console.handle("cancel"), Ok(Request::Cancelled));
+        assert_eq!(
+            console.handle("confirm"),
+            Err(CommandError::NothingToConfirm)
+        );
+
+        console.handle("config unset ssid").unwrap();
+        console.cancel();
+        assert_eq!(
+            console.handle("confirm"),
+            Err(CommandError::NothingToConfirm)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut console = Console::new();
+        assert_eq!(
+            console.handle("config set latch_mode sometimes"),
+            Err(CommandError::InvalidLatchMode)
+        );
+        assert_eq!(
+            console.handle("config set ip_address 192.168.1.50"),
+            Err(CommandError::InvalidAddress)
+        );
+        assert_eq!(
+            console.handle("config set dns_servers 1.1.1.1,,one"),
+            Err(CommandError::InvalidAddress)
+        );
+        let long = [b'a'; MAX_VALUE_LEN + 1];
+        let mut line: String<200> = String::from("config set ssid ");
+        line.push_str(core::str::from_utf8(&long).unwrap()).unwrap();
+        assert_eq!(console.handle(&line), Err(CommandError::ValueTooLong));
+    }
+}
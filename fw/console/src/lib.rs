@@ -0,0 +1,16 @@
+#![no_std]
+
+//Line based command console used for provisioning and diagnostics over USB serial.
+//Nothing in here touches hardware, so `cargo test` runs it on the host - the firmware
+//feeds received bytes in, and carries out the requests that come back.
+
+mod address;
+mod command;
+mod line;
+
+pub use address::{parse_dns_servers, parse_ipv4, parse_ipv4_cidr, MAX_DNS_SERVERS};
+pub use command::{
+    parse_command, parse_latch_mode, Command, CommandError, ConfigKey, Console, PendingChange,
+    Request, HELP, MAX_VALUE_LEN,
+};
+pub use line::{LineBuffer, MAX_LINE_LEN};
@@ -0,0 +1,96 @@
+//Assembles typed input into lines. Terminals send a line ending of CR, LF or CRLF, and
+//backspace (or delete) erases the previous character
+
+use heapless::String;
+
+pub const MAX_LINE_LEN: usize = 160;
+
+#[derive(Default)]
+pub struct LineBuffer {
+    line: String<MAX_LINE_LEN>,
+    overflow: bool, //Line too long - discarded when it ends
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //Feed in received bytes - returns a line when one is complete, leaving the rest of the
+    //input for the next call. Lines that were too long come back as Err
+    pub fn push(&mut self, input: &mut &[u8]) -> Option<Result<String<MAX_LINE_LEN>, ()>> {
+        while let Some((&byte, rest)) = input.split_first() {
+            *input = rest;
+            match byte {
+                b'\r' | b'\n' => {
+                    let line = core::mem::take(&mut self.line);
+                    if core::mem::take(&mut self.overflow) {
+                        return Some(Err(()));
+                    }
+                    //CRLF gives an empty line after the CR - ignore it
+                    if !line.is_empty() {
+                        return Some(Ok(line));
+                    }
+                }
+                0x08 | 0x7f => {
+                    self.line.pop();
+                }
+                //Printable ascii only - escape sequences (e.g. arrow keys) are dropped
+                0x20..=0x7e => self.overflow |= self.line.push(byte as char).is_err(),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(
+        buffer: &mut LineBuffer,
+        mut input: &[u8],
+    ) -> heapless::Vec<Result<String<MAX_LINE_LEN>, ()>, 4> {
+        let mut lines = heapless::Vec::new();
+        while let Some(line) = buffer.push(&mut input) {
+            lines.push(line).unwrap();
+        }
+        lines
+    }
+
+    #[test]
+    fn splits_lines() {
+        let mut buffer = LineBuffer::new();
+        let lines = lines(&mut buffer, b"help\r\nsync\rdb\n");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].as_deref(), Ok("help"));
+        assert_eq!(lines[1].as_deref(), Ok("sync"));
+        assert_eq!(lines[2].as_deref(), Ok("db"));
+    }
+
+    #[test]
+    fn lines_span_reads() {
+        let mut buffer = LineBuffer::new();
+        assert!(lines(&mut buffer, b"con").is_empty());
+        let lines = lines(&mut buffer, b"fig\r");
+        assert_eq!(lines[0].as_deref(), Ok("config"));
+    }
+
+    #[test]
+    fn handles_backspace() {
+        let mut buffer = LineBuffer::new();
+        let lines = lines(&mut buffer, b"syx\x08nc\x7f\x7f\x7f\x7f\x7fdb\r");
+        assert_eq!(lines[0].as_deref(), Ok("db"));
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let mut buffer = LineBuffer::new();
+        let long = [b'a'; MAX_LINE_LEN + 1];
+        assert!(lines(&mut buffer, &long).is_empty());
+        let lines = lines(&mut buffer, b"\rhelp\r");
+        assert_eq!(lines[0], Err(()));
+        assert_eq!(lines[1].as_deref(), Ok("help"));
+    }
+}
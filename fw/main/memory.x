@@ -1,7 +1,17 @@
+/* Bootloader-aware layout (see fw/bootloader) - the firmware runs from ACTIVE, after the
+   bootloader, and downloads updates into DFU. Must match fw/bootloader/memory.x */
 MEMORY
 {
-BOOT2   : ORIGIN = 0x10000000, LENGTH = 0x100
-FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-  RAM : ORIGIN = 0x20000000, LENGTH = 264K
+BOOT2            : ORIGIN = 0x10000000, LENGTH = 0x100
+BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+FLASH            : ORIGIN = 0x10007000, LENGTH = 992K
+DFU              : ORIGIN = 0x100FF000, LENGTH = 996K
+  RAM            : ORIGIN = 0x20000000, LENGTH = 264K
 }
 
+/* Offsets from the start of flash, as used by embassy-boot */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
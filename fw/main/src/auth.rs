@@ -0,0 +1,38 @@
+use defmt::*;
+
+use reqwless::response::StatusCode;
+
+use crate::CONFIG;
+
+//Longest api_token that fits in the Authorization header buffer
+pub(crate) const MAX_TOKEN_LEN: usize = 128;
+pub(crate) const AUTH_HEADER_BUF_LEN: usize = MAX_TOKEN_LEN + "Bearer ".len();
+
+//The Authorization header identifying this device to the backend, built in buf. Sent with
+//every request - as a single header slice, so it can be passed straight to reqwless
+pub(crate) fn header(buf: &mut [u8; AUTH_HEADER_BUF_LEN]) -> Option<(&'static str, &str)> {
+    let token = CONFIG.api_token?;
+    let value = format_no_std::show(buf, format_args!("Bearer {}", token))
+        .expect("api_token too long");
+    Some(("Authorization", value))
+}
+
+//The backend rejected our token (or we didn't send one)
+pub(crate) fn is_unauthorized(status: StatusCode) -> bool {
+    if matches!(status.0, 401 | 403) {
+        error!("Backend rejected device credentials - status {}", status.0);
+        return true;
+    }
+    false
+}
+
+//Warn at startup if the backend can't tell this device from any other client
+pub(crate) fn check_config() {
+    match CONFIG.api_token {
+        Some(token) => {
+            assert!(token.len() <= MAX_TOKEN_LEN, "api_token too long");
+            info!("Backend requests authenticated by bearer token");
+        }
+        None => warn!("No api_token configured - backend requests are unauthenticated"),
+    }
+}
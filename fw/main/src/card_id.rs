@@ -0,0 +1,39 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest as _, Sha256};
+
+use access_db::{CardIdAlgorithm, Digest, DIGEST_LEN};
+
+use crate::CONFIG;
+
+//Longest card UID - a triple size UID
+pub(crate) const MAX_UID_LEN: usize = 10;
+pub(crate) type CardUid = heapless::Vec<u8, MAX_UID_LEN>;
+
+//The database key for a card, under the given algorithm. The SHA-256 based digests are
+//truncated to the 16 bytes of the database keys
+pub(crate) fn card_digest(algorithm: CardIdAlgorithm, uid: &[u8]) -> Digest {
+    let mut digest = [0x00u8; DIGEST_LEN];
+    match algorithm {
+        CardIdAlgorithm::Md5 => digest = md5::compute(uid).0,
+        CardIdAlgorithm::Sha256 => {
+            let hash = Sha256::new()
+                .chain_update(CONFIG.card_id_secret)
+                .chain_update(uid)
+                .finalize();
+            digest.copy_from_slice(&hash[..DIGEST_LEN]);
+        }
+        CardIdAlgorithm::HmacSha256 => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(CONFIG.card_id_secret)
+                .expect("HMAC accepts any key length");
+            mac.update(uid);
+            digest.copy_from_slice(&mac.finalize().into_bytes()[..DIGEST_LEN]);
+        }
+    }
+    digest
+}
+
+//The admin card is identified the same way whatever the database uses, so it survives the
+//server changing algorithm
+pub(crate) fn admin_digest(uid: &[u8]) -> Digest {
+    card_digest(CardIdAlgorithm::HmacSha256, uid)
+}
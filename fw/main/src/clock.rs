@@ -0,0 +1,44 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_time::Instant;
+
+use defmt::Format;
+
+use serde::{Deserialize, Serialize};
+
+//Wall-clock time, held as the unix time (microseconds) at which the uptime counter was zero.
+//Until the SNTP task sets it, the time is unknown - schedules that restrict access then deny it
+static UNIX_MICROS_AT_BOOT: BlockingMutex<CriticalSectionRawMutex, Cell<Option<u64>>> =
+    BlockingMutex::new(Cell::new(None));
+
+//When something happened - unix time if the clock was synced, otherwise seconds since boot
+#[derive(Clone, Copy, Debug, Format, Serialize, Deserialize)]
+pub(crate) struct Timestamp {
+    pub time: u64,
+    pub synced: bool,
+}
+
+//Set the clock, given the unix time (microseconds) at the given instant
+pub(crate) fn set_unix_time(unix_micros: u64, at: Instant) {
+    let boot = unix_micros.saturating_sub(at.as_micros());
+    UNIX_MICROS_AT_BOOT.lock(|time| time.set(Some(boot)));
+}
+
+//Current unix time (seconds), if known
+pub(crate) fn unix_time() -> Option<u64> {
+    UNIX_MICROS_AT_BOOT
+        .lock(|time| time.get())
+        .map(|boot| (boot + Instant::now().as_micros()) / 1_000_000)
+}
+
+pub(crate) fn now() -> Timestamp {
+    match unix_time() {
+        Some(time) => Timestamp { time, synced: true },
+        None => Timestamp {
+            time: Instant::now().as_secs(),
+            synced: false,
+        },
+    }
+}
@@ -1,33 +1,166 @@
+use core::ops::Deref;
+
+use embassy_sync::once_lock::OnceLock;
 use embassy_time::Duration;
 
+use access_controller::SignOutPolicy;
+use access_db::{Role, Timezone};
+
+use crate::retry::RetryConfig;
+use crate::tls::TlsPsk;
+
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub(crate) enum LatchMode {
     Latching, //Device/controller will remain enabled until another card is scanned to disable it
     Timed(Duration), //Device controller will remain enabled for <time> then disable again
+    //Two different valid cards within <window> - one with at least the <supervisor> role, if
+    //set - then as Latching. Compiled-in only, it can't be set from the console
+    TwoPerson {
+        window: Duration,
+        supervisor: Option<Role>,
+    },
 }
 
-pub(crate) struct Config<'a> {
+//A wifi network to join - an empty password for an open network
+#[derive(Clone, Copy)]
+pub(crate) struct WifiNetwork<'a> {
     pub ssid: &'a str,
+    pub pw: &'a str,
+}
+
+pub(crate) const MAX_FALLBACK_NETWORKS: usize = 2;
+
+//Fixed addressing, for networks without DHCP
+#[derive(Clone, Copy)]
+pub(crate) struct StaticIpv4<'a> {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: Option<[u8; 4]>,
+    pub dns_servers: &'a [[u8; 4]], //At most console::MAX_DNS_SERVERS
+}
+
+pub(crate) struct Config<'a> {
+    pub ssid: &'a str, //Empty if not provisioned - the controller starts in provisioning mode
     pub wifi_pw: &'a str,
+    pub fallback_networks: [Option<WifiNetwork<'a>>; MAX_FALLBACK_NETWORKS], //Tried after ssid
+    //Failed passes through the networks at boot before falling back to provisioning mode
+    //(0 = never). Once connected, a dropped link is always rejoined
+    pub wifi_join_attempts: u32,
+    pub wifi_retry: RetryConfig, //Backoff between passes through the networks
+    pub static_ipv4: Option<StaticIpv4<'a>>, //None to use DHCP
+    pub provisioning_ap_pw: &'a str, //WPA2 passphrase (8-63 characters) for the provisioning AP
     pub device_name: &'a str,
     pub url_endpoint: &'a str,
+    pub tls_psk: Option<TlsPsk<'a>>, //Verifies the backend - requires an https url_endpoint
+    pub db_signing_key: Option<[u8; 32]>, //Ed25519 public key - if set, databases must be signed
+    pub firmware_signing_key: Option<[u8; 32]>, //Ed25519 public key - no OTA updates without one
+    pub api_token: Option<&'a str>, //Per-device secret, sent as a bearer token on every request
+    pub card_id_secret: &'a [u8], //Site secret (HMAC key, or salt) for hashing card UIDs
     pub db_prefix: &'a str,
     pub db_version_prefix: &'a str,
+    pub db_delta_prefix: &'a str,
     pub log_prefix: &'a str,
+    pub log_batch_prefix: Option<&'a str>, //None if the backend only accepts single events
+    pub firmware_version_prefix: &'a str,
+    pub firmware_prefix: &'a str,
     pub http_timeout: Duration,
     pub latch_mode: LatchMode,
+    pub sign_out: SignOutPolicy, //Latching mode only - which cards can end a session
+    pub handover: bool, //Latching mode only - another valid card takes over a session
+    pub max_session: Option<Duration>, //Latching mode only - sessions are ended after this
+    pub session_warning: Duration, //How long the LEDs flash before max_session is reached
     pub db_sync_frequency: Duration,
+    pub db_sync_retry: RetryConfig, //Backoff after a failed sync, instead of db_sync_frequency
+    pub log_retry: RetryConfig,
+    pub timezone: Timezone, //Local time, used for access schedules
+    pub ntp_server: &'a str,
+    pub sntp_sync_frequency: Duration,
+    pub ota_self_test_timeout: Duration, //Updated firmware must sync the database within this
+}
+
+impl Config<'_> {
+    //The networks to try, in order
+    pub(crate) fn wifi_networks(&self) -> impl Iterator<Item = WifiNetwork<'_>> {
+        let primary = WifiNetwork {
+            ssid: self.ssid,
+            pw: self.wifi_pw,
+        };
+        core::iter::once(primary)
+            .chain(self.fallback_networks.iter().flatten().copied())
+            .filter(|network| !network.ssid.is_empty())
+    }
+}
+
+//The settings in use - the compiled-in defaults below, overridden by any stored in flash
+//(see config_store). Until config_store::load() runs, the defaults are used
+pub(crate) static CONFIG: RuntimeConfig = RuntimeConfig(OnceLock::new());
+
+pub(crate) struct RuntimeConfig(OnceLock<Config<'static>>);
+
+impl RuntimeConfig {
+    pub(crate) fn init(&self, config: Config<'static>) {
+        assert!(self.0.init(config).is_ok(), "Config already loaded");
+    }
+}
+
+impl Deref for RuntimeConfig {
+    type Target = Config<'static>;
+    fn deref(&self) -> &Config<'static> {
+        static DEFAULTS: Config<'static> = DEFAULT_CONFIG;
+        self.0.try_get().unwrap_or(&DEFAULTS)
+    }
 }
 
-pub(crate) static CONFIG: Config = Config {
-    ssid: "YOUR_SSID",
-    wifi_pw: "YOUR_WIFI_PW",
+//Shared by every controller - per-controller settings (wifi, device name etc) can be
+//provisioned into flash instead, so one firmware build suits all of them
+pub(crate) const DEFAULT_CONFIG: Config<'static> = Config {
+    ssid: "", //Provisioned over the provisioning AP or USB console - or set one here
+    wifi_pw: "",
+    //e.g. [Some(WifiNetwork { ssid: "Workshop", pw: "..." }), None]
+    fallback_networks: [None; MAX_FALLBACK_NETWORKS],
+    wifi_join_attempts: 10,
+    wifi_retry: RetryConfig {
+        base: Duration::from_secs(5),
+        cap: Duration::from_secs(5 * 60),
+        jitter_percent: 20,
+    },
+    //e.g. Some(StaticIpv4 { address: [192, 168, 1, 50], prefix_len: 24, ... })
+    static_ipv4: None,
+    provisioning_ap_pw: "YOUR_AP_PW",
     device_name: "DEVICE_NAME",
     url_endpoint: "http://YOUR_URL_ENDPOINT",
+    tls_psk: None, //e.g. Some(TlsPsk { identity: b"DEVICE_NAME", key: &[...] })
+    db_signing_key: None,
+    firmware_signing_key: None,
+    api_token: None,
+    card_id_secret: b"", //Must match the server - only used if it asks for sha256/hmac-sha256
     db_prefix: "db",
     db_version_prefix: "dbVersion",
+    db_delta_prefix: "dbDelta",
     log_prefix: "logEvent",
+    log_batch_prefix: None, //e.g. Some("logEvents")
+    firmware_version_prefix: "fwVersion",
+    firmware_prefix: "firmware",
     http_timeout: Duration::from_secs(10),
     latch_mode: LatchMode::Timed(Duration::from_secs(5)),
+    sign_out: SignOutPolicy::AnyCard, //e.g. SignOutPolicy::SameCardOrSupervisor(Role::Maintainer)
+    handover: false,
+    max_session: None, //e.g. Some(Duration::from_secs(4 * 60 * 60))
+    session_warning: Duration::from_secs(60),
     db_sync_frequency: Duration::from_secs(5 * 60),
+    db_sync_retry: RetryConfig {
+        base: Duration::from_secs(30),
+        cap: Duration::from_secs(60 * 60),
+        jitter_percent: 20,
+    },
+    log_retry: RetryConfig {
+        base: Duration::from_secs(10),
+        cap: Duration::from_secs(30 * 60),
+        jitter_percent: 20,
+    },
+    timezone: Timezone::UK,
+    ntp_server: "pool.ntp.org",
+    sntp_sync_frequency: Duration::from_secs(60 * 60),
+    ota_self_test_timeout: Duration::from_secs(10 * 60),
 };
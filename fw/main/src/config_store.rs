@@ -0,0 +1,367 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::once_lock::OnceLock;
+use embassy_time::Duration;
+
+use defmt::{Format, *};
+
+use ekv::{config, Database};
+
+use heapless::{String, Vec};
+
+use serde::{Deserialize, Serialize};
+
+use static_cell::StaticCell;
+
+use access_db::{Digest, DIGEST_LEN};
+
+use console::MAX_DNS_SERVERS;
+
+use crate::auth::MAX_TOKEN_LEN;
+use crate::config::{Config, LatchMode, StaticIpv4, WifiNetwork, CONFIG, DEFAULT_CONFIG};
+use crate::flash::{FlashDevice, FlashRegion, SharedFlash, CONFIG_SIZE, CONFIG_START_ADDR};
+
+//Settings are stored as a single key, prefixed with a format byte
+const CONFIG_KEY: &[u8] = b"config";
+const CONFIG_FORMAT: u8 = 3;
+//StoredConfig's field count in each format, from format 1. Each format only adds Option
+//fields at the end, so older settings decode once a None (a single zero byte, to postcard)
+//is appended for each field they lack
+const FORMAT_FIELDS: [usize; CONFIG_FORMAT as usize] = [6, 10, 13];
+//The admin card is kept under its own key, as it is enrolled at the reader rather than
+//provisioned with the other settings
+const ADMIN_CARD_KEY: &[u8] = b"admin_card";
+//Largest possible stored config - format byte, and every field at its maximum length
+const MAX_STORED_CONFIG_LEN: usize = 768;
+
+pub(crate) const MAX_SSID_LEN: usize = 32;
+pub(crate) const MAX_WIFI_PW_LEN: usize = 63;
+pub(crate) const MAX_DEVICE_NAME_LEN: usize = 32;
+//Leaves room in the 128 byte URL buffers for the device name and prefix
+pub(crate) const MAX_URL_ENDPOINT_LEN: usize = 64;
+//Longest timed latch - a day
+const MAX_LATCH_SECS: u32 = 24 * 60 * 60;
+
+type ConfigDb = Database<FlashRegion<'static, FlashDevice>, ThreadModeRawMutex>;
+
+static CONFIG_DB: OnceLock<ConfigDb> = OnceLock::new();
+//The loaded settings - CONFIG borrows its strings from here
+static LOADED: StaticCell<StoredConfig> = StaticCell::new();
+static ADMIN_CARD: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Digest>>> =
+    BlockingMutex::new(Cell::new(None));
+
+#[derive(Debug, Format)]
+pub(crate) enum ConfigError {
+    FlashError,
+    EncodeError,
+    DecodeError,        //Corrupt, or written by newer firmware
+    InvalidSsid,        //Empty
+    InvalidWifiPw,      //WPA2 passwords are 8-63 characters - empty for an open network
+    InvalidDeviceName,  //Empty, or containing characters that aren't safe in a URL path
+    InvalidUrlEndpoint, //Not http:// or https://, or with a trailing '/'
+    InvalidLatchMode,   //Timed latch of zero, or longer than a day
+    InvalidApiToken,    //Empty
+    InvalidIpAddress,   //Prefix length not 1-32
+    InvalidGateway,     //Not on the static address's subnet
+    InvalidDnsServers,  //None
+    NotAuthorised,      //Provisioning settings saved before the admin card was presented
+}
+
+impl ConfigError {
+    //For the provisioning interfaces
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ConfigError::FlashError => "flash error",
+            ConfigError::EncodeError => "settings too large to store",
+            ConfigError::DecodeError => "stored settings unreadable",
+            ConfigError::InvalidSsid => "ssid can't be empty",
+            ConfigError::InvalidWifiPw => {
+                "wifi_pw must be 8-63 characters, or empty for an open network"
+            }
+            ConfigError::InvalidDeviceName => {
+                "device_name may only contain letters, digits, '-', '_' and '.'"
+            }
+            ConfigError::InvalidUrlEndpoint => {
+                "url_endpoint must start http:// or https://, and not end with '/'"
+            }
+            ConfigError::InvalidLatchMode => "latch_mode must be between 1 second and a day",
+            ConfigError::InvalidApiToken => "api_token can't be empty",
+            ConfigError::InvalidIpAddress => "ip_address must be like 192.168.1.50/24",
+            ConfigError::InvalidGateway => "gateway must be on ip_address's subnet",
+            ConfigError::InvalidDnsServers => "dns_servers must be 1-3 comma separated addresses",
+            ConfigError::NotAuthorised => "present the admin card to the reader first",
+        }
+    }
+}
+
+//Stored form of LatchMode
+#[derive(Clone, Copy, Debug, Format, Serialize, Deserialize, PartialEq)]
+pub(crate) enum StoredLatchMode {
+    Latching,
+    Timed(u32), //Seconds
+}
+
+impl From<StoredLatchMode> for LatchMode {
+    fn from(mode: StoredLatchMode) -> Self {
+        match mode {
+            StoredLatchMode::Latching => LatchMode::Latching,
+            StoredLatchMode::Timed(secs) => LatchMode::Timed(Duration::from_secs(secs as u64)),
+        }
+    }
+}
+
+//Stored form of a static address
+#[derive(Clone, Copy, Debug, Format, Serialize, Deserialize, PartialEq)]
+pub(crate) struct StoredIpv4Cidr {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+}
+
+//Per-controller settings, provisioned into flash. Any that aren't set use the compiled-in
+//defaults. New fields must be added at the end, as Options, with a new CONFIG_FORMAT and
+//FORMAT_FIELDS entry
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub(crate) struct StoredConfig {
+    pub ssid: Option<String<MAX_SSID_LEN>>,
+    pub wifi_pw: Option<String<MAX_WIFI_PW_LEN>>,
+    pub device_name: Option<String<MAX_DEVICE_NAME_LEN>>,
+    pub url_endpoint: Option<String<MAX_URL_ENDPOINT_LEN>>,
+    pub latch_mode: Option<StoredLatchMode>,
+    pub api_token: Option<String<MAX_TOKEN_LEN>>,
+    //Format 2
+    pub ssid_2: Option<String<MAX_SSID_LEN>>,
+    pub wifi_pw_2: Option<String<MAX_WIFI_PW_LEN>>,
+    pub ssid_3: Option<String<MAX_SSID_LEN>>,
+    pub wifi_pw_3: Option<String<MAX_WIFI_PW_LEN>>,
+    //Format 3
+    pub ip_address: Option<StoredIpv4Cidr>,
+    pub gateway: Option<[u8; 4]>,
+    pub dns_servers: Option<Vec<[u8; 4], MAX_DNS_SERVERS>>,
+}
+
+impl StoredConfig {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        for ssid in [&self.ssid, &self.ssid_2, &self.ssid_3] {
+            if ssid.as_ref().is_some_and(|ssid| ssid.is_empty()) {
+                return Err(ConfigError::InvalidSsid);
+            }
+        }
+        for pw in [&self.wifi_pw, &self.wifi_pw_2, &self.wifi_pw_3] {
+            if pw.as_ref().is_some_and(|pw| !pw.is_empty() && pw.len() < 8) {
+                return Err(ConfigError::InvalidWifiPw);
+            }
+        }
+        if self.device_name.as_ref().is_some_and(|name| {
+            name.is_empty()
+                || !name
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"-_.".contains(&byte))
+        }) {
+            return Err(ConfigError::InvalidDeviceName);
+        }
+        if self.url_endpoint.as_ref().is_some_and(|url| {
+            !(url.starts_with("http://") || url.starts_with("https://")) || url.ends_with('/')
+        }) {
+            return Err(ConfigError::InvalidUrlEndpoint);
+        }
+        if let Some(StoredLatchMode::Timed(secs)) = self.latch_mode {
+            if secs == 0 || secs > MAX_LATCH_SECS {
+                return Err(ConfigError::InvalidLatchMode);
+            }
+        }
+        if self.api_token.as_ref().is_some_and(|token| token.is_empty()) {
+            return Err(ConfigError::InvalidApiToken);
+        }
+        if let Some(ip) = self.ip_address {
+            if !(1..=32).contains(&ip.prefix_len) {
+                return Err(ConfigError::InvalidIpAddress);
+            }
+            let mask = u32::MAX << (32 - ip.prefix_len);
+            let subnet = u32::from_be_bytes(ip.address) & mask;
+            if self
+                .gateway
+                .is_some_and(|gateway| u32::from_be_bytes(gateway) & mask != subnet)
+            {
+                return Err(ConfigError::InvalidGateway);
+            }
+        }
+        if self.dns_servers.as_ref().is_some_and(|servers| servers.is_empty()) {
+            return Err(ConfigError::InvalidDnsServers);
+        }
+        Ok(())
+    }
+
+    //The defaults, with any stored settings applied
+    fn apply(&'static self, defaults: Config<'static>) -> Config<'static> {
+        Config {
+            ssid: self.ssid.as_deref().unwrap_or(defaults.ssid),
+            wifi_pw: self.wifi_pw.as_deref().unwrap_or(defaults.wifi_pw),
+            fallback_networks: [
+                fallback_network(&self.ssid_2, &self.wifi_pw_2, defaults.fallback_networks[0]),
+                fallback_network(&self.ssid_3, &self.wifi_pw_3, defaults.fallback_networks[1]),
+            ],
+            static_ipv4: self.static_ipv4(defaults.static_ipv4),
+            device_name: self.device_name.as_deref().unwrap_or(defaults.device_name),
+            url_endpoint: self.url_endpoint.as_deref().unwrap_or(defaults.url_endpoint),
+            latch_mode: self.latch_mode.map(LatchMode::from).unwrap_or(defaults.latch_mode),
+            api_token: self.api_token.as_deref().or(defaults.api_token),
+            ..defaults
+        }
+    }
+
+    //Static addressing, with any stored settings applied over the default. A stored
+    //ip_address switches from DHCP to static addressing
+    fn static_ipv4(
+        &'static self,
+        default: Option<StaticIpv4<'static>>,
+    ) -> Option<StaticIpv4<'static>> {
+        let (address, prefix_len) = match (self.ip_address, default) {
+            (Some(ip), _) => (ip.address, ip.prefix_len),
+            (None, Some(default)) => (default.address, default.prefix_len),
+            (None, None) => return None,
+        };
+        Some(StaticIpv4 {
+            address,
+            prefix_len,
+            gateway: self.gateway.or(default.and_then(|default| default.gateway)),
+            dns_servers: match (&self.dns_servers, default) {
+                (Some(servers), _) => &servers[..],
+                (None, Some(default)) => default.dns_servers,
+                (None, None) => &[],
+            },
+        })
+    }
+}
+
+//A fallback network, with any stored ssid or password applied over the default
+fn fallback_network(
+    ssid: &'static Option<String<MAX_SSID_LEN>>,
+    pw: &'static Option<String<MAX_WIFI_PW_LEN>>,
+    default: Option<WifiNetwork<'static>>,
+) -> Option<WifiNetwork<'static>> {
+    let ssid = ssid.as_deref().or(default.map(|network| network.ssid))?;
+    let pw = pw.as_deref().or(default.map(|network| network.pw));
+    Some(WifiNetwork {
+        ssid,
+        pw: pw.unwrap_or(""),
+    })
+}
+
+//Mount the config store, and load CONFIG from it. Must be called before any other task
+//reads CONFIG. A missing or invalid stored config leaves the compiled-in defaults in use
+pub(crate) async fn load(flash: &'static SharedFlash<FlashDevice>) {
+    let db = Database::new(
+        FlashRegion {
+            start: CONFIG_START_ADDR,
+            page_count: CONFIG_SIZE / config::PAGE_SIZE,
+            flash,
+        },
+        ekv::Config::default(),
+    );
+    if db.mount().await.is_err() {
+        info!("No valid config store found - formatting...");
+        db.format().await.expect("Flash format failure");
+    }
+    assert!(CONFIG_DB.init(db).is_ok(), "Config store already mounted");
+
+    let stored = match read_stored().await {
+        Ok(Some(stored)) => {
+            info!("Loaded stored config");
+            stored
+        }
+        Ok(None) => {
+            info!("No stored config - using compiled-in defaults");
+            StoredConfig::default()
+        }
+        Err(e) => {
+            error!("Stored config rejected - {} - using compiled-in defaults", e);
+            StoredConfig::default()
+        }
+    };
+    let stored: &'static StoredConfig = LOADED.init(stored);
+    CONFIG.init(stored.apply(DEFAULT_CONFIG));
+
+    let rtx = CONFIG_DB.get().await.read_transaction().await;
+    let mut card = [0x00u8; DIGEST_LEN];
+    match rtx.read(ADMIN_CARD_KEY, &mut card).await {
+        Ok(DIGEST_LEN) => ADMIN_CARD.lock(|admin| admin.set(Some(card))),
+        Ok(_) | Err(ekv::ReadError::KeyNotFound) => info!("No admin card enrolled"),
+        Err(_) => error!("Unable to read admin card"),
+    }
+}
+
+//The settings currently in flash (which may have changed since they were loaded)
+pub(crate) async fn read_stored() -> Result<Option<StoredConfig>, ConfigError> {
+    let db = CONFIG_DB.get().await;
+    let rtx = db.read_transaction().await;
+    let mut buf = [0x00u8; MAX_STORED_CONFIG_LEN];
+    let len = match rtx.read(CONFIG_KEY, &mut buf).await {
+        Ok(len) => len,
+        Err(ekv::ReadError::KeyNotFound) => return Ok(None),
+        Err(_) => return Err(ConfigError::FlashError),
+    };
+    let format = match buf[..len].first() {
+        Some(&format) if (1..=CONFIG_FORMAT).contains(&format) => format as usize,
+        _ => return Err(ConfigError::DecodeError),
+    };
+    //Pad settings from older firmware out to the current format
+    let missing = FORMAT_FIELDS[FORMAT_FIELDS.len() - 1] - FORMAT_FIELDS[format - 1];
+    buf.get_mut(len..len + missing)
+        .ok_or(ConfigError::DecodeError)?
+        .fill(0x00);
+    let stored: StoredConfig =
+        postcard::from_bytes(&buf[1..len + missing]).map_err(|_| ConfigError::DecodeError)?;
+    stored.validate()?;
+    Ok(Some(stored))
+}
+
+//The settings currently in flash, as the starting point for a change. Settings that can't be
+//read are replaced - they wouldn't be used at boot anyway
+pub(crate) async fn read_stored_or_default() -> StoredConfig {
+    match read_stored().await {
+        Ok(stored) => stored.unwrap_or_default(),
+        Err(e) => {
+            warn!("Stored config unreadable ({}) - starting from the defaults", e);
+            StoredConfig::default()
+        }
+    }
+}
+
+//Validate and store new settings. They take effect from the next boot
+pub(crate) async fn save(stored: &StoredConfig) -> Result<(), ConfigError> {
+    stored.validate()?;
+    let mut buf = [0x00u8; MAX_STORED_CONFIG_LEN];
+    buf[0] = CONFIG_FORMAT;
+    let len = postcard::to_slice(stored, &mut buf[1..])
+        .map_err(|_| ConfigError::EncodeError)?
+        .len();
+
+    let db = CONFIG_DB.get().await;
+    let mut wtx = db.write_transaction().await;
+    wtx.write(CONFIG_KEY, &buf[..1 + len])
+        .await
+        .map_err(|_| ConfigError::FlashError)?;
+    wtx.commit().await.map_err(|_| ConfigError::FlashError)?;
+    info!("Config saved - reboot to apply");
+    Ok(())
+}
+
+//The enrolled admin card's digest (see card_id::admin_digest), if any
+pub(crate) fn admin_card() -> Option<Digest> {
+    ADMIN_CARD.lock(|admin| admin.get())
+}
+
+//Enrol a new admin card, replacing any previous one
+pub(crate) async fn enrol_admin_card(card: &Digest) -> Result<(), ConfigError> {
+    let db = CONFIG_DB.get().await;
+    let mut wtx = db.write_transaction().await;
+    wtx.write(ADMIN_CARD_KEY, card)
+        .await
+        .map_err(|_| ConfigError::FlashError)?;
+    wtx.commit().await.map_err(|_| ConfigError::FlashError)?;
+    ADMIN_CARD.lock(|admin| admin.set(Some(*card)));
+    info!("Admin card enrolled");
+    Ok(())
+}
@@ -0,0 +1,482 @@
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+
+use embassy_futures::join::join;
+use embassy_net::Stack;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::Builder;
+
+use defmt::*;
+
+use heapless::String;
+
+use console::{
+    parse_dns_servers, parse_ipv4, parse_ipv4_cidr, parse_latch_mode, ConfigKey, Console,
+    LineBuffer, PendingChange, Request, HELP,
+};
+
+use crate::clock;
+use crate::config::{LatchMode, CONFIG};
+use crate::config_store::{self, ConfigError, StoredConfig, StoredIpv4Cidr, StoredLatchMode};
+use crate::database_task::{DB_STATUS, DB_SYNC_REQUEST_SIGNAL};
+use crate::log_task::{event_json, LOG_DUMP_REQUEST_SIGNAL, LOG_DUMP_SIGNAL, MAX_EVENT_JSON_LEN};
+use crate::main_task::RELAY_TEST_SIGNAL;
+use crate::ota_task::{self, FIRMWARE_VERSION};
+use crate::retry::{SharedRetryStatus, DB_SYNC_RETRY_STATUS, LOG_RETRY_STATUS, WIFI_RETRY_STATUS};
+use crate::wifi_task::{wifi_status, WifiState};
+use crate::{Irqs, UsbResources};
+
+//Placeholder USB IDs, as used by the embassy examples - not allocated to us. Replace with an
+//allocated VID/PID (e.g. from pid.codes) before shipping devices
+const USB_VID: u16 = 0xc0de;
+const USB_PID: u16 = 0xcafe;
+const MAX_PACKET_SIZE: usize = 64;
+//Longest reply - a full log dump
+const MAX_REPLY_LEN: usize = 3072;
+//How long to wait for the log task to answer a dump request - it may be mid-upload
+const LOG_DUMP_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Class<'d> = CdcAcmClass<'d, Driver<'d, USB>>;
+type Reply = String<MAX_REPLY_LEN>;
+
+//Replies are truncated if they don't fit - the write! results are deliberately ignored
+macro_rules! reply {
+    ($reply:expr, $($arg:tt)*) => {
+        let _ = write!($reply, $($arg)*);
+        let _ = $reply.push_str("\r\n");
+    };
+}
+
+#[embassy_executor::task]
+pub async fn console_task(usb: UsbResources, stack: Stack<'static>) -> ! {
+    let driver = Driver::new(usb.usb, Irqs);
+
+    let mut config = embassy_usb::Config::new(USB_VID, USB_PID);
+    config.manufacturer = Some("Makerspace");
+    config.product = Some("Access controller console");
+    config.serial_number = Some(CONFIG.device_name);
+    config.max_power = 100;
+    config.max_packet_size_0 = MAX_PACKET_SIZE as u8;
+
+    let mut config_descriptor = [0x00u8; 256];
+    let mut bos_descriptor = [0x00u8; 256];
+    let mut control_buf = [0x00u8; 64];
+    let mut state = State::new();
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut [],
+        &mut control_buf,
+    );
+    let mut class = CdcAcmClass::new(&mut builder, &mut state, MAX_PACKET_SIZE as u16);
+    let mut usb = builder.build();
+
+    info!("USB console ready");
+    //Neither future returns
+    join(usb.run(), run_console(&mut class, stack)).await.0
+}
+
+async fn run_console(class: &mut Class<'_>, stack: Stack<'static>) -> ! {
+    let mut console = Console::new();
+    let mut lines = LineBuffer::new();
+    loop {
+        class.wait_connection().await;
+        info!("Console connected");
+        let _ = send(
+            class,
+            b"\r\nAccess controller console - type 'help' for commands\r\n> ",
+        )
+        .await;
+        //Returns an error once the terminal disconnects
+        let _ = session(class, &mut console, &mut lines, stack).await;
+        info!("Console disconnected");
+        console.cancel();
+        lines = LineBuffer::new();
+    }
+}
+
+//Handle commands until the terminal disconnects
+async fn session(
+    class: &mut Class<'_>,
+    console: &mut Console,
+    lines: &mut LineBuffer,
+    stack: Stack<'static>,
+) -> Result<(), EndpointError> {
+    let mut buf = [0x00u8; MAX_PACKET_SIZE];
+    loop {
+        let len = class.read_packet(&mut buf).await?;
+
+        //Echo what was typed, as terminals don't
+        let mut echo: String<{ 3 * MAX_PACKET_SIZE }> = String::new();
+        for &byte in &buf[..len] {
+            let _ = match byte {
+                b'\r' | b'\n' => echo.push_str("\r\n"),
+                0x08 | 0x7f => echo.push_str("\x08 \x08"),
+                0x20..=0x7e => echo.push(byte as char),
+                _ => Ok(()),
+            };
+        }
+        send(class, echo.as_bytes()).await?;
+
+        let mut input = &buf[..len];
+        while let Some(line) = lines.push(&mut input) {
+            let mut reply = Reply::new();
+            let reboot = match line {
+                Ok(line) => handle_line(console, &line, stack, &mut reply).await,
+                Err(()) => {
+                    reply!(reply, "error: line too long");
+                    false
+                }
+            };
+            let _ = reply.push_str("> ");
+            send(class, reply.as_bytes()).await?;
+            if reboot {
+                warn!("Rebooting at console request");
+                //Give the reply a chance to reach the host
+                Timer::after_millis(100).await;
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
+    }
+}
+
+//Send text in packet sized chunks. A transfer that fills its last packet is ended with an
+//empty packet, so the host doesn't wait for more
+async fn send(class: &mut Class<'_>, text: &[u8]) -> Result<(), EndpointError> {
+    for chunk in text.chunks(MAX_PACKET_SIZE) {
+        class.write_packet(chunk).await?;
+    }
+    if !text.is_empty() && text.len() % MAX_PACKET_SIZE == 0 {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+//Carry out a command, writing the reply. Returns true if the controller should reboot
+async fn handle_line(
+    console: &mut Console,
+    line: &str,
+    stack: Stack<'static>,
+    reply: &mut Reply,
+) -> bool {
+    //A change with an invalid value is dropped straight away, rather than awaiting confirmation
+    let mut rejected = false;
+    match console.handle(line) {
+        Ok(Request::Help) => {
+            let _ = reply.push_str(HELP);
+        }
+        Ok(Request::ShowConfig) => show_config(reply),
+        Ok(Request::Confirm(change)) => {
+            let change = change.clone();
+            match changed_config(&change).await {
+                Ok(_) => {
+                    let _ = reply.push_str("Change ");
+                    describe_change(reply, &change);
+                    reply!(reply, "? Type 'confirm' to save it, or 'cancel'");
+                }
+                Err(e) => {
+                    rejected = true;
+                    reply!(reply, "error: {}", e.as_str());
+                }
+            }
+        }
+        Ok(Request::Apply(change)) => {
+            let result = match changed_config(&change).await {
+                Ok(stored) => config_store::save(&stored).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) => {
+                    info!("Config change saved from console - {}", change.key);
+                    let _ = reply.push_str("Saved ");
+                    describe_change(reply, &change);
+                    reply!(reply, " - reboot to apply");
+                }
+                Err(e) => {
+                    error!("Unable to save config change - {}", e);
+                    reply!(reply, "error: unable to save - {}", e.as_str());
+                }
+            }
+        }
+        Ok(Request::Cancelled) => {
+            reply!(reply, "Change cancelled");
+        }
+        Ok(Request::Sync) => {
+            DB_SYNC_REQUEST_SIGNAL.signal(());
+            reply!(reply, "Database sync requested");
+        }
+        Ok(Request::Db) => show_db(reply),
+        Ok(Request::Log) => show_log(reply).await,
+        Ok(Request::Status) => show_status(reply, stack),
+        Ok(Request::RelayTest) => {
+            RELAY_TEST_SIGNAL.signal(());
+            reply!(reply, "Relay test requested");
+        }
+        Ok(Request::Reboot) => {
+            reply!(reply, "Rebooting...");
+            return true;
+        }
+        Err(e) => {
+            reply!(reply, "error: {}", e.as_str());
+        }
+    }
+    if rejected {
+        console.cancel();
+    }
+    false
+}
+
+fn show_config(reply: &mut Reply) {
+    for key in ConfigKey::ALL {
+        let _ = write!(reply, "{:<14}", key.as_str());
+        let value = match key {
+            ConfigKey::Ssid => Some(CONFIG.ssid),
+            ConfigKey::WifiPw => Some(CONFIG.wifi_pw),
+            ConfigKey::Ssid2 => CONFIG.fallback_networks[0].map(|network| network.ssid),
+            ConfigKey::WifiPw2 => CONFIG.fallback_networks[0].map(|network| network.pw),
+            ConfigKey::Ssid3 => CONFIG.fallback_networks[1].map(|network| network.ssid),
+            ConfigKey::WifiPw3 => CONFIG.fallback_networks[1].map(|network| network.pw),
+            ConfigKey::IpAddress => match CONFIG.static_ipv4 {
+                Some(ip) => {
+                    reply!(reply, "{}/{}", Ipv4Addr::from(ip.address), ip.prefix_len);
+                    continue;
+                }
+                None => Some("(not set - using DHCP)"),
+            },
+            ConfigKey::Gateway => match CONFIG.static_ipv4.and_then(|ip| ip.gateway) {
+                Some(gateway) => {
+                    reply!(reply, "{}", Ipv4Addr::from(gateway));
+                    continue;
+                }
+                None => None,
+            },
+            ConfigKey::DnsServers => match CONFIG.static_ipv4 {
+                Some(ip) if !ip.dns_servers.is_empty() => {
+                    for (i, server) in ip.dns_servers.iter().enumerate() {
+                        let separator = if i > 0 { "," } else { "" };
+                        let _ = write!(reply, "{}{}", separator, Ipv4Addr::from(*server));
+                    }
+                    reply!(reply, "");
+                    continue;
+                }
+                _ => None,
+            },
+            ConfigKey::DeviceName => Some(CONFIG.device_name),
+            ConfigKey::UrlEndpoint => Some(CONFIG.url_endpoint),
+            ConfigKey::LatchMode => match CONFIG.latch_mode {
+                LatchMode::Latching => Some("latching"),
+                LatchMode::Timed(time) => {
+                    reply!(reply, "{} seconds", time.as_secs());
+                    continue;
+                }
+                LatchMode::TwoPerson { window, .. } => {
+                    reply!(reply, "two-person, within {} seconds", window.as_secs());
+                    continue;
+                }
+            },
+            ConfigKey::ApiToken => CONFIG.api_token,
+        };
+        //Secrets are never shown
+        match value {
+            None => {
+                reply!(reply, "(not set)");
+            }
+            Some(_) if key.is_secret() => {
+                reply!(reply, "(set)");
+            }
+            Some(value) => {
+                reply!(reply, "{}", value);
+            }
+        }
+    }
+    reply!(reply, "Changes saved since boot take effect after a reboot");
+}
+
+fn describe_change(reply: &mut Reply, change: &PendingChange) {
+    let _ = match (&change.value, change.key.is_secret()) {
+        (None, _) => write!(reply, "{} to the default", change.key.as_str()),
+        (Some(_), true) => write!(reply, "{} to (hidden)", change.key.as_str()),
+        (Some(value), false) => write!(reply, "{} to '{}'", change.key.as_str(), value),
+    };
+}
+
+//The settings currently in flash, with the change made - checked, but not saved
+async fn changed_config(change: &PendingChange) -> Result<StoredConfig, ConfigError> {
+    let mut stored = config_store::read_stored_or_default().await;
+    let value = change.value.as_deref();
+    match change.key {
+        ConfigKey::Ssid => stored.ssid = field(value, ConfigError::InvalidSsid)?,
+        ConfigKey::WifiPw => stored.wifi_pw = field(value, ConfigError::InvalidWifiPw)?,
+        ConfigKey::Ssid2 => stored.ssid_2 = field(value, ConfigError::InvalidSsid)?,
+        ConfigKey::WifiPw2 => stored.wifi_pw_2 = field(value, ConfigError::InvalidWifiPw)?,
+        ConfigKey::Ssid3 => stored.ssid_3 = field(value, ConfigError::InvalidSsid)?,
+        ConfigKey::WifiPw3 => stored.wifi_pw_3 = field(value, ConfigError::InvalidWifiPw)?,
+        //Already checked by the console
+        ConfigKey::IpAddress => {
+            stored.ip_address = match value.map(parse_ipv4_cidr) {
+                None => None,
+                Some(Ok((address, prefix_len))) => Some(StoredIpv4Cidr {
+                    address,
+                    prefix_len,
+                }),
+                Some(Err(_)) => return Err(ConfigError::InvalidIpAddress),
+            }
+        }
+        ConfigKey::Gateway => {
+            stored.gateway = value
+                .map(parse_ipv4)
+                .transpose()
+                .map_err(|_| ConfigError::InvalidGateway)?
+        }
+        ConfigKey::DnsServers => {
+            stored.dns_servers = value
+                .map(parse_dns_servers)
+                .transpose()
+                .map_err(|_| ConfigError::InvalidDnsServers)?
+        }
+        ConfigKey::DeviceName => stored.device_name = field(value, ConfigError::InvalidDeviceName)?,
+        ConfigKey::UrlEndpoint => {
+            stored.url_endpoint = field(value, ConfigError::InvalidUrlEndpoint)?
+        }
+        ConfigKey::LatchMode => {
+            stored.latch_mode = match value.map(parse_latch_mode) {
+                None => None,
+                Some(Ok(None)) => Some(StoredLatchMode::Latching),
+                Some(Ok(Some(secs))) => Some(StoredLatchMode::Timed(secs)),
+                Some(Err(_)) => return Err(ConfigError::InvalidLatchMode),
+            }
+        }
+        ConfigKey::ApiToken => stored.api_token = field(value, ConfigError::InvalidApiToken)?,
+    }
+    stored.validate()?;
+    Ok(stored)
+}
+
+//A stored setting - too long is invalid
+fn field<const N: usize>(
+    value: Option<&str>,
+    error: ConfigError,
+) -> Result<Option<String<N>>, ConfigError> {
+    value
+        .map(|value| {
+            let mut field = String::new();
+            field.push_str(value).map_err(|_| error)?;
+            Ok(field)
+        })
+        .transpose()
+}
+
+fn show_db(reply: &mut Reply) {
+    let status = DB_STATUS.lock(|status| status.borrow().clone());
+    match core::str::from_utf8(&status.version) {
+        Ok(version) => {
+            reply!(reply, "Version:   {}", version);
+        }
+        Err(_) => {
+            reply!(reply, "Version:   {:02x?}", status.version.as_slice());
+        }
+    }
+    reply!(reply, "Cards:     {}", status.count);
+    reply!(reply, "Slot:      {}", status.slot);
+    match status.last_sync {
+        Some(at) => {
+            reply!(reply, "Last sync: {}s ago", (Instant::now() - at).as_secs());
+        }
+        None => {
+            reply!(reply, "Last sync: none since boot");
+        }
+    }
+}
+
+async fn show_log(reply: &mut Reply) {
+    LOG_DUMP_SIGNAL.reset();
+    LOG_DUMP_REQUEST_SIGNAL.signal(());
+    let dump = match LOG_DUMP_SIGNAL.wait().with_timeout(LOG_DUMP_TIMEOUT).await {
+        Ok(dump) => dump,
+        Err(_timeout) => {
+            LOG_DUMP_REQUEST_SIGNAL.reset();
+            reply!(reply, "error: log task busy - try again");
+            return;
+        }
+    };
+    reply!(reply, "{} events awaiting upload", dump.pending);
+    let mut json_buf = [0x00u8; MAX_EVENT_JSON_LEN];
+    for event in dump.events.iter() {
+        match event_json(&mut json_buf, event) {
+            Some(json) => {
+                reply!(reply, "{}", json);
+            }
+            None => {
+                reply!(reply, "(unprintable event)");
+            }
+        }
+    }
+    if dump.pending > dump.events.len() {
+        reply!(reply, "...and {} more", dump.pending - dump.events.len());
+    }
+}
+
+fn show_status(reply: &mut Reply, stack: Stack<'static>) {
+    if ota_task::is_booted() {
+        reply!(reply, "Firmware:  {}", FIRMWARE_VERSION);
+    } else {
+        reply!(reply, "Firmware:  {} (awaiting self-test)", FIRMWARE_VERSION);
+    }
+    reply!(reply, "Uptime:    {}s", Instant::now().as_secs());
+    match clock::unix_time() {
+        Some(time) => {
+            reply!(reply, "Unix time: {}", time);
+        }
+        None => {
+            reply!(reply, "Unix time: not synced");
+        }
+    }
+    let wifi = wifi_status();
+    match (wifi.state, wifi.ssid) {
+        (WifiState::Provisioning, _) => {
+            reply!(reply, "Wifi:      provisioning mode");
+        }
+        (state, Some(ssid)) => {
+            reply!(reply, "Wifi:      {:?}, {} ({} drops)", state, ssid, wifi.drops);
+        }
+        (state, None) => {
+            reply!(reply, "Wifi:      {:?} ({} drops)", state, wifi.drops);
+        }
+    }
+    show_retry(reply, "Wifi join:", &WIFI_RETRY_STATUS);
+    match stack.config_v4() {
+        Some(config) if stack.is_config_up() => {
+            reply!(reply, "Network:   up, {}", config.address);
+        }
+        _ => {
+            reply!(reply, "Network:   down");
+        }
+    }
+    show_retry(reply, "DB sync:  ", &DB_SYNC_RETRY_STATUS);
+    show_retry(reply, "Log:      ", &LOG_RETRY_STATUS);
+}
+
+fn show_retry(reply: &mut Reply, name: &str, status: &SharedRetryStatus) {
+    let status = status.lock(|status| status.get());
+    match status.last_failure {
+        Some(kind) if status.failures > 0 => {
+            reply!(
+                reply,
+                "{} {} consecutive failures ({:?}), retrying after {}s",
+                name,
+                status.failures,
+                kind,
+                status.delay.as_secs()
+            );
+        }
+        _ => {
+            reply!(reply, "{} ok", name);
+        }
+    }
+}
@@ -1,3 +1,8 @@
+use core::cell::{Cell, RefCell};
+use core::pin::pin;
+
+use embassy_futures::select::{select, Either};
+
 use embassy_net::{
     dns::DnsSocket,
     tcp::client::{TcpClient, TcpClientState},
@@ -5,22 +10,17 @@ use embassy_net::{
 };
 
 use embassy_rp::clocks::RoscRng;
-use embassy_rp::gpio::{Level, Output};
-use embassy_rp::spi::{Config as SpiConfig, Spi};
 
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
 use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Instant, Timer};
 
 use defmt::{Format, *};
 
-use ekv::flash::{self, PageID};
 use ekv::{config, Database};
 
-//For SPI flash
-use w25q32jv::W25q32jv;
-
 use embedded_io_async::Read;
 use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
 
@@ -28,20 +28,87 @@ use heapless::Vec;
 
 use rand::RngCore;
 
-use reqwless::client::{HttpClient, TlsConfig, TlsVerify};
-use reqwless::request::Method;
+use static_cell::ConstStaticCell;
+
+use reqwless::client::{HttpClient, TlsConfig};
+use reqwless::request::{Method, RequestBuilder};
 use reqwless::response::StatusCode;
 
+use access_db::{
+    check_access, decode_card_ids, decode_schedule, encode_card_ids, encode_schedule,
+    legacy_card_ids, newest_slot, parse_version, schema1_entry, Access, CardEntry, CardIds,
+    CardRecord, Change, DeltaParser, Digest, Entry, HashListParser, ParseError, RemoteVersion,
+    Role, Schedule, DIGEST_LEN, MAX_CARD_IDS_LEN, MAX_RECORD_LEN, MAX_SCHEDULE_LEN,
+};
+
+use crate::auth;
+use crate::card_id::{card_digest, CardUid};
+use crate::clock;
 use crate::config::CONFIG;
-use crate::FlashResources;
+use crate::flash::{FlashDevice, FlashRegion, SharedFlash};
+use crate::ota_task::{self, OtaFlash};
+use crate::retry::{FailureKind, RetryPolicy, DB_SYNC_RETRY_STATUS};
+use crate::signature::SignedDownload;
+use crate::tls;
+
+//The database region is split into two equal slots - one holds the live database, the other
+//is used to stage a new database during a sync. Each slot is 1MB
+const DB_SLOT_SIZE: usize = 0x10_0000;
+const DB_SLOT_PAGE_COUNT: usize = if config::MAX_PAGE_COUNT < DB_SLOT_SIZE / config::PAGE_SIZE {
+    config::MAX_PAGE_COUNT
+} else {
+    DB_SLOT_SIZE / config::PAGE_SIZE
+};
+//The original firmware kept a single database of MAX_PAGE_COUNT pages from the start of the
+//region - overlapping both slots, and the log and config stores
+const LEGACY_PAGE_COUNT: usize = config::MAX_PAGE_COUNT;
+//Most cards carried over from the original database, which has to be held in RAM meanwhile.
+//It can't be streamed into a slot instead - ekv places pages anywhere in the region, so any
+//write to the chip may land on a page still to be read. At 16 bytes a card this is 32KB of
+//the RP2040's 264KB, which fits alongside the 192KB task arena. A larger database keeps the
+//first 2048 cards and is replaced by a full download at the first sync
+const MAX_LEGACY_CARDS: usize = 2048;
+
+//Reserved keys - these are never 16 bytes long, so can't clash with a card
+const DB_VERSION_KEY: &[u8] = b"__DB_VERSION__";
+//Written with the version when a slot is committed - a slot without it is not a complete database
+const DB_SEQUENCE_KEY: &[u8] = b"__DB_SEQUENCE__";
+//The server's serial for the database, a u64 - a signed database is only replaced by a newer one
+const DB_SERIAL_KEY: &[u8] = b"__DB_SERIAL__";
+//Format of the card keys - absent in the original format, which used 32 byte ascii hex keys
+const DB_SCHEMA_KEY: &[u8] = b"__DB_SCHEMA__";
+//Schema 2 - keys are the binary 16 byte MD5 digests
+const DB_SCHEMA: u8 = 2;
+//Followed by a byte for the role, holding that role's default schedule
+const DB_SCHEDULE_KEY_PREFIX: &[u8; 12] = b"__SCHEDULE__";
+//Algorithms the card keys are hashed with, in order of preference - absent means MD5 only
+const DB_CARD_ID_KEY: &[u8] = b"__CARD_ID__";
+
+//Larger deltas fall back to a full download, as the whole delta is applied in one transaction
+const MAX_DELTA_ENTRIES: usize = 128;
+
+type Db<'a, T> = Database<FlashRegion<'a, T>, NoopRawMutex>;
 
-// Workaround for alignment requirements.
-#[repr(C, align(4))]
-struct AlignedBuf<const N: usize>([u8; N]);
+//Default schedule for each role, indexed by role
+type RoleSchedules = [Option<Schedule>; Role::ALL.len()];
 
-struct DbFlash<T: NorFlash + ReadNorFlash> {
-    start: usize,
-    flash: T,
+//The original firmware's database, read into RAM before anything overwrites it
+pub(crate) struct LegacyDb {
+    version: Vec<u8, 32>,
+    digests: Vec<Digest, MAX_LEGACY_CARDS>,
+}
+
+static LEGACY_DB: ConstStaticCell<LegacyDb> = ConstStaticCell::new(LegacyDb {
+    version: Vec::new(),
+    digests: Vec::new(),
+});
+
+//A/B pair of databases. Lookups are always served from the active slot, updates are
+//downloaded into the staging slot and only made active once complete.
+struct DbSlots<'a, T: NorFlash + ReadNorFlash> {
+    slots: [Db<'a, T>; 2],
+    active: Cell<usize>,
+    sequence: Cell<u32>,
 }
 
 #[derive(Debug, Format)]
@@ -50,16 +117,56 @@ pub(crate) enum UpdateError {
     ConnectionError, //Reqwless unable to connect
     Timeout,
     RemoteServerError(reqwless::response::StatusCode), //Http error from remote server (not 200!)
-    InvalidDbVersion, //DBVersion should (currently) be a 16 byte MD5 hash
+    InvalidDbVersion, //DBVersion should be 24 bytes, optionally followed by card id algorithms
+    InvalidDatabase,  //Downloaded database was truncated or malformed
+    FlashError,       //Unable to write the staging database
+    DeltaUnavailable, //Server can't supply a delta from our version - full download required
+    TlsVerificationFailed, //Server failed TLS verification - it may not be our backend
+    SignatureInvalid, //Downloaded database wasn't signed by our key - the old one is kept
+    StaleDatabase,    //Remote serial missing or not newer than ours - may be a replayed database
+    Unauthorized,     //Backend rejected our api_token (401/403)
+    InvalidFirmware,  //Firmware version or image unusable - empty, or too large
+}
+
+impl UpdateError {
+    fn failure_kind(&self) -> FailureKind {
+        match self {
+            UpdateError::WifiNotConnected => FailureKind::WifiNotConnected,
+            UpdateError::Timeout => FailureKind::Timeout,
+            UpdateError::RemoteServerError(status) => FailureKind::from_status(*status),
+            //Needs the server or device reconfiguring - retrying soon won't help
+            UpdateError::TlsVerificationFailed
+            | UpdateError::SignatureInvalid
+            | UpdateError::StaleDatabase
+            | UpdateError::Unauthorized => FailureKind::ClientError,
+            _ => FailureKind::Other,
+        }
+    }
+
+    //Error making a request to the backend
+    pub(crate) fn connection(error: reqwless::Error) -> Self {
+        match tls::is_verification_failure(&error) {
+            true => {
+                error!("Backend failed TLS verification");
+                UpdateError::TlsVerificationFailed
+            }
+            false => UpdateError::ConnectionError,
+        }
+    }
 }
+
 pub(crate) enum DatabaseTaskCommand {
-    CheckMD5Hash([u8; 32]),
+    CheckCard(CardUid),
     // ForceUpdate,
 }
 
+//Each response carries the card's hash - the one it was found under, or if it wasn't found,
+//its hash under the preferred algorithm
 pub(crate) enum DatabaseTaskResponse {
-    Found,
-    NotFound,
+    Found(Digest, CardRecord),
+    NotFound(Digest),
+    DeniedOutsideSchedule(Digest, CardRecord), //Valid card, but not at this time
+
    // Invalid,
    // Error,
    // UpdateOk,
@@ -69,146 +176,363 @@ pub(crate) static DATABASE_COMMAND_SIGNAL: Signal<ThreadModeRawMutex, DatabaseTa
     Signal::new();
 pub(crate) static DATABASE_RESPONSE_SIGNAL: Signal<ThreadModeRawMutex, DatabaseTaskResponse> =
     Signal::new();
+//Signal to sync now, rather than waiting for the next scheduled sync
+pub(crate) static DB_SYNC_REQUEST_SIGNAL: Signal<ThreadModeRawMutex, ()> = Signal::new();
+//Signalled after each successful sync - the OTA self-test waits for one
+pub(crate) static DB_SYNC_OK_SIGNAL: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+//The active database, for diagnostics
+#[derive(Clone)]
+pub(crate) struct DbStatus {
+    pub version: Vec<u8, 32>,
+    pub count: usize,
+    pub slot: usize,
+    pub last_sync: Option<Instant>, //Last successful sync (whether or not anything changed)
+}
+
+pub(crate) static DB_STATUS: BlockingMutex<CriticalSectionRawMutex, RefCell<DbStatus>> =
+    BlockingMutex::new(RefCell::new(DbStatus {
+        version: Vec::new(),
+        count: 0,
+        slot: 0,
+        last_sync: None,
+    }));
 
-//This is an EKV<->NorFlash+ReadNorFlash shim
-impl<T: NorFlash + ReadNorFlash> flash::Flash for DbFlash<T> {
-    type Error = T::Error;
-    fn page_count(&self) -> usize {
-        config::MAX_PAGE_COUNT
+impl<'a, T: NorFlash + ReadNorFlash> DbSlots<'a, T> {
+    fn new(flash: &'a SharedFlash<T>, start_addr: usize) -> Self {
+        Self {
+            slots: [
+                Database::new(
+                    FlashRegion {
+                        start: start_addr,
+                        page_count: DB_SLOT_PAGE_COUNT,
+                        flash,
+                    },
+                    ekv::Config::default(),
+                ),
+                Database::new(
+                    FlashRegion {
+                        start: start_addr + DB_SLOT_SIZE,
+                        page_count: DB_SLOT_PAGE_COUNT,
+                        flash,
+                    },
+                    ekv::Config::default(),
+                ),
+            ],
+            active: Cell::new(0),
+            sequence: Cell::new(0),
+        }
     }
 
-    async fn erase(&mut self, page_id: PageID) -> Result<(), <DbFlash<T> as flash::Flash>::Error> {
-        self.flash.erase(
-            (self.start + page_id.index() * config::PAGE_SIZE) as u32,
-            (self.start + page_id.index() * config::PAGE_SIZE + config::PAGE_SIZE) as u32,
-        )
+    fn active(&self) -> &Db<'a, T> {
+        &self.slots[self.active.get()]
     }
 
-    async fn read(
-        &mut self,
-        page_id: PageID,
-        offset: usize,
-        data: &mut [u8],
-    ) -> Result<(), <DbFlash<T> as flash::Flash>::Error> {
-        let address = self.start + page_id.index() * config::PAGE_SIZE + offset;
-        let mut buf = AlignedBuf([0; config::PAGE_SIZE]);
-        self.flash.read(address as u32, &mut buf.0[..data.len()])?;
-        data.copy_from_slice(&buf.0[..data.len()]);
+    fn staging(&self) -> &Db<'a, T> {
+        &self.slots[1 - self.active.get()]
+    }
+
+    //Mount both slots, returning each one's committed sequence
+    async fn committed(&self) -> [Option<u32>; 2] {
+        let mut committed = [None, None];
+        for (slot, db) in self.slots.iter().enumerate() {
+            if db.mount().await.is_ok() {
+                committed[slot] = read_sequence(db).await;
+            }
+            debug!("Database slot {} committed sequence: {}", slot, committed[slot]);
+        }
+        committed
+    }
+
+    //Mount both slots, and make the most recently committed one active. With neither
+    //committed, the original firmware's database is migrated if there was one
+    async fn mount(&self, legacy: Option<&LegacyDb>) {
+        let mut original = None;
+        match (newest_slot(self.committed().await), legacy) {
+            (Some((slot, sequence)), _) => {
+                self.active.set(slot);
+                self.sequence.set(sequence);
+            }
+            //Treated as committed at sequence 0, in slot 0 (which it overlaps)
+            (None, Some(legacy)) => {
+                self.active.set(0);
+                self.sequence.set(0);
+                original = Some(legacy);
+            }
+            (None, None) => {
+                info!("No valid database found - formatting...");
+                let db = &self.slots[0];
+                db.format().await.expect("Flash format failure");
+                //write version key post format - 0x00 forces an update
+                let mut wtx = db.write_transaction().await;
+                wtx.write(DB_SCHEMA_KEY, &[DB_SCHEMA]).await.unwrap();
+                wtx.write(DB_SEQUENCE_KEY, &0u32.to_le_bytes())
+                    .await
+                    .unwrap();
+                wtx.write(DB_VERSION_KEY, b"0x00").await.unwrap();
+                wtx.commit().await.unwrap();
+                self.active.set(0);
+                self.sequence.set(0);
+            }
+        }
+
+        //The original database is schema 1, read from RAM rather than the active slot
+        let schema = match original {
+            Some(_) => 1,
+            None => read_schema(self.active()).await,
+        };
+        if schema != DB_SCHEMA {
+            info!("Migrating database from schema {} to {}", schema, DB_SCHEMA);
+            self.migrate(original)
+                .await
+                .expect("Database migration failure");
+        }
+    }
+
+    //Called once the staging slot holds a complete database - writes the reserved keys in a
+    //single transaction, then makes it the active slot
+    async fn commit_staging(
+        &self,
+        version: &[u8],
+        card_ids: &CardIds,
+        serial: Option<u64>,
+        schedules: &RoleSchedules,
+    ) -> Result<(), UpdateError> {
+        let sequence = self.sequence.get().wrapping_add(1);
+        let mut wtx = self.staging().write_transaction().await;
+        let mut buf = [0x00u8; MAX_CARD_IDS_LEN];
+        let value = encode_card_ids(card_ids, &mut buf).map_err(|_| UpdateError::InvalidDatabase)?;
+        wtx.write(DB_CARD_ID_KEY, value)
+            .await
+            .map_err(|_| UpdateError::FlashError)?;
+        wtx.write(DB_SCHEMA_KEY, &[DB_SCHEMA])
+            .await
+            .map_err(|_| UpdateError::FlashError)?;
+        wtx.write(DB_SEQUENCE_KEY, &sequence.to_le_bytes())
+            .await
+            .map_err(|_| UpdateError::FlashError)?;
+        if let Some(serial) = serial {
+            wtx.write(DB_SERIAL_KEY, &serial.to_le_bytes())
+                .await
+                .map_err(|_| UpdateError::FlashError)?;
+        }
+        wtx.write(DB_VERSION_KEY, version)
+            .await
+            .map_err(|_| UpdateError::FlashError)?;
+        //The schedule keys sort after the other reserved keys, in role order
+        let mut buf = [0x00u8; MAX_SCHEDULE_LEN];
+        for (role, schedule) in Role::ALL.iter().zip(schedules.iter()) {
+            if let Some(schedule) = schedule {
+                let value =
+                    encode_schedule(schedule, &mut buf).map_err(|_| UpdateError::InvalidDatabase)?;
+                wtx.write(&schedule_key(*role), value)
+                    .await
+                    .map_err(|_| UpdateError::FlashError)?;
+            }
+        }
+        wtx.commit().await.map_err(|_| UpdateError::FlashError)?;
+
+        self.active.set(1 - self.active.get());
+        self.sequence.set(sequence);
         Ok(())
     }
 
-    async fn write(
-        &mut self,
-        page_id: PageID,
-        offset: usize,
-        data: &[u8],
-    ) -> Result<(), <DbFlash<T> as flash::Flash>::Error> {
-        let address = self.start + page_id.index() * config::PAGE_SIZE + offset;
-        let mut buf = AlignedBuf([0; config::PAGE_SIZE]);
-        buf.0[..data.len()].copy_from_slice(data);
-        self.flash.write(address as u32, &buf.0[..data.len()])
+    //Copy a schema 1 database (ascii hex keys) into the staging slot with binary keys, then
+    //make it active - from the active slot, or the original firmware's database read into RAM.
+    //If interrupted, the old database is still active, and migration reruns
+    async fn migrate(&self, original: Option<&LegacyDb>) -> Result<(), UpdateError> {
+        let staging = self.staging();
+        staging
+            .format()
+            .await
+            .map_err(|_| UpdateError::FlashError)?;
+
+        //Lowercase hex sorts in the same order as the binary keys, so chunks stay in order
+        let mut store: Vec<CardEntry, 32> = Vec::new();
+        let mut count = 0usize;
+        let mut version: Vec<u8, 32> = Vec::new();
+        match original {
+            Some(original) => {
+                version.clone_from(&original.version);
+                for digest in original.digests.iter() {
+                    //Its records were all a single 0x00 byte - a plain user card
+                    let entry = CardEntry {
+                        digest: *digest,
+                        record: CardRecord::default(),
+                    };
+                    count += stage_entry(staging, &mut store, entry).await?;
+                }
+            }
+            None => {
+                let rtx = self.active().read_transaction().await;
+                let mut cursor = rtx.read_all().await.map_err(|_| UpdateError::FlashError)?;
+                let mut keybuf = [0x00u8; 32];
+                let mut valbuf = [0x00u8; MAX_RECORD_LEN];
+                while let Some((key_len, val_len)) = cursor
+                    .next(&mut keybuf, &mut valbuf)
+                    .await
+                    .map_err(|_| UpdateError::FlashError)?
+                {
+                    let (key, value) = (&keybuf[..key_len], &valbuf[..val_len]);
+                    if key == DB_VERSION_KEY {
+                        version = Vec::from_slice(value).unwrap_or_default();
+                        continue;
+                    }
+                    match schema1_entry(key, value) {
+                        Ok(Some(entry)) => count += stage_entry(staging, &mut store, entry).await?,
+                        Ok(None) => {}
+                        Err(e) => warn!("Dropping invalid key during migration - {}", e),
+                    }
+                }
+            }
+        }
+        count += store_hashes(staging, &mut store).await?;
+
+        //Schema 1 databases predate role schedules, card id negotiation and serials
+        self.commit_staging(&version, &legacy_card_ids(), None, &RoleSchedules::default())
+            .await?;
+        info!("Migrated {} hashes to binary keys", count);
+        Ok(())
+    }
+}
+
+//Read the original firmware's database into RAM, if that's what the flash holds. Called at
+//boot before the config and log stores mount, as it overlaps their regions - database_task
+//only re-lays the slots once it has been read
+pub(crate) async fn read_legacy_database<T: NorFlash + ReadNorFlash>(
+    flash: &SharedFlash<T>,
+    start_addr: usize,
+) -> Option<&'static LegacyDb> {
+    if DbSlots::new(flash, start_addr).committed().await != [None, None] {
+        return None;
+    }
+
+    let db: Db<'_, T> = Database::new(
+        FlashRegion {
+            start: start_addr,
+            page_count: LEGACY_PAGE_COUNT,
+            flash,
+        },
+        ekv::Config::default(),
+    );
+    //A valid database without a sequence key - any other is blank flash, or not the original
+    if db.mount().await.is_err() || read_sequence(&db).await.is_some() {
+        return None;
+    }
+    let rtx = db.read_transaction().await;
+    let mut keybuf = [0x00u8; 32];
+    let mut valbuf = [0x00u8; MAX_RECORD_LEN];
+    let len = rtx.read(DB_VERSION_KEY, &mut keybuf).await.ok()?;
+    info!("Found the original database - reading it to migrate");
+
+    let legacy = LEGACY_DB.take();
+    legacy.version = Vec::from_slice(&keybuf[..len]).unwrap_or_default();
+    let mut dropped = 0usize;
+    let mut cursor = rtx.read_all().await.ok()?;
+    while let Ok(Some((key_len, val_len))) = cursor.next(&mut keybuf, &mut valbuf).await {
+        match schema1_entry(&keybuf[..key_len], &valbuf[..val_len]) {
+            Ok(Some(entry)) => {
+                if legacy.digests.push(entry.digest).is_err() {
+                    dropped += 1;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Dropping invalid key from the original database - {}", e),
+        }
+    }
+    if dropped > 0 {
+        error!("Original database too large - {} cards not migrated", dropped);
+        //Not the database the version names - make sure the first sync downloads it in full
+        legacy.version = Vec::from_slice(b"0x00").unwrap_or_default();
     }
+    Some(legacy)
 }
 
 #[embassy_executor::task]
 pub async fn database_task(
-    r: FlashResources,
+    flash: &'static SharedFlash<FlashDevice>,
     start_addr: usize,
     stack: Stack<'static>,
+    ota_flash: &'static OtaFlash,
+    legacy: Option<&'static LegacyDb>,
 ) {
-    //Initialise the SPI1 bus
-    let spi1: Spi<'_, embassy_rp::peripherals::SPI1, embassy_rp::spi::Blocking> =
-        Spi::new_blocking(r.spi, r.sck, r.mosi, r.miso, SpiConfig::default());
-
-    //Initialise the flash device
-    let flash_wp = Output::new(r.wp, Level::Low); //WP is ACTIVE LOW - start with flash WP set
-    let flash_hold = Output::new(r.hold, Level::High); //Flash hold is ACTIVE LOW - start with hold not enabled
-    let flash_cs = Output::new(r.cs, Level::High); //SPI flash CS pin
-    let spi_device = embedded_hal_bus::spi::ExclusiveDevice::new_no_delay(spi1, flash_cs);
-    let mut flash =
-        W25q32jv::new(spi_device, flash_hold, flash_wp).expect("Unable to initialise flash");
-
-    info!(
-        "SPI flash (W25Q32) initialised - device id {}",
-        flash.device_id().expect("Unable to read flash ID")
-    );
-    //Wrap it into the DBFlash
-    let flash = DbFlash {
-        start: start_addr,
-        flash,
-    };
-
-    //Initialise and mount the EKV database
-    let db = Database::<_, NoopRawMutex>::new(flash, ekv::Config::default());
-    if db.mount().await.is_err() {
-        info!("Formatting...");
-        db.format().await.expect("Flash format failure");
-        //write version key post format
-        let mut wtx = db.write_transaction().await;
-        wtx.write(b"__DB_VERSION__", b"0x00").await.unwrap();
-        wtx.commit().await.unwrap();
-    } else {
-        let mut buf = [0u8; 32];
-        let rtx = db.read_transaction().await;
-        let key_read = rtx
-            .read(b"__DB_VERSION__", &mut buf)
-            .await
-            .map(|n| &buf[..n]);
-        drop(rtx); //to allow write below, if needed.
-
-        let current_db_version = match key_read {
-            Ok(val) => val,
-            Err(_e) => {
-                error!("DB version tag missing from flash - writing tag as 0x00 to force update");
-                let mut wtx = db.write_transaction().await;
-                wtx.write(b"__DB_VERSION__", b"0x00").await.unwrap();
-                wtx.commit().await.unwrap();
-                &[0x00]
-            }
-        };
+    //Initialise and mount the EKV databases
+    let slots = DbSlots::new(flash, start_addr);
+    slots.mount(legacy).await;
 
+    publish_status(&slots).await;
+    DB_STATUS.lock(|status| {
+        let status = status.borrow();
         info!(
-            "Local database version: {}, containing {} RFID hashes",
-            current_db_version,
-            db_count(&db).await - 1
-        ); //-1 to account for the __DB_VERSION__ key
-    }
+            "Local database version: {}, containing {} RFID hashes (slot {}, sequence {})",
+            status.version.as_slice(),
+            status.count,
+            status.slot,
+            slots.sequence.get()
+        );
+    });
 
-    let mut last_sync_attempt_time = Instant::MIN;
+    //Sync 60 seconds after startup (to let wifi come up) and at specified intervals,
+    //backing off after failures
+    let mut retry = RetryPolicy::new(&CONFIG.db_sync_retry, &DB_SYNC_RETRY_STATUS);
+    let mut next_sync = Instant::now() + Duration::from_secs(60);
 
     loop {
-        //Sync 60 seconds after startup (to let wifi come up) and at specified intervals
-        if stack.is_config_up()
-            && ((last_sync_attempt_time == Instant::MIN
-                && Instant::now() > Instant::MIN + Duration::from_secs(60))
-                || Instant::now() > last_sync_attempt_time + CONFIG.db_sync_frequency)
-        {
-            last_sync_attempt_time = Instant::now();
+        if Instant::now() >= next_sync {
+            //Any sync request is satisfied by this sync
+            DB_SYNC_REQUEST_SIGNAL.reset();
+            let result = if stack.is_config_up() {
+                //Keep answering lookups from the active database while the sync runs
+                let mut sync = pin!(sync_database(&slots, stack, ota_flash));
+                loop {
+                    match select(DATABASE_COMMAND_SIGNAL.wait(), sync.as_mut()).await {
+                        Either::First(cmd) => handle_command(slots.active(), cmd).await,
+                        Either::Second(result) => break result,
+                    }
+                }
+            } else {
+                Err(UpdateError::WifiNotConnected)
+            };
 
-            match sync_database(&db, stack).await {
-                Ok(_) => {
+            match result {
+                Ok(updated) => {
                     info!("Database sync successful");
+                    if updated {
+                        publish_status(&slots).await;
+                    }
+                    DB_STATUS.lock(|status| status.borrow_mut().last_sync = Some(Instant::now()));
+                    DB_SYNC_OK_SIGNAL.signal(());
+                    retry.success();
+                    next_sync = Instant::now() + CONFIG.db_sync_frequency;
                 }
                 Err(err) => {
-                    error!("Database sync failed - {}", err);
+                    let delay = retry.failure(err.failure_kind());
+                    error!(
+                        "Database sync failed - {} ({} consecutive failures), retrying in {}s",
+                        err,
+                        retry.status().failures,
+                        delay.as_secs()
+                    );
+                    next_sync = Instant::now() + delay;
                 }
             }
         }
         debug!("Now awaiting database command signal");
-        //Purpose of timeout is to give us an opportunity to check, every 60s, if we need to do a DB update
-        match embassy_time::with_timeout(Duration::from_secs(60), DATABASE_COMMAND_SIGNAL.wait())
-            .await
+        //Purpose of timeout is to give us an opportunity to check, at least every 60s, if we need to do a DB update
+        let timeout = next_sync
+            .saturating_duration_since(Instant::now())
+            .min(Duration::from_secs(60));
+        match embassy_time::with_timeout(
+            timeout,
+            select(DATABASE_COMMAND_SIGNAL.wait(), DB_SYNC_REQUEST_SIGNAL.wait()),
+        )
+        .await
         {
-            Ok(cmd) => match cmd {
-                DatabaseTaskCommand::CheckMD5Hash(hash) => match db_lookup(&db, hash).await {
-                    Some(_) => {
-                        DATABASE_RESPONSE_SIGNAL.signal(DatabaseTaskResponse::Found);
-                    }
-                    None => {
-                        DATABASE_RESPONSE_SIGNAL.signal(DatabaseTaskResponse::NotFound);
-                    }
-                },
-            },
+            Ok(Either::First(cmd)) => handle_command(slots.active(), cmd).await,
+            Ok(Either::Second(())) => {
+                info!("Database sync requested");
+                next_sync = Instant::now();
+            }
             Err(_) => {
                 debug!("Database command signal timeout, will check if update is due");
             }
@@ -216,42 +540,200 @@ pub async fn database_task(
     }
 }
 
+async fn handle_command<T: NorFlash + ReadNorFlash>(db: &Db<'_, T>, cmd: DatabaseTaskCommand) {
+    match cmd {
+        DatabaseTaskCommand::CheckCard(uid) => match card_lookup(db, &uid).await {
+            Ok((hash, record)) => {
+                let role_schedule = read_role_schedule(db, record.role).await;
+                match check_access(
+                    &record,
+                    role_schedule.as_deref(),
+                    clock::unix_time(),
+                    &CONFIG.timezone,
+                ) {
+                    Access::Granted => {
+                        DATABASE_RESPONSE_SIGNAL.signal(DatabaseTaskResponse::Found(hash, record));
+                    }
+                    Access::OutsideSchedule => {
+                        info!("Card {:02x} is outside its schedule", hash);
+                        DATABASE_RESPONSE_SIGNAL
+                            .signal(DatabaseTaskResponse::DeniedOutsideSchedule(hash, record));
+                    }
+                }
+            }
+            Err(hash) => {
+                DATABASE_RESPONSE_SIGNAL.signal(DatabaseTaskResponse::NotFound(hash));
+            }
+        },
+    }
+}
+
+//Look a card up under each of the database's card id algorithms in turn, returning the hash
+//it was found under. If not found, returns its hash under the preferred algorithm
+async fn card_lookup<T: NorFlash + ReadNorFlash>(
+    db: &Db<'_, T>,
+    uid: &[u8],
+) -> Result<(Digest, CardRecord), Digest> {
+    let card_ids = read_card_ids(db).await;
+    for algorithm in card_ids.iter() {
+        let hash = card_digest(*algorithm, uid);
+        if let Some(record) = db_lookup(db, hash).await {
+            debug!("Card found by {} hash", algorithm.as_str());
+            return Ok((hash, record));
+        }
+    }
+    Err(card_digest(card_ids[0], uid))
+}
+
+async fn read_card_ids<T: NorFlash + ReadNorFlash>(db: &Db<'_, T>) -> CardIds {
+    let rtx = db.read_transaction().await;
+    let mut buf = [0u8; MAX_CARD_IDS_LEN];
+    match rtx.read(DB_CARD_ID_KEY, &mut buf).await {
+        Ok(n) => decode_card_ids(&buf[..n]).unwrap_or_else(|e| {
+            error!("Unable to decode card id algorithms - {}", e);
+            legacy_card_ids()
+        }),
+        //Databases from before negotiation
+        Err(_) => legacy_card_ids(),
+    }
+}
+
 async fn db_lookup<T: NorFlash + ReadNorFlash>(
-    db: &Database<DbFlash<T>, NoopRawMutex>,
-    hash: [u8; 32],
-) -> Option<()> {
+    db: &Db<'_, T>,
+    hash: Digest,
+) -> Option<CardRecord> {
     let rtx = db.read_transaction().await;
-    let mut buf = [0u8; 32];
+    let mut buf = [0u8; MAX_RECORD_LEN];
 
-    if let Ok(_key) = rtx.read(&hash, &mut buf).await.map(|n| &buf[..n]) {
+    if let Ok(value) = rtx.read(&hash, &mut buf).await.map(|n| &buf[..n]) {
         debug!("Key {:?} found in database", hash);
-        Some(())
+        match CardRecord::decode(value) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                //Don't grant access on a record we can't read - it may carry restrictions
+                error!("Unable to decode card record - {}", e);
+                None
+            }
+        }
     } else {
         debug!("Key {:?} NOT found in database", hash);
         None
     }
 }
 
-async fn db_count<T: NorFlash + ReadNorFlash>(db: &Database<DbFlash<T>, NoopRawMutex>) -> usize {
+fn schedule_key(role: Role) -> [u8; DB_SCHEDULE_KEY_PREFIX.len() + 1] {
+    let mut key = [0x00u8; DB_SCHEDULE_KEY_PREFIX.len() + 1];
+    key[..DB_SCHEDULE_KEY_PREFIX.len()].copy_from_slice(DB_SCHEDULE_KEY_PREFIX);
+    key[DB_SCHEDULE_KEY_PREFIX.len()] = role as u8;
+    key
+}
+
+async fn read_role_schedule<T: NorFlash + ReadNorFlash>(
+    db: &Db<'_, T>,
+    role: Role,
+) -> Option<Schedule> {
+    let rtx = db.read_transaction().await;
+    let mut buf = [0u8; MAX_SCHEDULE_LEN];
+    let value = rtx
+        .read(&schedule_key(role), &mut buf)
+        .await
+        .map(|n| &buf[..n])
+        .ok()?;
+    match decode_schedule(value) {
+        Ok(schedule) => Some(schedule),
+        Err(e) => {
+            //Fail closed - an unreadable schedule can't be shown to allow access
+            error!("Unable to decode {} schedule - {}", role.as_str(), e);
+            Some(Schedule::new())
+        }
+    }
+}
+
+async fn read_schema<T: NorFlash + ReadNorFlash>(db: &Db<'_, T>) -> u8 {
+    let rtx = db.read_transaction().await;
+    let mut buf = [0u8; 1];
+    match rtx.read(DB_SCHEMA_KEY, &mut buf).await {
+        Ok(1) => buf[0],
+        _ => 1,
+    }
+}
+
+async fn read_sequence<T: NorFlash + ReadNorFlash>(db: &Db<'_, T>) -> Option<u32> {
+    let rtx = db.read_transaction().await;
+    let mut buf = [0u8; 4];
+    match rtx.read(DB_SEQUENCE_KEY, &mut buf).await {
+        Ok(4) => Some(u32::from_le_bytes(buf)),
+        _ => None,
+    }
+}
+
+async fn read_serial<T: NorFlash + ReadNorFlash>(db: &Db<'_, T>) -> Option<u64> {
+    let rtx = db.read_transaction().await;
+    let mut buf = [0u8; 8];
+    match rtx.read(DB_SERIAL_KEY, &mut buf).await {
+        Ok(8) => Some(u64::from_le_bytes(buf)),
+        _ => None,
+    }
+}
+
+//With downloads signed, only a database with a strictly newer serial is accepted - an older
+//one is still validly signed, so may be being replayed to us
+fn check_serial(remote: &RemoteVersion, stored: Option<u64>) -> Result<(), UpdateError> {
+    if CONFIG.db_signing_key.is_none() || remote.is_newer(stored) {
+        return Ok(());
+    }
+    error!(
+        "Remote database serial {} is not newer than ours ({}) - rejecting update",
+        remote.serial, stored
+    );
+    Err(UpdateError::StaleDatabase)
+}
+
+//Update DB_STATUS from the active database
+async fn publish_status<T: NorFlash + ReadNorFlash>(slots: &DbSlots<'_, T>) {
+    let rtx = slots.active().read_transaction().await;
+    let mut buf = [0u8; 32];
+    let version = rtx
+        .read(DB_VERSION_KEY, &mut buf)
+        .await
+        .map(|n| &buf[..n])
+        .unwrap_or(&[0x00]);
+    let version = Vec::from_slice(version).unwrap_or_default();
+    drop(rtx);
+    let count = db_count(slots.active()).await;
+    DB_STATUS.lock(|status| {
+        let mut status = status.borrow_mut();
+        status.version = version;
+        status.count = count;
+        status.slot = slots.active.get();
+    });
+}
+
+async fn db_count<T: NorFlash + ReadNorFlash>(db: &Db<'_, T>) -> usize {
     let rtx = db.read_transaction().await;
     let mut cursor = rtx.read_all().await.expect("Cursor fail");
     let mut count = 0usize;
 
     let mut keybuf = [0x00u8; 32];
-    let mut valbuf = [0x00u8; 32];
+    let mut valbuf = [0x00u8; MAX_RECORD_LEN];
 
-    while cursor.next(&mut keybuf, &mut valbuf).await.ok() != Some(None) {
-        count += 1;
+    while let Ok(Some((key_len, _))) = cursor.next(&mut keybuf, &mut valbuf).await {
+        //Don't count the reserved keys
+        if key_len == DIGEST_LEN {
+            count += 1;
+        }
         //Without a brief 1 micro wait, the watchdog doesnt have a chance to run.....
         Timer::after_micros(1).await;
     }
     count
 }
 
+//Returns whether the database changed
 async fn sync_database<T: NorFlash + ReadNorFlash>(
-    db: &Database<DbFlash<T>, NoopRawMutex>,
+    slots: &DbSlots<'_, T>,
     stack: Stack<'static>,
-) -> Result<(), UpdateError> {
+    ota_flash: &OtaFlash,
+) -> Result<bool, UpdateError> {
     //Check if network is up, abort if not
     if !stack.is_config_up() {
         error!("Unable to sync - no wifi connection");
@@ -272,161 +754,495 @@ async fn sync_database<T: NorFlash + ReadNorFlash>(
         seed,
         &mut tls_read_buffer,
         &mut tls_write_buffer,
-        TlsVerify::None,
+        tls::tls_verify(),
     );
     let mut http_client = HttpClient::new_with_tls(&tcp_client, &dns_client, tls_config);
 
+    //Firmware updates are checked alongside the database - an update reboots into the new
+    //firmware. A failed check doesn't stop the database sync
+    if let Err(e) = ota_task::check_for_update(ota_flash, &mut http_client).await {
+        warn!("Firmware update check failed - {}", e);
+    }
+
     //Check current database version
-    let rtx = db.read_transaction().await;
+    let rtx = slots.active().read_transaction().await;
     let mut buf = [0u8; 32];
 
     let current_db_version = rtx
-        .read(b"__DB_VERSION__", &mut buf)
+        .read(DB_VERSION_KEY, &mut buf)
         .await
         .map(|n| &buf[..n])
         .expect("Fatal error - unable to read database version");
 
     info!("Current database version: {:a}", current_db_version);
-    //Must drop rtx, otherwise attempting to set up a wtx will block
     drop(rtx);
+    let current_serial = read_serial(slots.active()).await;
 
     match get_remote_db_version(&mut http_client).await {
-        Ok(remote_db_version) => {
-            info!("Remote DB version is {}", remote_db_version);
+        Ok(remote) => {
+            let remote_db_version = &remote.version;
+            info!(
+                "Remote DB version is {}, card ids {}",
+                remote_db_version,
+                remote.card_ids.as_slice()
+            );
             if remote_db_version == current_db_version {
                 info!("No update needed - database in sync");
-            } else {
-                info!(
-                    "Commencing database update from {:a} to {:a}",
-                    current_db_version, remote_db_version
-                );
-                //Erase existing database
-                debug!("Erasing database");
-                db.format().await.expect("Failed to erase database");
-                debug!("Preparing to download new database");
-                let mut url_buf = [0x00u8; 128];
-                let url = format_no_std::show(
-                    &mut url_buf,
-                    format_args!(
-                        "{}/{}/{}",
-                        CONFIG.url_endpoint, CONFIG.device_name, CONFIG.db_prefix
-                    ),
-                )
-                .expect("Unable to build DB update URL");
-                debug!("Connecting to {}", &url);
-
-                //Make connection
-                let mut rx_buffer = [0; 2048];
-                info!("Creating HTTP request");
-
-                let mut request = match embassy_time::with_timeout(
-                    CONFIG.http_timeout,
-                    http_client.request(Method::GET, url),
-                )
+                return Ok(false);
+            }
+            info!(
+                "Commencing database update from {:a} to {:a}",
+                current_db_version, remote_db_version
+            );
+            check_serial(&remote, current_serial)?;
+
+            //Try an incremental update first - a freshly formatted database has nothing to apply it to
+            if current_db_version != b"0x00" {
+                match sync_delta(slots.active(), &mut http_client, current_db_version, &remote)
                 .await
                 {
-                    Ok(e) => e.map_err(|_| UpdateError::ConnectionError)?,
-                    Err(_) => {
-                        return Err(UpdateError::Timeout);
+                    Ok(count) => {
+                        info!("Database delta applied successfully - {} changes", count);
+                        return Ok(true);
+                    }
+                    Err(UpdateError::DeltaUnavailable) => {
+                        info!("Delta unavailable, falling back to full database download");
                     }
-                };
+                    Err(e) => {
+                        return Err(e);
+                    }
+                }
+            }
 
-                debug!("Connecting");
-                let response = match embassy_time::with_timeout(
-                    CONFIG.http_timeout,
-                    request.send(&mut rx_buffer),
-                )
+            //Erase the staging slot - the active slot is left untouched, and still serves lookups
+            let staging = slots.staging();
+            debug!("Erasing staging database");
+            staging
+                .format()
                 .await
-                {
-                    Ok(e) => e.map_err(|_| UpdateError::ConnectionError)?,
-                    Err(_) => {
-                        return Err(UpdateError::Timeout);
-                    }
-                };
+                .map_err(|_| UpdateError::FlashError)?;
 
-                if !StatusCode::is_successful(&response.status) {
-                    return Err(UpdateError::RemoteServerError(response.status));
-                }
+            let mut schedules = RoleSchedules::default();
+            let count =
+                download_database(staging, &mut http_client, &remote, &mut schedules).await?;
 
-                debug!("Connected to server, receiving hashes");
-                //We will process the hashes in 32 hash chunks, so we can sort and store them
-                //Each hash is 32 bytes long with a trailing space as a separator.
+            //The whole body has been received, stored and verified - commit it. Until the reserved
+            //keys are written, the slot will be ignored at mount
+            slots
+                .commit_staging(remote_db_version, &remote.card_ids, remote.serial, &schedules)
+                .await?;
+            info!(
+                "Database update completed successfully - {} hashes, now using slot {}",
+                count,
+                slots.active.get()
+            );
+            Ok(true)
+        }
+        Err(e) => {
+            Err(e)
+        }
+    }
+}
 
-                let mut buf = [0x00u8; 32 * 32 + 32]; //there will be a space between each
-                let mut reader = response.body().reader();
+//Download the full hash list into the (freshly formatted) database, returning the hash count.
+//Role schedules are returned in schedules, to be written when the database is committed.
+//The hash list's signature is checked once it has all arrived
+async fn download_database<T: NorFlash + ReadNorFlash>(
+    db: &Db<'_, T>,
+    http_client: &mut HttpClient<'_, TcpClient<'_, 2>, DnsSocket<'_>>,
+    remote: &RemoteVersion,
+    schedules: &mut RoleSchedules,
+) -> Result<usize, UpdateError> {
+    debug!("Preparing to download new database");
+    let mut url_buf = [0x00u8; 128];
+    let url = format_no_std::show(
+        &mut url_buf,
+        format_args!(
+            "{}/{}/{}",
+            CONFIG.url_endpoint, CONFIG.device_name, CONFIG.db_prefix
+        ),
+    )
+    .expect("Unable to build DB update URL");
+    debug!("Connecting to {}", &url);
 
-                let mut buf_offset = 0usize;
+    //Make connection
+    let mut rx_buffer = [0; 2048];
+    info!("Creating HTTP request");
 
-                while let Ok(len) = reader.read(&mut buf[buf_offset..]).await {
-                    if len == 0 {
-                        //EOF
-                        debug!("Hit EOF");
-                        break;
-                    }
-                    debug!("Read {} bytes", len);
-                    let mut store: Vec<[u8; 32], 32> = Vec::new();
-
-                    let hashes = buf[..len + buf_offset].chunks_exact(33);
-                    for hash in hashes {
-                        //Convert hash into correct length type
-                        let hash: [u8; 32] = hash[0..32].try_into().unwrap();
-                        debug!(
-                            "Storing hash {} into vec",
-                            core::str::from_utf8(&hash).unwrap()
-                        );
-                        store.push(hash).expect("Heapless vec hash store error");
-                    }
+    let mut auth_buf = [0x00u8; auth::AUTH_HEADER_BUF_LEN];
+    let auth = auth::header(&mut auth_buf);
+    let mut request = match embassy_time::with_timeout(
+        CONFIG.http_timeout,
+        http_client.request(Method::GET, url),
+    )
+    .await
+    {
+        Ok(e) => e.map_err(UpdateError::connection)?.headers(auth.as_slice()),
+        Err(_) => {
+            return Err(UpdateError::Timeout);
+        }
+    };
 
-                    //Sort the store - ekv requires the keys to be sorted in order within a transaction
-                    store.sort_unstable();
+    debug!("Connecting");
+    let response = match embassy_time::with_timeout(
+        CONFIG.http_timeout,
+        request.send(&mut rx_buffer),
+    )
+    .await
+    {
+        Ok(e) => e.map_err(UpdateError::connection)?,
+        Err(_) => {
+            return Err(UpdateError::Timeout);
+        }
+    };
 
-                    let mut wtx: ekv::WriteTransaction<'_, DbFlash<T>, NoopRawMutex> =
-                        db.write_transaction().await;
-                    for i in store {
-                        debug!("Writing key: {:a}", i);
-                        wtx.write(&i, &[0x00]).await.expect("Key write failure");
-                    }
-                    wtx.commit().await.expect("Transaction commit failed");
-
-                    let excess_byte_count = (len + buf_offset) % 33;
-                    if excess_byte_count != 0 {
-                        //odd bytes, need to copy these to the start of the buffer, and set offset appropriately
-                        //so the next read arrives at the right place and complete hash can be processed
-                        let (left, right) =
-                            buf.split_at_mut((len + buf_offset) - excess_byte_count);
-                        debug!("Len is {}, excess byte count is {}", len, excess_byte_count);
-                        debug!(
-                            "Left is \n{}, right is \n{}",
-                            core::str::from_utf8(left).unwrap(),
-                            core::str::from_utf8(right).unwrap()
-                        );
-                        left[0..excess_byte_count].copy_from_slice(&right[..excess_byte_count]);
-                        buf_offset = excess_byte_count;
-                    } else {
-                        buf_offset = 0;
-                    }
+    if auth::is_unauthorized(response.status) {
+        return Err(UpdateError::Unauthorized);
+    }
+    if !StatusCode::is_successful(&response.status) {
+        return Err(UpdateError::RemoteServerError(response.status));
+    }
+
+    debug!("Connected to server, receiving hashes");
+    let mut signed = SignedDownload::database(remote, response.headers());
+    //Hashes are stored in 32 hash chunks, so each chunk can be sorted and written in one transaction
+    let mut buf = [0x00u8; 1024];
+    let mut reader = response.body().reader();
+    let mut parser = HashListParser::new();
+    let mut store: Vec<CardEntry, 32> = Vec::new();
+    let mut count = 0usize;
+
+    loop {
+        //A read error or stalled stream means the body is incomplete - abandon the update
+        let len = match embassy_time::with_timeout(CONFIG.http_timeout, reader.read(&mut buf))
+            .await
+        {
+            Ok(e) => e.map_err(|_| UpdateError::ConnectionError)?,
+            Err(_) => {
+                return Err(UpdateError::Timeout);
+            }
+        };
+
+        if len == 0 {
+            //EOF - the last hash may not have a trailing separator
+            debug!("Hit EOF");
+            if let Some(entry) = parser.finish() {
+                if let Some(card) = sort_entry(valid_entry(entry)?, schedules)? {
+                    store.push(card).ok(); //Store is never full here - flushed below
                 }
             }
+            count += store_hashes(db, &mut store).await?;
+            break;
+        }
+        debug!("Read {} bytes", len);
+        signed.update(&buf[..len]);
 
-            //Update the DB version tag
-            let mut wtx = db.write_transaction().await;
-            wtx.write(b"__DB_VERSION__", &remote_db_version)
-                .await
-                .unwrap();
-            wtx.commit().await.unwrap();
-            //Fixme  -remaining bytes
-            info!("Database update completed successfully");
-            Ok(())
+        let mut input = &buf[..len];
+        while let Some(entry) = parser.parse(&mut input) {
+            if let Some(card) = sort_entry(valid_entry(entry)?, schedules)? {
+                store.push(card).ok();
+            }
+            if store.is_full() {
+                count += store_hashes(db, &mut store).await?;
+            }
         }
-        Err(e) => {
-            Err(e)
+    }
+
+    if !signed.verify() {
+        error!("Database signature invalid - rejecting update");
+        return Err(UpdateError::SignatureInvalid);
+    }
+    Ok(count)
+}
+
+//A malformed entry means the whole database is rejected, rather than partially applied
+fn valid_entry<E>(entry: Result<E, ParseError>) -> Result<E, UpdateError> {
+    entry.map_err(|e| {
+        error!("Invalid database entry - {}", e);
+        UpdateError::InvalidDatabase
+    })
+}
+
+//Cards are returned for storing, role schedules are set aside - each role may only have one
+fn sort_entry(
+    entry: Entry,
+    schedules: &mut RoleSchedules,
+) -> Result<Option<CardEntry>, UpdateError> {
+    match entry {
+        Entry::Card(card) => Ok(Some(card)),
+        Entry::Schedule(role, schedule) => {
+            if schedules[role as usize].replace(schedule).is_some() {
+                error!("Database contains duplicate {} schedules", role.as_str());
+                return Err(UpdateError::InvalidDatabase);
+            }
+            Ok(None)
+        }
+    }
+}
+
+//Add a card to the store, writing the store out once it's full. Returns the cards written
+async fn stage_entry<T: NorFlash + ReadNorFlash>(
+    db: &Db<'_, T>,
+    store: &mut Vec<CardEntry, 32>,
+    entry: CardEntry,
+) -> Result<usize, UpdateError> {
+    store.push(entry).ok();
+    match store.is_full() {
+        true => store_hashes(db, store).await,
+        false => Ok(0),
+    }
+}
+
+//Write a chunk of cards to the database in a single transaction, emptying the store
+async fn store_hashes<T: NorFlash + ReadNorFlash>(
+    db: &Db<'_, T>,
+    store: &mut Vec<CardEntry, 32>,
+) -> Result<usize, UpdateError> {
+    //Sort the store - ekv requires the keys to be sorted in order within a transaction
+    store.sort_unstable_by_key(|entry| entry.digest);
+
+    let mut wtx = db.write_transaction().await;
+    let mut buf = [0x00u8; MAX_RECORD_LEN];
+    for i in store.iter() {
+        debug!("Writing key: {:02x}", i.digest);
+        let record = i.record.encode(&mut buf).map_err(|_| UpdateError::InvalidDatabase)?;
+        wtx.write(&i.digest, record)
+            .await
+            .map_err(|_| UpdateError::FlashError)?;
+    }
+    wtx.commit().await.map_err(|_| UpdateError::FlashError)?;
+
+    let count = store.len();
+    store.clear();
+    Ok(count)
+}
+
+//Fetch the changes between our version and the current remote version, and apply them to the
+//database in a single transaction, along with the new version tag. Returns the change count
+async fn sync_delta<T: NorFlash + ReadNorFlash>(
+    db: &Db<'_, T>,
+    http_client: &mut HttpClient<'_, TcpClient<'_, 2>, DnsSocket<'_>>,
+    current_db_version: &[u8],
+    remote: &RemoteVersion,
+) -> Result<usize, UpdateError> {
+    let mut version_buf = [0x00u8; 3 * 32];
+    let version =
+        url_encode(&mut version_buf, current_db_version).ok_or(UpdateError::InvalidDbVersion)?;
+
+    let mut url_buf = [0x00u8; 192];
+    let url = format_no_std::show(
+        &mut url_buf,
+        format_args!(
+            "{}/{}/{}/{}",
+            CONFIG.url_endpoint, CONFIG.device_name, CONFIG.db_delta_prefix, version
+        ),
+    )
+    .expect("Unable to build DB delta URL");
+    debug!("Requesting database delta from {}", &url);
+
+    let mut rx_buffer = [0; 2048];
+    let mut auth_buf = [0x00u8; auth::AUTH_HEADER_BUF_LEN];
+    let auth = auth::header(&mut auth_buf);
+    let mut request = match embassy_time::with_timeout(
+        CONFIG.http_timeout,
+        http_client.request(Method::GET, url),
+    )
+    .await
+    {
+        Ok(e) => e.map_err(UpdateError::connection)?.headers(auth.as_slice()),
+        Err(_) => {
+            return Err(UpdateError::Timeout);
         }
+    };
+
+    let response = match embassy_time::with_timeout(
+        CONFIG.http_timeout,
+        request.send(&mut rx_buffer),
+    )
+    .await
+    {
+        Ok(e) => e.map_err(UpdateError::connection)?,
+        Err(_) => {
+            return Err(UpdateError::Timeout);
+        }
+    };
+
+    //No Content, Not Found or Gone all mean the server can't build a delta from our version
+    if matches!(response.status.0, 204 | 404 | 410) {
+        return Err(UpdateError::DeltaUnavailable);
+    }
+    if auth::is_unauthorized(response.status) {
+        return Err(UpdateError::Unauthorized);
+    }
+    if !StatusCode::is_successful(&response.status) {
+        return Err(UpdateError::RemoteServerError(response.status));
     }
+
+    //Each entry is '+' (add) or '-' (remove), followed by the hash or role schedule
+    let mut signed = SignedDownload::delta(current_db_version, remote, response.headers());
+    let mut changes: Vec<Change, MAX_DELTA_ENTRIES> = Vec::new();
+    let mut buf = [0x00u8; 1024];
+    let mut reader = response.body().reader();
+    let mut parser = DeltaParser::new();
+
+    loop {
+        let len = match embassy_time::with_timeout(CONFIG.http_timeout, reader.read(&mut buf))
+            .await
+        {
+            Ok(e) => e.map_err(|_| UpdateError::ConnectionError)?,
+            Err(_) => {
+                return Err(UpdateError::Timeout);
+            }
+        };
+
+        signed.update(&buf[..len]);
+        let mut input = &buf[..len];
+        while let Some(entry) = match len {
+            0 => parser.finish(),
+            _ => parser.parse(&mut input),
+        } {
+            if changes.push(valid_entry(entry)?).is_err() {
+                warn!("Delta exceeds {} entries", MAX_DELTA_ENTRIES);
+                return Err(UpdateError::DeltaUnavailable);
+            }
+        }
+        if len == 0 {
+            break;
+        }
+    }
+
+    //Nothing has been written yet - a bad delta leaves the database untouched
+    if !signed.verify() {
+        error!("Delta signature invalid - rejecting update");
+        return Err(UpdateError::SignatureInvalid);
+    }
+    let count = changes.len();
+
+    //The version, card id algorithms, serial and any role schedule changes are written as
+    //reserved keys, amongst the cards. A value of None deletes the key. The 24 byte version and
+    //the card id algorithms fit within MAX_SCHEDULE_LEN
+    let mut reserved: Vec<(&[u8], Option<Vec<u8, MAX_SCHEDULE_LEN>>), 6> = Vec::new();
+    let mut version = Vec::new();
+    version.extend_from_slice(&remote.version).ok();
+    reserved.push((DB_VERSION_KEY, Some(version))).ok();
+    let mut card_id_buf = [0x00u8; MAX_CARD_IDS_LEN];
+    let card_ids = encode_card_ids(&remote.card_ids, &mut card_id_buf)
+        .map_err(|_| UpdateError::InvalidDatabase)?;
+    reserved
+        .push((DB_CARD_ID_KEY, Vec::from_slice(card_ids).ok()))
+        .ok();
+    let serial = remote.serial.map(|serial| serial.to_le_bytes());
+    reserved
+        .push((DB_SERIAL_KEY, serial.and_then(|serial| Vec::from_slice(&serial).ok())))
+        .ok();
+
+    let keys = Role::ALL.map(schedule_key);
+    let mut schedule_changed = [false; Role::ALL.len()];
+    let mut schedule_buf = [0x00u8; MAX_SCHEDULE_LEN];
+    for change in changes.iter() {
+        if let Change::Schedule(role, schedule) = change {
+            if core::mem::replace(&mut schedule_changed[*role as usize], true) {
+                error!("Delta contains duplicate {} schedules", role.as_str());
+                return Err(UpdateError::InvalidDatabase);
+            }
+            let value = match schedule {
+                Some(schedule) => Some(
+                    Vec::from_slice(
+                        encode_schedule(schedule, &mut schedule_buf)
+                            .map_err(|_| UpdateError::InvalidDatabase)?,
+                    )
+                    .map_err(|_| UpdateError::InvalidDatabase)?,
+                ),
+                None => None,
+            };
+            reserved.push((keys[*role as usize].as_slice(), value)).ok();
+        }
+    }
+    reserved.sort_unstable_by_key(|(key, _)| *key);
+
+    //ekv requires keys in ascending order within a transaction, and each key only once
+    changes.retain(|change| change.digest().is_some());
+    changes.sort_unstable_by(|a, b| a.digest().cmp(&b.digest()));
+    if changes
+        .windows(2)
+        .any(|pair| pair[0].digest() == pair[1].digest())
+    {
+        error!("Delta contains duplicate hashes");
+        return Err(UpdateError::InvalidDatabase);
+    }
+
+    let mut wtx = db.write_transaction().await;
+    let mut buf = [0x00u8; MAX_RECORD_LEN];
+    let mut reserved = reserved.iter().peekable();
+    let mut cards = changes.iter().peekable();
+    loop {
+        //The reserved keys sort amongst the hashes, so have to be written in their place
+        let next_reserved = match (reserved.peek(), cards.peek()) {
+            (Some((key, _)), Some(change)) => {
+                change.digest().is_some_and(|hash| *key < hash.as_slice())
+            }
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+        if next_reserved {
+            if let Some((key, value)) = reserved.next() {
+                match value {
+                    Some(value) => wtx
+                        .write(key, value)
+                        .await
+                        .map_err(|_| UpdateError::FlashError)?,
+                    None => wtx.delete(key).await.map_err(|_| UpdateError::FlashError)?,
+                }
+            }
+            continue;
+        }
+        match cards.next() {
+            Some(Change::Add(entry)) => {
+                debug!("Adding key: {:02x}", entry.digest);
+                let record = entry
+                    .record
+                    .encode(&mut buf)
+                    .map_err(|_| UpdateError::InvalidDatabase)?;
+                wtx.write(&entry.digest, record)
+                    .await
+                    .map_err(|_| UpdateError::FlashError)?;
+            }
+            Some(Change::Remove(hash)) => {
+                debug!("Removing key: {:02x}", hash);
+                wtx.delete(hash).await.map_err(|_| UpdateError::FlashError)?;
+            }
+            //Schedules were removed from the card changes above
+            Some(Change::Schedule(..)) | None => {}
+        }
+    }
+    wtx.commit().await.map_err(|_| UpdateError::FlashError)?;
+
+    Ok(count)
+}
+
+//Percent-encode a DB version (which may contain base64 '/' and '+') for use in a URL path
+fn url_encode<'a>(buf: &'a mut [u8], data: &[u8]) -> Option<&'a str> {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let mut len = 0usize;
+    for &byte in data {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            *buf.get_mut(len)? = byte;
+            len += 1;
+        } else {
+            *buf.get_mut(len)? = b'%';
+            *buf.get_mut(len + 1)? = HEX[(byte >> 4) as usize];
+            *buf.get_mut(len + 2)? = HEX[(byte & 0x0f) as usize];
+            len += 3;
+        }
+    }
+    core::str::from_utf8(&buf[..len]).ok()
 }
 
+//The remote version, and the card id algorithms its database uses
 async fn get_remote_db_version(
     http_client: &mut HttpClient<'_, TcpClient<'_, 2>, DnsSocket<'_>>,
-) -> Result<[u8; 24], UpdateError> {
+) -> Result<RemoteVersion, UpdateError> {
     let mut url_buf = [0x00u8; 128];
     let url = format_no_std::show(
         &mut url_buf,
@@ -440,13 +1256,15 @@ async fn get_remote_db_version(
 
     //Make connection
     let mut rx_buffer = [0; 2048];
+    let mut auth_buf = [0x00u8; auth::AUTH_HEADER_BUF_LEN];
+    let auth = auth::header(&mut auth_buf);
     let mut request = match embassy_time::with_timeout(
         CONFIG.http_timeout,
         http_client.request(Method::GET, url),
     )
     .await
     {
-        Ok(e) => e.map_err(|_| UpdateError::ConnectionError)?,
+        Ok(e) => e.map_err(UpdateError::connection)?.headers(auth.as_slice()),
         Err(_) => {
             return Err(UpdateError::Timeout);
         }
@@ -454,12 +1272,15 @@ async fn get_remote_db_version(
     debug!("Connecting");
     let response =
         match embassy_time::with_timeout(CONFIG.http_timeout, request.send(&mut rx_buffer)).await {
-            Ok(e) => e.map_err(|_| UpdateError::ConnectionError)?,
+            Ok(e) => e.map_err(UpdateError::connection)?,
             Err(_) => {
                 return Err(UpdateError::Timeout);
             }
         };
 
+    if auth::is_unauthorized(response.status) {
+        return Err(UpdateError::Unauthorized);
+    }
     if !StatusCode::is_successful(&response.status) {
         return Err(UpdateError::RemoteServerError(response.status));
     }
@@ -473,12 +1294,9 @@ async fn get_remote_db_version(
         .await
         .map_err(|_| UpdateError::ConnectionError)?;
 
-    if len != 24 {
-        error!("Wrong DBVersion length - should be 24 bytes, got {}", len);
-        return Err(UpdateError::InvalidDbVersion);
-    }
-
-    let mut res = [0x00u8; 24];
-    res.copy_from_slice(&rx_buffer[0..24]);
-    Ok(res)
+    //A 24 byte version, optionally followed by the card id algorithms
+    parse_version(&buf[..len]).map_err(|e| {
+        error!("Invalid DBVersion - {}", e);
+        UpdateError::InvalidDbVersion
+    })
 }
@@ -0,0 +1,120 @@
+use core::cell::RefCell;
+
+use embassy_rp::gpio::{Level, Output};
+use embassy_rp::peripherals::SPI1;
+use embassy_rp::spi::{Blocking, Config as SpiConfig, Spi};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+
+use defmt::*;
+
+use ekv::config;
+use ekv::flash::{self, PageID};
+
+use embedded_hal_bus::spi::{ExclusiveDevice, NoDelay};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use static_cell::StaticCell;
+
+//For SPI flash
+use w25q32jv::W25q32jv;
+
+use crate::FlashResources;
+
+//W25Q32 (4MB) layout:
+//0x000000 - 0x1FFFFF: A/B card database slots (see database_task)
+//0x200000 - 0x23FFFF: Log events awaiting upload (see log_task)
+//0x240000 - 0x24FFFF: Provisioned settings (see config_store)
+//0x250000 - 0x3FFFFF: Spare
+pub(crate) const DB_START_ADDR: usize = 0x00_0000;
+pub(crate) const LOG_START_ADDR: usize = 0x20_0000;
+pub(crate) const LOG_SIZE: usize = 0x4_0000;
+pub(crate) const CONFIG_START_ADDR: usize = 0x24_0000;
+pub(crate) const CONFIG_SIZE: usize = 0x1_0000;
+
+pub(crate) type FlashDevice = W25q32jv<
+    ExclusiveDevice<Spi<'static, SPI1, Blocking>, Output<'static>, NoDelay>,
+    Output<'static>,
+    Output<'static>,
+>;
+
+//The flash device is shared between the database and log tasks - each ekv database
+//only holds the lock for a single (blocking) page operation
+pub(crate) type SharedFlash<T> = BlockingMutex<ThreadModeRawMutex, RefCell<T>>;
+
+static FLASH: StaticCell<SharedFlash<FlashDevice>> = StaticCell::new();
+
+pub(crate) fn init(r: FlashResources) -> &'static SharedFlash<FlashDevice> {
+    //Initialise the SPI1 bus
+    let spi1: Spi<'_, SPI1, Blocking> =
+        Spi::new_blocking(r.spi, r.sck, r.mosi, r.miso, SpiConfig::default());
+
+    //Initialise the flash device
+    let flash_wp = Output::new(r.wp, Level::Low); //WP is ACTIVE LOW - start with flash WP set
+    let flash_hold = Output::new(r.hold, Level::High); //Flash hold is ACTIVE LOW - start with hold not enabled
+    let flash_cs = Output::new(r.cs, Level::High); //SPI flash CS pin
+    let spi_device = ExclusiveDevice::new_no_delay(spi1, flash_cs);
+    let mut flash =
+        W25q32jv::new(spi_device, flash_hold, flash_wp).expect("Unable to initialise flash");
+
+    info!(
+        "SPI flash (W25Q32) initialised - device id {}",
+        flash.device_id().expect("Unable to read flash ID")
+    );
+    FLASH.init(SharedFlash::new(RefCell::new(flash)))
+}
+
+// Workaround for alignment requirements.
+#[repr(C, align(4))]
+struct AlignedBuf<const N: usize>([u8; N]);
+
+//A region of the shared flash, holding one ekv database
+pub(crate) struct FlashRegion<'a, T: NorFlash + ReadNorFlash> {
+    pub start: usize,
+    pub page_count: usize,
+    pub flash: &'a SharedFlash<T>,
+}
+
+//This is an EKV<->NorFlash+ReadNorFlash shim
+impl<T: NorFlash + ReadNorFlash> flash::Flash for FlashRegion<'_, T> {
+    type Error = T::Error;
+    fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    async fn erase(&mut self, page_id: PageID) -> Result<(), <Self as flash::Flash>::Error> {
+        self.flash.lock(|flash| {
+            flash.borrow_mut().erase(
+                (self.start + page_id.index() * config::PAGE_SIZE) as u32,
+                (self.start + page_id.index() * config::PAGE_SIZE + config::PAGE_SIZE) as u32,
+            )
+        })
+    }
+
+    async fn read(
+        &mut self,
+        page_id: PageID,
+        offset: usize,
+        data: &mut [u8],
+    ) -> Result<(), <Self as flash::Flash>::Error> {
+        let address = self.start + page_id.index() * config::PAGE_SIZE + offset;
+        let mut buf = AlignedBuf([0; config::PAGE_SIZE]);
+        self.flash
+            .lock(|flash| flash.borrow_mut().read(address as u32, &mut buf.0[..data.len()]))?;
+        data.copy_from_slice(&buf.0[..data.len()]);
+        Ok(())
+    }
+
+    async fn write(
+        &mut self,
+        page_id: PageID,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), <Self as flash::Flash>::Error> {
+        let address = self.start + page_id.index() * config::PAGE_SIZE + offset;
+        let mut buf = AlignedBuf([0; config::PAGE_SIZE]);
+        buf.0[..data.len()].copy_from_slice(data);
+        self.flash
+            .lock(|flash| flash.borrow_mut().write(address as u32, &buf.0[..data.len()]))
+    }
+}
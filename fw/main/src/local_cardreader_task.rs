@@ -5,6 +5,7 @@ use defmt::*;
 use mfrc522::{comm::blocking::spi::SpiInterface, Mfrc522, Uid};
 
 use crate::{
+    card_id::CardUid,
     main_task::{CardReaderEvent, CARDREADER_EVENT_SIGNAL},
     Spi0Resources,
 };
@@ -46,20 +47,20 @@ pub async fn local_cardreader_task(spi: Spi0Resources) -> ! {
                         match mfrc.select(&atqa) {
                             Ok(ref _uid @ Uid::Single(ref inner)) => {
                                 debug!("Single UID card read");
-                                CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardMD5(
-                                    md5::compute(&inner.as_bytes()[..4]),
+                                CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardRead(
+                                    CardUid::from_slice(&inner.as_bytes()[..4]).unwrap(),
                                 ))
                             }
                             Ok(ref _uid @ Uid::Double(ref inner)) => {
                                 debug!("Double UID card read");
-                                CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardMD5(
-                                    md5::compute(&inner.as_bytes()[..7]),
+                                CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardRead(
+                                    CardUid::from_slice(&inner.as_bytes()[..7]).unwrap(),
                                 ))
                             }
                             Ok(ref _uid @ Uid::Triple(ref inner)) => {
                                 debug!("Triple UID card read");
-                                CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardMD5(
-                                    md5::compute(&inner.as_bytes()[..10]),
+                                CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardRead(
+                                    CardUid::from_slice(&inner.as_bytes()[..10]).unwrap(),
                                 ))
                             }
                             Err(_e) => {
@@ -0,0 +1,203 @@
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::Timer;
+
+use defmt::{Format, *};
+
+use ekv::{config, Database};
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use heapless::Vec;
+
+use access_db::MAX_RECORD_LEN;
+
+use crate::clock::Timestamp;
+use crate::flash::{FlashRegion, SharedFlash};
+use crate::log_task::{LogEventV1, StampedLogEvent};
+
+//Oldest events are dropped beyond this, leaving plenty of free space for ekv to compact into
+const MAX_STORED_EVENTS: usize = 1024;
+//Stored events are prefixed with a format byte, so the format can change without a flash wipe.
+//Format 1 predates the deactivation reason
+const LOG_FORMAT: u8 = 2;
+//Largest possible stored event - format byte, event, digests, card record and timestamp
+const MAX_STORED_EVENT_LEN: usize = MAX_RECORD_LEN + 64;
+
+type LogDb<'a, T> = Database<FlashRegion<'a, T>, NoopRawMutex>;
+
+#[derive(Debug, Format)]
+pub(crate) enum LogStoreError {
+    FlashError,
+    EncodeError,
+}
+
+//Flash backed FIFO of log events awaiting upload, so they survive a reboot or power cut.
+//Each event is keyed by a big-endian sequence number, so ekv's key order is the order they
+//were logged in. An event is only deleted once the server has acknowledged it.
+pub(crate) struct LogStore<'a, T: NorFlash + ReadNorFlash> {
+    db: LogDb<'a, T>,
+    next_sequence: u32, //Wraps after 4 billion events - not a concern in practice
+    len: usize,
+}
+
+impl<'a, T: NorFlash + ReadNorFlash> LogStore<'a, T> {
+    //Mount the log region (formatting it if it doesn't hold a log), and find the pending events
+    pub(crate) async fn mount(flash: &'a SharedFlash<T>, start: usize, size: usize) -> Self {
+        let db = Database::new(
+            FlashRegion {
+                start,
+                page_count: size / config::PAGE_SIZE,
+                flash,
+            },
+            ekv::Config::default(),
+        );
+        if db.mount().await.is_err() {
+            info!("No valid log store found - formatting...");
+            db.format().await.expect("Flash format failure");
+        }
+
+        let mut store = Self {
+            db,
+            next_sequence: 0,
+            len: 0,
+        };
+        let rtx = store.db.read_transaction().await;
+        let mut cursor = rtx.read_all().await.expect("Cursor fail");
+        let mut keybuf = [0x00u8; 4];
+        let mut valbuf = [0x00u8; MAX_STORED_EVENT_LEN];
+        while let Ok(Some((key_len, _))) = cursor.next(&mut keybuf, &mut valbuf).await {
+            if key_len == 4 {
+                store.next_sequence = u32::from_be_bytes(keybuf).wrapping_add(1);
+                store.len += 1;
+            }
+            //Let the watchdog run
+            Timer::after_micros(1).await;
+        }
+        drop(cursor);
+        drop(rtx);
+        store
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    //Append an event. If the store is full, the oldest event is dropped to make room
+    pub(crate) async fn push(&mut self, event: &StampedLogEvent) -> Result<(), LogStoreError> {
+        let mut buf = [0x00u8; MAX_STORED_EVENT_LEN];
+        buf[0] = LOG_FORMAT;
+        let len = postcard::to_slice(event, &mut buf[1..])
+            .map_err(|_| LogStoreError::EncodeError)?
+            .len();
+
+        let oldest = match self.len >= MAX_STORED_EVENTS {
+            true => self.oldest_sequence().await,
+            false => None,
+        };
+
+        let sequence = self.next_sequence;
+        let mut wtx = self.db.write_transaction().await;
+        //Keys must be written in order - the oldest always sorts before the new event
+        if let Some(oldest) = oldest {
+            warn!("Log store full - dropping oldest event");
+            wtx.delete(&oldest.to_be_bytes())
+                .await
+                .map_err(|_| LogStoreError::FlashError)?;
+        }
+        wtx.write(&sequence.to_be_bytes(), &buf[..1 + len])
+            .await
+            .map_err(|_| LogStoreError::FlashError)?;
+        wtx.commit().await.map_err(|_| LogStoreError::FlashError)?;
+
+        self.next_sequence = sequence.wrapping_add(1);
+        if oldest.is_none() {
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    //Fill events with the oldest pending events, in order, along with their sequence numbers.
+    //Events that can't be decoded (e.g. written by newer firmware) are discarded
+    pub(crate) async fn read_oldest<const N: usize>(
+        &mut self,
+        events: &mut Vec<(u32, StampedLogEvent), N>,
+    ) -> Result<(), LogStoreError> {
+        events.clear();
+        let mut invalid: Vec<u32, N> = Vec::new();
+        {
+            let rtx = self.db.read_transaction().await;
+            let mut cursor = rtx.read_all().await.map_err(|_| LogStoreError::FlashError)?;
+            let mut keybuf = [0x00u8; 4];
+            let mut valbuf = [0x00u8; MAX_STORED_EVENT_LEN];
+            while !events.is_full() && !invalid.is_full() {
+                let Some((key_len, val_len)) = cursor
+                    .next(&mut keybuf, &mut valbuf)
+                    .await
+                    .map_err(|_| LogStoreError::FlashError)?
+                else {
+                    break;
+                };
+                if key_len != 4 {
+                    continue;
+                }
+                let sequence = u32::from_be_bytes(keybuf);
+                match valbuf[..val_len].split_first() {
+                    Some((&LOG_FORMAT, value)) => match postcard::from_bytes(value) {
+                        Ok(event) => {
+                            events.push((sequence, event)).ok();
+                        }
+                        Err(_) => {
+                            invalid.push(sequence).ok();
+                        }
+                    },
+                    Some((&1, value)) => {
+                        match postcard::from_bytes::<(LogEventV1, Timestamp)>(value) {
+                            Ok((event, timestamp)) => {
+                                let event = event.into();
+                                events
+                                    .push((sequence, StampedLogEvent { event, timestamp }))
+                                    .ok();
+                            }
+                            Err(_) => {
+                                invalid.push(sequence).ok();
+                            }
+                        }
+                    }
+                    _ => {
+                        invalid.push(sequence).ok();
+                    }
+                }
+            }
+        }
+
+        if !invalid.is_empty() {
+            error!("Discarding {} unreadable log events", invalid.len());
+            self.remove(&invalid).await?;
+        }
+        Ok(())
+    }
+
+    //Delete events once the server has acknowledged them. Sequences must be in ascending order
+    pub(crate) async fn remove(&mut self, sequences: &[u32]) -> Result<(), LogStoreError> {
+        let mut wtx = self.db.write_transaction().await;
+        for sequence in sequences {
+            wtx.delete(&sequence.to_be_bytes())
+                .await
+                .map_err(|_| LogStoreError::FlashError)?;
+        }
+        wtx.commit().await.map_err(|_| LogStoreError::FlashError)?;
+        self.len = self.len.saturating_sub(sequences.len());
+        Ok(())
+    }
+
+    async fn oldest_sequence(&self) -> Option<u32> {
+        let rtx = self.db.read_transaction().await;
+        let mut cursor = rtx.read_all().await.ok()?;
+        let mut keybuf = [0x00u8; 4];
+        let mut valbuf = [0x00u8; MAX_STORED_EVENT_LEN];
+        match cursor.next(&mut keybuf, &mut valbuf).await {
+            Ok(Some((4, _))) => Some(u32::from_be_bytes(keybuf)),
+            _ => None,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use embassy_futures::select::{select, Either};
 use embassy_net::{
     dns::DnsSocket,
     tcp::client::{TcpClient, TcpClientState},
@@ -6,32 +7,117 @@ use embassy_net::{
 use embassy_rp::clocks::RoscRng;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use embassy_sync::channel::Channel;
-use embassy_time::{Timer, WithTimeout};
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, WithTimeout};
 
 use defmt::Format;
 use defmt::*;
 
+use heapless::Vec;
+
 use rand::RngCore;
 
-use reqwless::client::{HttpClient, TlsConfig, TlsVerify};
+use serde::{Deserialize, Serialize};
+
+use access_db::{encode_hex, CardRecord, Digest};
+
+use reqwless::client::{HttpClient, TlsConfig};
 use reqwless::request::Method;
 use reqwless::{request::RequestBuilder, response::StatusCode};
 
+use crate::auth;
+use crate::clock::Timestamp;
+use crate::flash::{FlashDevice, SharedFlash, LOG_SIZE, LOG_START_ADDR};
+use crate::log_store::LogStore;
+use crate::retry::{FailureKind, RetryPolicy, LOG_RETRY_STATUS};
+use crate::tls;
 use crate::CONFIG;
 
 const MAX_QUEUE_LEN: usize = 32usize;
+//Most events sent in one batch upload
+const MAX_BATCH_LEN: usize = 16;
+pub(crate) const MAX_EVENT_JSON_LEN: usize = 320;
+//Most events returned by a log dump
+pub(crate) const MAX_DUMP_LEN: usize = 8;
 
 #[allow(dead_code)]
+#[derive(Serialize, Deserialize)]
 pub(crate) enum LogEvent {
-    Activated([u8; 32]),
-    Deactivated([u8; 32]),
-    LoginFail([u8; 32]),
+    Activated(Digest, CardRecord),
+    Deactivated(Digest, CardRecord, DeactivationReason),
+    LoginFail(Digest),
+    DeniedOutsideSchedule(Digest, CardRecord), //Known card, used outside its schedule
     Error,
+    SignOutRejected(Digest, Digest), //The card read, and the signed in card it couldn't sign out
+    TwoPersonActivated(Digest, CardRecord, Digest), //First card (and details), then second
+    DeniedNoSupervisor(Digest, CardRecord), //Two-person mode - neither card was a supervisor
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum DeactivationReason {
+    SignedOut, //A card was read
+    Timeout,   //The session reached max_session
+    Handover,  //Another member took over the session
+}
+
+impl DeactivationReason {
+    //Name as sent to the server
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            DeactivationReason::SignedOut => "signed_out",
+            DeactivationReason::Timeout => "timeout",
+            DeactivationReason::Handover => "handover",
+        }
+    }
+}
+
+//LogEvent as stored before deactivations carried a reason (log store format 1)
+#[derive(Deserialize)]
+pub(crate) enum LogEventV1 {
+    Activated(Digest, CardRecord),
+    Deactivated(Digest, CardRecord),
+    LoginFail(Digest),
+    DeniedOutsideSchedule(Digest, CardRecord),
+    Error,
+}
+
+impl From<LogEventV1> for LogEvent {
+    fn from(event: LogEventV1) -> Self {
+        match event {
+            LogEventV1::Activated(hash, record) => LogEvent::Activated(hash, record),
+            LogEventV1::Deactivated(hash, record) => {
+                LogEvent::Deactivated(hash, record, DeactivationReason::SignedOut)
+            }
+            LogEventV1::LoginFail(hash) => LogEvent::LoginFail(hash),
+            LogEventV1::DeniedOutsideSchedule(hash, record) => {
+                LogEvent::DeniedOutsideSchedule(hash, record)
+            }
+            LogEventV1::Error => LogEvent::Error,
+        }
+    }
+}
+
+//An event, with the time it happened - so events logged late still carry the right time
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StampedLogEvent {
+    pub event: LogEvent,
+    pub timestamp: Timestamp,
 }
 
-//The queue can hold 32 events awaiting logging
-pub(crate) static LOG_EVENT_QUEUE: Channel<ThreadModeRawMutex, LogEvent, MAX_QUEUE_LEN> =
-    Channel::<ThreadModeRawMutex, LogEvent, MAX_QUEUE_LEN>::new();
+//Events are handed to the log task via this queue, which can hold 32 events - the log task
+//immediately persists them to flash, where they stay until the server has acknowledged them
+pub(crate) static LOG_EVENT_QUEUE: Channel<ThreadModeRawMutex, StampedLogEvent, MAX_QUEUE_LEN> =
+    Channel::<ThreadModeRawMutex, StampedLogEvent, MAX_QUEUE_LEN>::new();
+
+//The events awaiting upload, for diagnostics - the oldest events, and how many there are in all
+pub(crate) struct LogDump {
+    pub pending: usize,
+    pub events: Vec<StampedLogEvent, MAX_DUMP_LEN>,
+}
+
+//Signal the log task to dump the events awaiting upload. It replies via LOG_DUMP_SIGNAL
+pub(crate) static LOG_DUMP_REQUEST_SIGNAL: Signal<ThreadModeRawMutex, ()> = Signal::new();
+pub(crate) static LOG_DUMP_SIGNAL: Signal<ThreadModeRawMutex, LogDump> = Signal::new();
 
 #[derive(Debug, Format)]
 pub enum LogError {
@@ -39,55 +125,306 @@ pub enum LogError {
     ConnectionError, //Reqwless unable to connect
     Timeout,
     RemoteServerError(reqwless::response::StatusCode), //Http error from remote server (not 200!)
+    NoneAccepted, //Batch upload succeeded, but the server accepted none of the events
+    TlsVerificationFailed, //Server failed TLS verification - it may not be our backend
+    Unauthorized, //Backend rejected our api_token (401/403)
+}
+
+impl LogError {
+    fn failure_kind(&self) -> FailureKind {
+        match self {
+            LogError::WifiNotConnected => FailureKind::WifiNotConnected,
+            LogError::Timeout => FailureKind::Timeout,
+            LogError::RemoteServerError(status) => FailureKind::from_status(*status),
+            //The server is refusing the events - retrying soon won't change that
+            LogError::NoneAccepted => FailureKind::ClientError,
+            //Needs the server or device reconfiguring - retrying soon won't help
+            LogError::TlsVerificationFailed | LogError::Unauthorized => FailureKind::ClientError,
+            LogError::ConnectionError => FailureKind::Other,
+        }
+    }
+
+    //Error making a request to the backend
+    fn connection(error: reqwless::Error) -> Self {
+        match tls::is_verification_failure(&error) {
+            true => {
+                error!("Backend failed TLS verification");
+                LogError::TlsVerificationFailed
+            }
+            false => LogError::ConnectionError,
+        }
+    }
 }
 
 #[embassy_executor::task]
-pub async fn log_task(stack: Stack<'static>) -> ! {
+pub async fn log_task(flash: &'static SharedFlash<FlashDevice>, stack: Stack<'static>) -> ! {
+    let mut store = LogStore::mount(flash, LOG_START_ADDR, LOG_SIZE).await;
+    info!("Log store mounted, {} events awaiting upload", store.len());
+    let mut retry = RetryPolicy::new(&CONFIG.log_retry, &LOG_RETRY_STATUS);
+
     loop {
-        //Await an event from the queue
-        let event = LOG_EVENT_QUEUE.receive().await;
-        match log_event(&stack, &event).await {
-            Ok(_) => {
-                info!("Log event recorded successfully")
+        //Persist any new events before attempting an upload
+        while let Ok(event) = LOG_EVENT_QUEUE.try_receive() {
+            persist_event(&mut store, &event).await;
+        }
+
+        let mut pending: Vec<(u32, StampedLogEvent), MAX_BATCH_LEN> = Vec::new();
+        if let Err(e) = store.read_oldest(&mut pending).await {
+            error!("Unable to read log store - {}", e);
+        }
+        if pending.is_empty() {
+            //Nothing to upload - await an event from the queue
+            let event = receive_event(&mut store).await;
+            persist_event(&mut store, &event).await;
+            continue;
+        }
+
+        //Batch uploads save a TLS handshake per event, if the backend supports them
+        let result = match CONFIG.log_batch_prefix {
+            Some(batch_prefix) if pending.len() > 1 => {
+                log_batch(&stack, batch_prefix, &pending).await
+            }
+            _ => log_event(&stack, &pending[0].1).await.map(|_| 1),
+        };
+
+        match result {
+            Ok(accepted) => {
+                info!("{} log events recorded successfully", accepted);
+                retry.success();
+                let mut sequences: Vec<u32, MAX_BATCH_LEN> = Vec::new();
+                for (sequence, _) in pending.iter().take(accepted) {
+                    sequences.push(*sequence).ok();
+                }
+                if let Err(e) = store.remove(&sequences).await {
+                    error!("Unable to remove logged events - {}", e);
+                }
             }
             Err(e) => {
-                warn!("Log event failed ({}), will be requeued", e);
-                //Attempt to requeue
-                if let Err(_e) = LOG_EVENT_QUEUE.try_send(event) {
-                    error!("Unable to requeue - this event will be lost")
+                let delay = retry.failure(e.failure_kind());
+                warn!(
+                    "Log event failed ({}, {} consecutive failures), will be retried in {}s",
+                    e,
+                    retry.status().failures,
+                    delay.as_secs()
+                );
+                //Don't try to log again until the retry is due, but keep persisting
+                //new events as they arrive
+                let retry_at = Instant::now() + delay;
+                while let Ok(event) =
+                    embassy_time::with_deadline(retry_at, receive_event(&mut store)).await
+                {
+                    persist_event(&mut store, &event).await;
                 }
-                //Don't try to log again for another minute after a failed attempt
-                Timer::after_secs(60).await;
             }
         }
     }
 }
 
-async fn log_event(stack: &Stack<'_>, event: &LogEvent) -> Result<(), LogError> {
-    //Abandon if wifi not running
-    if !stack.is_config_up() {
-        return Err(LogError::WifiNotConnected);
+//Await an event from the queue, answering any dump requests in the meantime
+async fn receive_event(store: &mut LogStore<'_, FlashDevice>) -> StampedLogEvent {
+    loop {
+        match select(LOG_EVENT_QUEUE.receive(), LOG_DUMP_REQUEST_SIGNAL.wait()).await {
+            Either::First(event) => return event,
+            Either::Second(()) => {
+                let mut oldest: Vec<(u32, StampedLogEvent), MAX_DUMP_LEN> = Vec::new();
+                if let Err(e) = store.read_oldest(&mut oldest).await {
+                    error!("Unable to read log store - {}", e);
+                }
+                LOG_DUMP_SIGNAL.signal(LogDump {
+                    pending: store.len(),
+                    events: oldest.into_iter().map(|(_, event)| event).collect(),
+                });
+            }
+        }
     }
+}
+
+async fn persist_event(store: &mut LogStore<'_, FlashDevice>, event: &StampedLogEvent) {
+    if let Err(e) = store.push(event).await {
+        error!("Unable to store log event - this event will be lost ({})", e);
+    }
+}
+
+//Log a single event to the log endpoint
+async fn log_event(stack: &Stack<'_>, stamped: &StampedLogEvent) -> Result<(), LogError> {
+    let mut json_buf = [0x00; MAX_EVENT_JSON_LEN];
+    let json = event_json(&mut json_buf, stamped).expect("Unable to build JSON string event");
+    debug!("Json string: {}", json);
+
+    post_json(stack, CONFIG.log_prefix, json.as_bytes(), &mut []).await?;
+    Ok(())
+}
+
+//Log several events as one JSON array to the batch endpoint. The server replies with the
+//number of events it accepted ({"accepted": n}) - these are always the first n in the array,
+//and the rest are retried. A reply without a count means every event was accepted
+async fn log_batch(
+    stack: &Stack<'_>,
+    batch_prefix: &str,
+    events: &[(u32, StampedLogEvent)],
+) -> Result<usize, LogError> {
+    let mut batch_buf = [0x00u8; MAX_BATCH_LEN * (MAX_EVENT_JSON_LEN + 1) + 2];
+    let mut len = 0usize;
+    batch_buf[len] = b'[';
+    len += 1;
+    for (index, (_, stamped)) in events.iter().enumerate() {
+        if index > 0 {
+            batch_buf[len] = b',';
+            len += 1;
+        }
+        let json = event_json(&mut batch_buf[len..len + MAX_EVENT_JSON_LEN], stamped)
+            .expect("Unable to build JSON string event")
+            .len();
+        len += json;
+    }
+    batch_buf[len] = b']';
+    len += 1;
+
+    let mut body = [0x00u8; 64];
+    let body_len = post_json(stack, batch_prefix, &batch_buf[..len], &mut body).await?;
+    let accepted = match parse_accepted(&body[..body_len]) {
+        Some(accepted) => accepted.min(events.len()),
+        None => events.len(),
+    };
+    if accepted == 0 {
+        return Err(LogError::NoneAccepted);
+    }
+    Ok(accepted)
+}
+
+//Find the count in a {"accepted": n} reply
+fn parse_accepted(body: &[u8]) -> Option<usize> {
+    const KEY: &[u8] = b"\"accepted\"";
+    let start = body.windows(KEY.len()).position(|window| window == KEY)? + KEY.len();
+    let value = body[start..]
+        .iter()
+        .skip_while(|&&byte| byte.is_ascii_whitespace() || byte == b':');
+    let mut count: Option<usize> = None;
+    for &byte in value {
+        if !byte.is_ascii_digit() {
+            break;
+        }
+        count = Some(
+            count
+                .unwrap_or(0)
+                .checked_mul(10)?
+                .checked_add((byte - b'0') as usize)?,
+        );
+    }
+    count
+}
+
+pub(crate) fn event_json<'a>(buf: &'a mut [u8], stamped: &StampedLogEvent) -> Option<&'a str> {
+    let event = &stamped.event;
+    let timestamp = &stamped.timestamp;
 
     //Convert hash to ascii string representation
     let hash = match event {
-        LogEvent::Activated(hash) | LogEvent::Deactivated(hash) | LogEvent::LoginFail(hash) => {
+        LogEvent::Activated(hash, _)
+        | LogEvent::Deactivated(hash, ..)
+        | LogEvent::LoginFail(hash)
+        | LogEvent::DeniedOutsideSchedule(hash, _)
+        | LogEvent::SignOutRejected(hash, _)
+        | LogEvent::TwoPersonActivated(hash, ..)
+        | LogEvent::DeniedNoSupervisor(hash, _) => {
             //Convert hash to an ascii str representation
-            hash
+            encode_hex(hash)
         }
-        _ => b"N/A                             ", //32 bytes long too...
+        _ => *b"N/A                             ", //32 bytes long too...
     };
-    let hash = core::str::from_utf8(hash).unwrap_or(" Non-ascii bytes in hash");
+    let hash = core::str::from_utf8(&hash).unwrap_or(" Non-ascii bytes in hash");
 
     //Get printable name for event, as expected by the Makerspace logging API
     let event_str = match event {
-        LogEvent::Activated(_) => "Activated",
-        LogEvent::Deactivated(_) => "Deactivated",
+        LogEvent::Activated(..) => "Activated",
+        LogEvent::Deactivated(..) => "Deactivated",
         LogEvent::LoginFail(_) => "LoginFail",
+        LogEvent::DeniedOutsideSchedule(..) => "DeniedOutsideSchedule",
         LogEvent::Error => "ERROR",
+        LogEvent::SignOutRejected(..) => "SignOutRejected",
+        LogEvent::TwoPersonActivated(..) => "TwoPersonActivated",
+        LogEvent::DeniedNoSupervisor(..) => "DeniedNoSupervisor",
     };
 
-    //Build a fresh http client for each database update attempt
+    //time is unix seconds if time_synced, otherwise seconds since the controller booted
+    match event {
+        //Member IDs are validated by the database parser, so never need escaping
+        LogEvent::Deactivated(_, record, reason) => format_no_std::show(
+            buf,
+            format_args!(
+                "{{ \"type\": \"{}\", \"hash\": \"{}\", \"member\": \"{}\", \"role\": \"{}\", \"reason\": \"{}\", \"time\": {}, \"time_synced\": {}}}",
+                event_str,
+                hash,
+                record.member.as_str(),
+                record.role.as_str(),
+                reason.as_str(),
+                timestamp.time,
+                timestamp.synced
+            ),
+        ),
+        LogEvent::SignOutRejected(_, signed_in) => format_no_std::show(
+            buf,
+            format_args!(
+                "{{ \"type\": \"{}\", \"hash\": \"{}\", \"signed_in_hash\": \"{}\", \"time\": {}, \"time_synced\": {}}}",
+                event_str,
+                hash,
+                core::str::from_utf8(&encode_hex(signed_in)).unwrap_or(""),
+                timestamp.time,
+                timestamp.synced
+            ),
+        ),
+        LogEvent::TwoPersonActivated(_, record, second) => format_no_std::show(
+            buf,
+            format_args!(
+                "{{ \"type\": \"{}\", \"hash\": \"{}\", \"member\": \"{}\", \"role\": \"{}\", \"second_hash\": \"{}\", \"time\": {}, \"time_synced\": {}}}",
+                event_str,
+                hash,
+                record.member.as_str(),
+                record.role.as_str(),
+                core::str::from_utf8(&encode_hex(second)).unwrap_or(""),
+                timestamp.time,
+                timestamp.synced
+            ),
+        ),
+        LogEvent::Activated(_, record)
+        | LogEvent::DeniedOutsideSchedule(_, record)
+        | LogEvent::DeniedNoSupervisor(_, record) => format_no_std::show(
+            buf,
+            format_args!(
+                "{{ \"type\": \"{}\", \"hash\": \"{}\", \"member\": \"{}\", \"role\": \"{}\", \"time\": {}, \"time_synced\": {}}}",
+                event_str,
+                hash,
+                record.member.as_str(),
+                record.role.as_str(),
+                timestamp.time,
+                timestamp.synced
+            ),
+        ),
+        _ => format_no_std::show(
+            buf,
+            format_args!(
+                "{{ \"type\": \"{}\", \"hash\": \"{}\", \"time\": {}, \"time_synced\": {}}}",
+                event_str, hash, timestamp.time, timestamp.synced
+            ),
+        ),
+    }
+    .ok()
+}
+
+//POST json to {url_endpoint}/{device_name}/{prefix}, copying up to body.len() bytes of
+//the response body into body. Returns the number of body bytes copied
+async fn post_json(
+    stack: &Stack<'_>,
+    prefix: &str,
+    json: &[u8],
+    body: &mut [u8],
+) -> Result<usize, LogError> {
+    //Abandon if wifi not running
+    if !stack.is_config_up() {
+        return Err(LogError::WifiNotConnected);
+    }
+
+    //Build a fresh http client for each log attempt
     debug!("Connecting to log endpoint");
     let mut tls_read_buffer = [0; 8096];
     let mut tls_write_buffer = [0; 8096];
@@ -101,66 +438,72 @@ async fn log_event(stack: &Stack<'_>, event: &LogEvent) -> Result<(), LogError>
         seed,
         &mut tls_read_buffer,
         &mut tls_write_buffer,
-        TlsVerify::None,
+        tls::tls_verify(),
     );
     let mut http_client = HttpClient::new_with_tls(&tcp_client, &dns_client, tls_config);
 
     let mut url_buf = [0x00u8; 128];
     let url = format_no_std::show(
         &mut url_buf,
-        format_args!(
-            "{}/{}/{}",
-            CONFIG.url_endpoint, CONFIG.device_name, CONFIG.log_prefix
-        ),
+        format_args!("{}/{}/{}", CONFIG.url_endpoint, CONFIG.device_name, prefix),
     )
-    .expect("Unable to build DB update URL");
+    .expect("Unable to build log URL");
     debug!("Connecting to {}", &url);
 
-    let mut json_buf = [0x00; 256];
-    let json = format_no_std::show(
-        &mut json_buf,
-        format_args!("{{ \"type\": \"{}\", \"hash\": \"{}\"}}", event_str, hash),
-    )
-    .expect("Unable to build JSON string event");
-
-    debug!("Json string: {}", json);
     let mut rx_buf = [0x00; 512];
+    let mut auth_buf = [0x00u8; auth::AUTH_HEADER_BUF_LEN];
+    let auth = auth::header(&mut auth_buf);
 
     let request = match http_client
         .request(Method::POST, url)
         .with_timeout(CONFIG.http_timeout)
         .await
     {
-        Ok(e) => e.map_err(|_| LogError::ConnectionError)?,
+        Ok(e) => e.map_err(LogError::connection)?,
         Err(_timeout) => {
             return Err(LogError::Timeout);
         }
     };
 
     debug!("Connecting");
-    let retval = match request
+    let response = match request
+        .headers(auth.as_slice())
         .content_type(reqwless::headers::ContentType::ApplicationJson)
-        .body(json.as_bytes())
+        .body(json)
         .send(&mut rx_buf)
         .with_timeout(CONFIG.http_timeout)
         .await
     {
-        Ok(result) => {
-            match result {
-                Ok(response) => {
-                    //Valid HTTP response received - check it is 'ok'
-                    if StatusCode::is_successful(&response.status) {
-                        //Success!
-                        Ok(())
-                    } else {
-                        //Successfully connected, but didn't get 200 OK (or another success code)
-                        Err(LogError::RemoteServerError(response.status))
-                    }
-                }
-                Err(_error) => Err(LogError::ConnectionError),
-            }
+        Ok(result) => result.map_err(LogError::connection)?,
+        Err(_timeout) => {
+            return Err(LogError::Timeout);
+        }
+    };
+
+    //Valid HTTP response received - check it is 'ok'
+    if auth::is_unauthorized(response.status) {
+        return Err(LogError::Unauthorized);
+    }
+    if !StatusCode::is_successful(&response.status) {
+        //Successfully connected, but didn't get 200 OK (or another success code)
+        return Err(LogError::RemoteServerError(response.status));
+    }
+    if body.is_empty() {
+        return Ok(0);
+    }
+
+    let received = match response
+        .body()
+        .read_to_end()
+        .with_timeout(CONFIG.http_timeout)
+        .await
+    {
+        Ok(result) => result.map_err(|_| LogError::ConnectionError)?,
+        Err(_timeout) => {
+            return Err(LogError::Timeout);
         }
-        Err(_timeout) => Err(LogError::Timeout),
     };
-    retval //Borrow checker gets unhappy otherwise!
+    let len = received.len().min(body.len());
+    body[..len].copy_from_slice(&received[..len]);
+    Ok(len)
 }
@@ -2,7 +2,6 @@
 #![no_main]
 #![allow(async_fn_in_trait)]
 
-use cyw43::JoinOptions;
 use cyw43_pio::{PioSpi, DEFAULT_CLOCK_DIVIDER};
 
 use defmt::*;
@@ -12,33 +11,50 @@ use {defmt_rtt as _, panic_probe as _};
 use assign_resources::assign_resources;
 
 use embassy_executor::Spawner;
-use embassy_net::{Config as WifiConfig, StackResources};
+use embassy_net::StackResources;
 use embassy_rp::bind_interrupts;
 use embassy_rp::clocks::RoscRng;
-use embassy_rp::gpio::{Level, Output};
+use embassy_rp::gpio::{Input, Level, Output, Pull};
 use embassy_rp::peripherals;
-use embassy_rp::peripherals::{DMA_CH0, PIO0};
+use embassy_rp::peripherals::{DMA_CH0, PIO0, USB};
 use embassy_rp::pio::{InterruptHandler, Pio};
-use embassy_time::Timer;
 
 use static_cell::StaticCell;
 
 use rand::RngCore;
 
+mod auth;
+mod card_id;
+mod clock;
+mod config_store;
+mod console_task;
 mod database_task;
+mod flash;
 mod local_cardreader_task;
+mod log_store;
 mod log_task;
 mod main_task;
+mod ota_task;
+mod provisioning;
 mod remote_cardreader_task;
+mod retry;
+mod signature;
+mod sntp_task;
+mod tls;
 mod watchdog;
+mod wifi_task;
 
+use console_task::console_task;
 use database_task::database_task;
 use local_cardreader_task::local_cardreader_task;
 use main_task::main_task;
+use ota_task::ota_task;
 use remote_cardreader_task::remote_cardreader_task;
+use sntp_task::sntp_task;
 use watchdog::watchdog_task;
+use wifi_task::wifi_task;
 
-use log_task::{log_task, LogEvent, LOG_EVENT_QUEUE};
+use log_task::{log_task, LogEvent, StampedLogEvent, LOG_EVENT_QUEUE};
 mod config;
 use config::CONFIG;
 
@@ -92,11 +108,24 @@ assign_resources! {
         dma_ch: DMA_CH0,
         pin_24: PIN_24,
         pin_29: PIN_29,
+    },
+    //USB serial console
+    usb: UsbResources {
+        usb: USB,
+    },
+    //Fit a link to ground to start in provisioning mode
+    provisioning: ProvisioningResources {
+        strap: PIN_22,
+    },
+    //Internal flash - holds the firmware, and OTA updates
+    ota: OtaResources {
+        flash: FLASH,
     }
 }
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
+    USBCTRL_IRQ => embassy_rp::usb::InterruptHandler<USB>;
 });
 
 #[embassy_executor::task]
@@ -119,6 +148,23 @@ async fn main(spawner: Spawner) {
     //Spawn the watchdog task
     spawner.must_spawn(watchdog_task(resources.watchdog));
 
+    //The SPI flash is shared by the config store, and the database and logger tasks
+    let flash = flash::init(resources.flash);
+    //The original firmware's database overlaps the config and log stores, so is read first
+    let legacy_db = database_task::read_legacy_database(flash, flash::DB_START_ADDR).await;
+    //Load any provisioned settings over the compiled-in defaults, before anything uses them
+    config_store::load(flash).await;
+
+    tls::check_config();
+    auth::check_config();
+    signature::check_config();
+    provisioning::check_config();
+
+    //The strap is only checked at boot
+    let strap = Input::new(resources.provisioning.strap, Pull::Up);
+    let strapped = strap.is_low();
+    drop(strap);
+
     let pwr = Output::new(resources.wifi.pwr, Level::Low);
     let cs = Output::new(resources.wifi.cs, Level::High);
     let mut pio = Pio::new(resources.wifi.pio, Irqs);
@@ -147,10 +193,12 @@ async fn main(spawner: Spawner) {
         .await;
 
     // Init network stack
-    let config = WifiConfig::dhcpv4(Default::default());
+    let config = wifi_task::stack_config();
     let mut rng = RoscRng;
     let seed = rng.next_u64();
-    static RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
+    //Sockets for DHCP, DNS, SNTP, and the database and log HTTP clients (up to 2 each) - and
+    //the DHCP, DNS and HTTP servers in provisioning mode
+    static RESOURCES: StaticCell<StackResources<10>> = StaticCell::new();
     let (stack, runner) = embassy_net::new(
         net_device,
         config,
@@ -174,38 +222,37 @@ async fn main(spawner: Spawner) {
     //Spawn the main task
     spawner.must_spawn(main_task(resources.status_leds, resources.relay));
 
-    //Spawn the database task (2mbit flash, start addr 0)
-    spawner.must_spawn(database_task(resources.flash, 0x00, stack));
-
-    //Spawn the logger task
-    spawner.must_spawn(log_task(stack));
-
-    loop {
-        match control
-            .join(CONFIG.ssid, JoinOptions::new(CONFIG.wifi_pw.as_bytes()))
-            .await
-        {
-            Ok(_) => {
-                info!("WiFi network {} joined, configuring stack", CONFIG.ssid);
-                break;
-            }
-            Err(err) => {
-                error!(
-                    "Failed to join {}, status {}, retrying in 10s",
-                    CONFIG.ssid, err.status
-                );
-                Timer::after_secs(10).await;
-            }
-        }
-    }
+    //Spawn the OTA task - keeps or rolls back newly updated firmware
+    let ota_flash = ota_task::init(resources.ota);
+    spawner.must_spawn(ota_task(ota_flash));
+
+    //Spawn the database task (A/B database slots) - also checks for firmware updates
+    spawner.must_spawn(database_task(
+        flash,
+        flash::DB_START_ADDR,
+        stack,
+        ota_flash,
+        legacy_db,
+    ));
+
+    //Spawn the logger task (events are queued in flash until uploaded)
+    spawner.must_spawn(log_task(flash, stack));
 
-    //Complete init of Wifi stack
-    debug!("DHCP init");
-    stack.wait_config_up().await;
-    debug!("Config ready, awaiting link up");
-    stack.wait_link_up().await;
-    debug!("Link ready, awaiting config up");
-    stack.wait_config_up().await;
-    info!("Wifi ready");
-    //Main function is now complete - the peripherals/tasks/stack are operational
+    //Spawn the SNTP task - keeps the wall-clock time used by schedules and log events
+    spawner.must_spawn(sntp_task(stack));
+
+    //Spawn the USB serial console - provisioning and diagnostics
+    spawner.must_spawn(console_task(resources.usb, stack));
+
+    let provision = if strapped {
+        warn!("Provisioning strap fitted");
+        Some(provisioning::Entry::Strap)
+    } else if CONFIG.wifi_networks().next().is_none() {
+        warn!("No wifi network configured");
+        Some(provisioning::Entry::Unconfigured)
+    } else {
+        None
+    };
+    //Spawn the connection manager - keeps the wifi connected, or runs provisioning mode
+    spawner.must_spawn(wifi_task(control, stack, provision));
 }
@@ -1,155 +1,334 @@
+use embassy_futures::select::{select4, Either4};
 use embassy_rp::gpio::{Output, Level};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use embassy_sync::signal::Signal;
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
 
 use defmt::*;
 
+use access_controller::{AccessController, Action, Actions, Config, Denial, Event, Input, Led};
+use access_controller::{Lookup, Reason};
+use access_controller::{LatchMode as ControllerLatchMode, Timer as ControllerTimer};
+
 use crate::database_task::{DatabaseTaskCommand, DatabaseTaskResponse};
 use crate::database_task::{DATABASE_COMMAND_SIGNAL, DATABASE_RESPONSE_SIGNAL};
 
 use crate::remote_cardreader_task::MAIN_MESSAGE_SIGNAL;
 use crate::{config::LatchMode, CONFIG};
 
-use crate::{LogEvent, LOG_EVENT_QUEUE};
+use crate::card_id::{self, CardUid};
+use crate::clock::{self, Timestamp};
+use crate::config_store;
+use crate::provisioning::{self, PROVISIONING_SIGNAL};
+use crate::log_task::DeactivationReason;
+use crate::{LogEvent, StampedLogEvent, LOG_EVENT_QUEUE};
 
 use crate::{StatusLedResources, RelayResources};
 
 pub (crate) enum CardReaderEvent {
-    CardMD5(md5::Digest),
+    CardRead(CardUid), //The database task hashes the UID, as the database dictates
 }
 
 pub (crate) static CARDREADER_EVENT_SIGNAL: Signal<ThreadModeRawMutex, CardReaderEvent> = Signal::new();
+//Signal to briefly switch the relay on, to test the wiring
+pub (crate) static RELAY_TEST_SIGNAL: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+//Holding the admin card on the reader for this many consecutive reads enters provisioning mode.
+//A held card is re-read about once a second
+const ADMIN_HOLD_READS: u32 = 5;
+const ADMIN_READ_GAP: Duration = Duration::from_secs(2);
+//How often the LEDs toggle while warning that a session is about to end
+const WARNING_FLASH_PERIOD: Duration = Duration::from_millis(250);
+//...and while waiting for the second card in two-person mode
+const AWAITING_SECOND_FLASH_PERIOD: Duration = Duration::from_millis(500);
 
-enum LatchState {
-    Enabled([u8;32]), //We store the card hash of the person who is signed into the controller
-    Disabled,
+//Deadlines for the controller's timers
+#[derive(Default)]
+struct Timers {
+    holdoff: Option<Instant>,
+    session: Option<Instant>,
 }
 
-#[embassy_executor::task]
-pub async fn main_task(leds: StatusLedResources, relay: RelayResources) -> ! {    
-    //Receives message of new RFID read via signal, passes to database task.
-    //Receives message from database task - card allowed, card denied
-    //Activates appropriate LED +- FET
-    //Sends message to the log task queue so it can update the backend
+impl Timers {
+    fn deadline(&mut self, timer: ControllerTimer) -> &mut Option<Instant> {
+        match timer {
+            ControllerTimer::Holdoff => &mut self.holdoff,
+            ControllerTimer::Session => &mut self.session,
+        }
+    }
 
-    //Briefly flash the allowed and denied LEDs at startup of task
-    let mut allowed_led = Output::new(leds.green_led, Level::High);
-    let mut denied_led = Output::new(leds.red_led, Level::High);
+    fn start(&mut self, timer: ControllerTimer, after: core::time::Duration) {
+        let after = Duration::from_micros(after.as_micros() as u64);
+        *self.deadline(timer) = Some(Instant::now() + after);
+    }
+
+    //Wait for the next timer to run out, returning which it was
+    async fn expired(&mut self) -> ControllerTimer {
+        let deadlines = [
+            (ControllerTimer::Holdoff, self.holdoff),
+            (ControllerTimer::Session, self.session),
+        ];
+        let next = deadlines
+            .into_iter()
+            .filter_map(|(timer, at)| Some((timer, at?)))
+            .min_by_key(|(_, at)| *at);
+        match next {
+            Some((timer, at)) => {
+                Timer::at(at).await;
+                *self.deadline(timer) = None;
+                timer
+            }
+            None => core::future::pending().await,
+        }
+    }
+}
+
+//The relay and status LEDs
+struct Outputs {
+    relay_pin: Output<'static>,
+    allowed_led: Output<'static>,
+    denied_led: Output<'static>,
     //Additional GPIO leds - low = on.
-    let mut allowed_led_additional = Output::new(leds.green_led_additional_gpio, Level::Low);
-    let mut denied_led_additional = Output::new(leds.red_led_additional_gpio, Level::Low);
-    Timer::after_millis(500).await;
-    allowed_led.set_low();
-    denied_led.set_low();
+    allowed_led_additional: Output<'static>,
+    denied_led_additional: Output<'static>,
+}
 
-    allowed_led_additional.set_high();
-    denied_led_additional.set_high();
+impl Outputs {
+    //Flashing LEDs start on - see toggle_flashing()
+    fn show(&mut self, led: Led) {
+        let allowed = matches!(led, Led::Allowed | Led::Warning | Led::AwaitingSecond);
+        let denied = matches!(led, Led::Denied | Led::Warning);
+        self.allowed_led.set_level(Level::from(allowed));
+        self.denied_led.set_level(Level::from(denied));
+        self.allowed_led_additional.set_level(Level::from(!allowed));
+        self.denied_led_additional.set_level(Level::from(!denied));
+    }
 
+    fn toggle_flashing(&mut self, led: Led) {
+        self.allowed_led.toggle();
+        self.allowed_led_additional.toggle();
+        if led == Led::Warning {
+            self.denied_led.toggle();
+            self.denied_led_additional.toggle();
+        }
+    }
 
+    //Carry out the controller's actions - events are logged with the given timestamp
+    fn apply(&mut self, actions: Actions, timers: &mut Timers, timestamp: Timestamp) {
+        for action in actions {
+            match action {
+                //Only asked for when a card is presented - see main_task
+                Action::Lookup => {}
+                Action::Relay(on) => {
+                    debug!("Relay {}", if on { "on" } else { "off" });
+                    self.relay_pin.set_level(Level::from(on));
+                }
+                Action::Led(led) => self.show(led),
+                Action::Remote(message) => MAIN_MESSAGE_SIGNAL.signal(message),
+                Action::Log(event) => queue_log_message(timestamp, log_event(event)),
+                Action::StartTimer(timer, after) => timers.start(timer, after),
+            }
+        }
+    }
+}
 
+#[embassy_executor::task]
+pub async fn main_task(leds: StatusLedResources, relay: RelayResources) -> ! {    
+    //Receives message of new RFID read via signal, passes to database task.
+    //Receives message from database task - card allowed, card denied
+    //The access controller decides what the relay, LEDs and remote reader do, and what's logged
 
-    let mut relay_pin = Output::new(relay.relay_pin, Level::Low);
+    //Briefly flash the allowed and denied LEDs at startup of task
+    let mut outputs = Outputs {
+        relay_pin: Output::new(relay.relay_pin, Level::Low),
+        allowed_led: Output::new(leds.green_led, Level::High),
+        denied_led: Output::new(leds.red_led, Level::High),
+        allowed_led_additional: Output::new(leds.green_led_additional_gpio, Level::Low),
+        denied_led_additional: Output::new(leds.red_led_additional_gpio, Level::Low),
+    };
+    Timer::after_millis(500).await;
+    outputs.show(Led::Off);
 
-    let mut latch_state = LatchState::Disabled;
+    let mut controller = AccessController::new(controller_config());
+    let mut timers = Timers::default();
+    let mut admin_reads = 0u32;
+    let mut last_admin_read: Option<Instant> = None;
 
     loop {
-        //Await a message from the card reader handler
-        match CARDREADER_EVENT_SIGNAL.wait().await {
-            CardReaderEvent::CardMD5(digest) => {
-                //Format the digest into an ascii string, as that's what we currently use as the in-flash representation (legacy!)             
-                let mut hash_buf = [0x00u8; 32];
-                let hash_str = match format_no_std::show(&mut hash_buf, format_args!("{:032x}", digest)) {
-                    Ok(str) => {
-                        str
-                    }
-                    Err(_e) => {
-                        error!("Unable to format MD5 hash");
-                        continue;
+        //Await a message from the card reader handler, a relay test request, or a timer
+        let events = select4(
+            CARDREADER_EVENT_SIGNAL.wait(),
+            RELAY_TEST_SIGNAL.wait(),
+            timers.expired(),
+            flash(controller.led()),
+        );
+        match events.await {
+            Either4::Fourth(()) => outputs.toggle_flashing(controller.led()),
+            Either4::Second(()) => {
+                if controller.relay_on() {
+                    warn!("Relay test skipped - the device is in use");
+                    continue;
+                }
+                info!("Relay test - relay on for 1 second");
+                outputs.relay_pin.set_high();
+                outputs.allowed_led.set_high();
+                Timer::after_secs(1).await;
+                outputs.relay_pin.set_low();
+                outputs.show(controller.led());
+            }
+            Either4::Third(timer) => {
+                debug!("{} timer expired", timer);
+                let actions = controller.handle(Input::TimerExpired(timer));
+                outputs.apply(actions, &mut timers, clock::now());
+            }
+            Either4::First(CardReaderEvent::CardRead(uid)) => {
+                //The admin card is checked for access like any other, unless it's being used
+                //for provisioning
+                let admin_digest = card_id::admin_digest(&uid);
+                let admin = config_store::admin_card() == Some(admin_digest);
+                let admin_ok = if provisioning::is_enrolling() {
+                    //The first card presented in provisioning mode becomes the admin card
+                    let ok = admin || config_store::enrol_admin_card(&admin_digest).await.is_ok();
+                    if ok {
+                        provisioning::enrolled();
                     }
-                };
-                info!("Card read with hash {}", hash_str);
-                //Check if card valid - we use the hash_buf to avoid lifetime issues
-                DATABASE_COMMAND_SIGNAL.signal(DatabaseTaskCommand::CheckMD5Hash(hash_buf));
-                info!("Awaiting database task reply");
-                let card_valid = matches!(DATABASE_RESPONSE_SIGNAL.wait().await, DatabaseTaskResponse::Found);
-                
-                match CONFIG.latch_mode {
-                    LatchMode::Latching => {
-                        match latch_state {
-                            LatchState::Disabled => {
-                                if card_valid {
-                                    info!("Card valid, access granted");
-                                    relay_pin.set_high();
-                                    allowed_led.set_high();
-                                    allowed_led_additional.set_low();
-                                    latch_state = LatchState::Enabled(hash_buf);
-                                    queue_log_message(LogEvent::Activated(hash_buf));
-                                    //If we have a remote cardreader, it will set LED to green
-                                    MAIN_MESSAGE_SIGNAL.signal(uart_protocol::MainMessage::AccessGranted);
-
-                                } else {
-                                    info!("Card invalid, access denied");
-                                    denied_led.set_high();
-                                    denied_led_additional.set_low();
-                                    //Remote cardreader LED to red
-                                    MAIN_MESSAGE_SIGNAL.signal(uart_protocol::MainMessage::AccessDenied);
-                                    Timer::after_secs(2).await;
-                                    denied_led.set_low();
-                                    denied_led_additional.set_high();
-                                    queue_log_message(LogEvent::LoginFail(hash_buf));
-                                    //Turn off remote cardreader LED (if present)
-                                    MAIN_MESSAGE_SIGNAL.signal(uart_protocol::MainMessage::AwaitingCard);
-                                }
-                            }
-                            LatchState::Enabled(hash) => {
-                                //Doesn't matter if card is valid, this counts as a sign out
-                                info!("Signed out, device deactivated");
-                                relay_pin.set_low();
-                                allowed_led.set_low();
-                                allowed_led_additional.set_high();
-                                latch_state = LatchState::Disabled;
-                                queue_log_message(LogEvent::Deactivated(hash));
-                            }
+                    Some(ok)
+                } else if admin && provisioning::authorise() {
+                    info!("Admin card presented - provisioning settings may be saved");
+                    Some(true)
+                } else if admin {
+                    let held = last_admin_read.is_some_and(|at| at.elapsed() < ADMIN_READ_GAP);
+                    admin_reads = if held { admin_reads + 1 } else { 1 };
+                    last_admin_read = Some(Instant::now());
+                    if !provisioning::is_active() {
+                        info!("Admin card read ({}/{}) - hold to enter provisioning mode", admin_reads, ADMIN_HOLD_READS);
+                        if admin_reads == ADMIN_HOLD_READS {
+                            PROVISIONING_SIGNAL.signal(());
                         }
                     }
-                    LatchMode::Timed(time) => {
-                        if card_valid {
-                            info!("Card valid, latching for {} seconds", time.as_secs());
-                            relay_pin.set_high();
-                            allowed_led.set_high();
-                            allowed_led_additional.set_low();
-                            MAIN_MESSAGE_SIGNAL.signal(uart_protocol::MainMessage::AccessGranted);
-                            Timer::after(time).await;
-                            relay_pin.set_low();
-                            allowed_led.set_low();
-                            allowed_led_additional.set_high();
-                            MAIN_MESSAGE_SIGNAL.signal(uart_protocol::MainMessage::AwaitingCard);
-                            debug!("Deactivated");
-                            queue_log_message(LogEvent::Activated(hash_buf));
-                        } else {
-                            info!("Card invalid, access denied");
-                            denied_led.set_high();
-                            denied_led_additional.set_low();
-                            MAIN_MESSAGE_SIGNAL.signal(uart_protocol::MainMessage::AccessDenied);
-                            Timer::after_secs(2).await;
-                            denied_led.set_low();
-                            denied_led_additional.set_high();
-                            MAIN_MESSAGE_SIGNAL.signal(uart_protocol::MainMessage::AwaitingCard);
-                            queue_log_message(LogEvent::LoginFail(hash_buf));
-                        }
+                    //Only the first read of a hold is checked for access - holding the card
+                    //mustn't sign out again. The rest are paced to about one a second
+                    if held {
+                        Timer::after_secs(1).await;
+                        CARDREADER_EVENT_SIGNAL.reset();
+                        continue;
                     }
+                    None
+                } else {
+                    None
+                };
+                if let Some(ok) = admin_ok {
+                    outputs.show(if ok { Led::Allowed } else { Led::Denied });
+                    Timer::after_secs(1).await;
+                    outputs.show(controller.led());
+                    CARDREADER_EVENT_SIGNAL.reset();
+                    continue;
+                }
+
+                //Events are logged with the time the card was read
+                let timestamp = clock::now();
+                let mut actions = controller.handle(Input::CardPresented);
+                if actions.contains(&Action::Lookup) {
+                    let lookup = lookup_card(uid).await;
+                    actions = controller.handle(Input::Lookup(lookup));
+                } else {
+                    debug!("Card ignored - too soon after the last");
+                }
+                outputs.apply(actions, &mut timers, timestamp);
+            }
+        }
+    }
+}
+
+//Wait until the LEDs next need toggling - forever, unless they're flashing
+async fn flash(led: Led) {
+    match led {
+        Led::Warning => Timer::after(WARNING_FLASH_PERIOD).await,
+        Led::AwaitingSecond => Timer::after(AWAITING_SECOND_FLASH_PERIOD).await,
+        _ => core::future::pending().await,
+    }
+}
+
+fn controller_config() -> Config {
+    let latch_mode = match CONFIG.latch_mode {
+        LatchMode::Latching => ControllerLatchMode::Latching,
+        LatchMode::Timed(time) => ControllerLatchMode::Timed(core_duration(time)),
+        LatchMode::TwoPerson { window, supervisor } => ControllerLatchMode::TwoPerson {
+            window: core_duration(window),
+            supervisor,
+        },
+    };
+    Config {
+        latch_mode,
+        sign_out: CONFIG.sign_out,
+        handover: CONFIG.handover,
+        max_session: CONFIG.max_session.map(core_duration),
+        session_warning: core_duration(CONFIG.session_warning),
+    }
+}
+
+fn core_duration(duration: Duration) -> core::time::Duration {
+    core::time::Duration::from_micros(duration.as_micros())
+}
+
+//Ask the database task about a card
+async fn lookup_card(uid: CardUid) -> Lookup {
+    DATABASE_COMMAND_SIGNAL.signal(DatabaseTaskCommand::CheckCard(uid));
+    info!("Awaiting database task reply");
+    match DATABASE_RESPONSE_SIGNAL.wait().await {
+        DatabaseTaskResponse::Found(hash, record) => {
+            info!("Card valid ({}), hash {:02x}", record.member.as_str(), hash);
+            Lookup::Found(hash, record)
+        }
+        DatabaseTaskResponse::NotFound(hash) => {
+            info!("Card unknown, hash {:02x}", hash);
+            Lookup::NotFound(hash)
+        }
+        DatabaseTaskResponse::DeniedOutsideSchedule(hash, record) => {
+            info!("Card outside its schedule ({}), hash {:02x}", record.member.as_str(), hash);
+            Lookup::OutsideSchedule(hash, record)
+        }
+    }
+}
+
+fn log_event(event: Event) -> LogEvent {
+    match event {
+        Event::Activated(hash, record) => {
+            info!("Access granted");
+            LogEvent::Activated(hash, record)
+        }
+        Event::TwoPersonActivated(hash, record, second) => {
+            info!("Access granted to two people");
+            LogEvent::TwoPersonActivated(hash, record, second)
+        }
+        Event::Deactivated(hash, record, reason) => {
+            let reason = match reason {
+                Reason::SignedOut => DeactivationReason::SignedOut,
+                Reason::Timeout => DeactivationReason::Timeout,
+                Reason::Handover => DeactivationReason::Handover,
+            };
+            info!("Device deactivated ({})", reason.as_str());
+            LogEvent::Deactivated(hash, record, reason)
+        }
+        Event::SignOutRejected(hash, signed_in) => {
+            info!("Card {:02x} can't sign out {:02x}", hash, signed_in);
+            LogEvent::SignOutRejected(hash, signed_in)
+        }
+        Event::Denied(denial) => {
+            info!("Access denied");
+            match denial {
+                Denial::NotFound(hash) => LogEvent::LoginFail(hash),
+                Denial::OutsideSchedule(hash, record) => {
+                    LogEvent::DeniedOutsideSchedule(hash, record)
                 }
+                Denial::NoSupervisor(hash, record) => LogEvent::DeniedNoSupervisor(hash, record),
             }
         }
-        //Wait two seconds, then discard any pending message to avoid double-activation
-        Timer::after_secs(2).await;
-        CARDREADER_EVENT_SIGNAL.reset();
     }
 }
 
-fn queue_log_message(e: LogEvent) {
-    match LOG_EVENT_QUEUE.try_send(e) {
+fn queue_log_message(timestamp: Timestamp, event: LogEvent) {
+    match LOG_EVENT_QUEUE.try_send(StampedLogEvent { event, timestamp }) {
         Ok(_) => {
             debug!("Log event added to logger queue");
         }
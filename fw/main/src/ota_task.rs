@@ -0,0 +1,303 @@
+use core::cell::{Cell, RefCell};
+
+use embassy_boot_rp::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_net::{dns::DnsSocket, tcp::client::TcpClient};
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_time::WithTimeout;
+
+use defmt::*;
+
+use embedded_io_async::Read;
+
+use heapless::Vec;
+
+use reqwless::client::HttpClient;
+use reqwless::request::{Method, RequestBuilder};
+use reqwless::response::StatusCode;
+
+use static_cell::StaticCell;
+
+use crate::auth;
+use crate::config::CONFIG;
+use crate::database_task::{UpdateError, DB_SYNC_OK_SIGNAL};
+use crate::signature::SignedDownload;
+use crate::OtaResources;
+
+//Over-the-air firmware updates, applied by the bootloader (see fw/bootloader). Alongside each
+//database sync, the backend's firmware version is checked - if it differs from ours, the
+//signed image is downloaded into the DFU partition, and the controller reboots into it. The
+//new firmware is only kept once it has synced the database - if it can't within
+//CONFIG.ota_self_test_timeout, or reboots first, the bootloader rolls back to the old one.
+
+pub(crate) const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+//Pico W - 2MB
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+//The ACTIVE partition in memory.x
+const MAX_FIRMWARE_LEN: usize = 992 * 1024;
+const MAX_VERSION_LEN: usize = 32;
+
+pub(crate) type OtaFlash =
+    BlockingMutex<NoopRawMutex, RefCell<Flash<'static, FLASH, Blocking, FLASH_SIZE>>>;
+
+static OTA_FLASH: StaticCell<OtaFlash> = StaticCell::new();
+
+//Whether this firmware has been marked booted - until then it can still be rolled back, and
+//won't download further updates
+static BOOTED: BlockingMutex<CriticalSectionRawMutex, Cell<bool>> =
+    BlockingMutex::new(Cell::new(false));
+
+pub(crate) fn init(r: OtaResources) -> &'static OtaFlash {
+    OTA_FLASH.init(BlockingMutex::new(RefCell::new(Flash::new_blocking(
+        r.flash,
+    ))))
+}
+
+pub(crate) fn is_booted() -> bool {
+    BOOTED.lock(|booted| booted.get())
+}
+
+//Runs the self-test after an update
+#[embassy_executor::task]
+pub async fn ota_task(flash: &'static OtaFlash) {
+    let mut aligned = AlignedBuffer([0x00u8; 1]);
+    let mut updater = BlockingFirmwareUpdater::new(
+        FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash),
+        &mut aligned.0,
+    );
+    info!("Firmware version {}", FIRMWARE_VERSION);
+
+    match updater.get_state() {
+        Ok(State::Swap) => {
+            warn!(
+                "Firmware updated - self-test requires a database sync within {}s",
+                CONFIG.ota_self_test_timeout.as_secs()
+            );
+            match DB_SYNC_OK_SIGNAL
+                .wait()
+                .with_timeout(CONFIG.ota_self_test_timeout)
+                .await
+            {
+                Ok(_) => {
+                    updater
+                        .mark_booted()
+                        .expect("Unable to mark firmware booted");
+                    info!("Self-test passed - keeping the new firmware");
+                    BOOTED.lock(|booted| booted.set(true));
+                }
+                Err(_) => {
+                    error!("Self-test failed - rolling back to the previous firmware");
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+            }
+        }
+        Ok(_) => BOOTED.lock(|booted| booted.set(true)),
+        Err(e) => error!("Unable to read bootloader state - {}", e),
+    }
+}
+
+//Check the backend's firmware version, and if it differs from ours, download and verify the
+//image and reboot into it. Returns if there's no update to apply
+pub(crate) async fn check_for_update(
+    flash: &OtaFlash,
+    http_client: &mut HttpClient<'_, TcpClient<'_, 2>, DnsSocket<'_>>,
+) -> Result<(), UpdateError> {
+    if CONFIG.firmware_signing_key.is_none() || !is_booted() {
+        return Ok(());
+    }
+    let version = get_remote_firmware_version(http_client).await?;
+    if version == FIRMWARE_VERSION.as_bytes() {
+        debug!("Firmware up to date");
+        return Ok(());
+    }
+    info!(
+        "Commencing firmware update from {} to {:a}",
+        FIRMWARE_VERSION,
+        version.as_slice()
+    );
+
+    let len = download_firmware(flash, http_client, &version).await?;
+    warn!(
+        "Firmware update downloaded ({} bytes) - rebooting to apply it",
+        len
+    );
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+//Stream the image into the DFU partition a flash page at a time, and once its signature has
+//been checked, mark it for the bootloader to swap in. Returns the image length
+async fn download_firmware(
+    flash: &OtaFlash,
+    http_client: &mut HttpClient<'_, TcpClient<'_, 2>, DnsSocket<'_>>,
+    version: &[u8],
+) -> Result<usize, UpdateError> {
+    let mut url_buf = [0x00u8; 128];
+    let url = format_no_std::show(
+        &mut url_buf,
+        format_args!(
+            "{}/{}/{}",
+            CONFIG.url_endpoint, CONFIG.device_name, CONFIG.firmware_prefix
+        ),
+    )
+    .expect("Unable to build firmware URL");
+    debug!("Downloading firmware from {}", url);
+
+    let mut rx_buffer = [0; 2048];
+    let mut auth_buf = [0x00u8; auth::AUTH_HEADER_BUF_LEN];
+    let auth = auth::header(&mut auth_buf);
+    let mut request = match embassy_time::with_timeout(
+        CONFIG.http_timeout,
+        http_client.request(Method::GET, url),
+    )
+    .await
+    {
+        Ok(e) => e.map_err(UpdateError::connection)?.headers(auth.as_slice()),
+        Err(_) => {
+            return Err(UpdateError::Timeout);
+        }
+    };
+    let response =
+        match embassy_time::with_timeout(CONFIG.http_timeout, request.send(&mut rx_buffer)).await {
+            Ok(e) => e.map_err(UpdateError::connection)?,
+            Err(_) => {
+                return Err(UpdateError::Timeout);
+            }
+        };
+
+    if auth::is_unauthorized(response.status) {
+        return Err(UpdateError::Unauthorized);
+    }
+    if !StatusCode::is_successful(&response.status) {
+        return Err(UpdateError::RemoteServerError(response.status));
+    }
+    if response
+        .content_length
+        .is_some_and(|len| len > MAX_FIRMWARE_LEN)
+    {
+        error!("Firmware image too large");
+        return Err(UpdateError::InvalidFirmware);
+    }
+
+    let mut aligned = AlignedBuffer([0x00u8; 1]);
+    let mut updater = BlockingFirmwareUpdater::new(
+        FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash),
+        &mut aligned.0,
+    );
+    let mut signed = SignedDownload::firmware(version, response.headers());
+    //Flash is erased and written a page at a time
+    let mut page = AlignedBuffer([0xffu8; ERASE_SIZE]);
+    let mut filled = 0usize;
+    let mut offset = 0usize;
+    let mut reader = response.body().reader();
+    loop {
+        //A read error or stalled stream means the image is incomplete - abandon the update
+        let len = match embassy_time::with_timeout(
+            CONFIG.http_timeout,
+            reader.read(&mut page.0[filled..]),
+        )
+        .await
+        {
+            Ok(e) => e.map_err(|_| UpdateError::ConnectionError)?,
+            Err(_) => {
+                return Err(UpdateError::Timeout);
+            }
+        };
+        signed.update(&page.0[filled..filled + len]);
+        filled += len;
+        if offset + filled > MAX_FIRMWARE_LEN {
+            error!("Firmware image too large");
+            return Err(UpdateError::InvalidFirmware);
+        }
+
+        //Write each full page, and the (padded) last one at EOF
+        if filled == ERASE_SIZE || (len == 0 && filled > 0) {
+            page.0[filled..].fill(0xff);
+            updater
+                .write_firmware(offset, &page.0)
+                .map_err(|_| UpdateError::FlashError)?;
+            offset += filled;
+            filled = 0;
+        }
+        if len == 0 {
+            break;
+        }
+    }
+
+    if offset == 0 {
+        error!("Firmware image empty");
+        return Err(UpdateError::InvalidFirmware);
+    }
+    if !signed.verify() {
+        error!("Firmware signature invalid - rejecting update");
+        return Err(UpdateError::SignatureInvalid);
+    }
+    updater
+        .mark_updated()
+        .map_err(|_| UpdateError::FlashError)?;
+    Ok(offset)
+}
+
+//The firmware version the backend offers - any that differs from ours is installed, so a
+//rollback is just a matter of offering the older version
+async fn get_remote_firmware_version(
+    http_client: &mut HttpClient<'_, TcpClient<'_, 2>, DnsSocket<'_>>,
+) -> Result<Vec<u8, MAX_VERSION_LEN>, UpdateError> {
+    let mut url_buf = [0x00u8; 128];
+    let url = format_no_std::show(
+        &mut url_buf,
+        format_args!(
+            "{}/{}/{}",
+            CONFIG.url_endpoint, CONFIG.device_name, CONFIG.firmware_version_prefix
+        ),
+    )
+    .expect("Unable to build firmware version URL");
+    debug!("Obtaining remote firmware version from {}", url);
+
+    let mut rx_buffer = [0; 2048];
+    let mut auth_buf = [0x00u8; auth::AUTH_HEADER_BUF_LEN];
+    let auth = auth::header(&mut auth_buf);
+    let mut request = match embassy_time::with_timeout(
+        CONFIG.http_timeout,
+        http_client.request(Method::GET, url),
+    )
+    .await
+    {
+        Ok(e) => e.map_err(UpdateError::connection)?.headers(auth.as_slice()),
+        Err(_) => {
+            return Err(UpdateError::Timeout);
+        }
+    };
+    let response =
+        match embassy_time::with_timeout(CONFIG.http_timeout, request.send(&mut rx_buffer)).await {
+            Ok(e) => e.map_err(UpdateError::connection)?,
+            Err(_) => {
+                return Err(UpdateError::Timeout);
+            }
+        };
+
+    if auth::is_unauthorized(response.status) {
+        return Err(UpdateError::Unauthorized);
+    }
+    if !StatusCode::is_successful(&response.status) {
+        return Err(UpdateError::RemoteServerError(response.status));
+    }
+
+    let mut buf = [0x00u8; 64];
+    let mut reader = response.body().reader();
+    let len = reader
+        .read(&mut buf)
+        .await
+        .map_err(|_| UpdateError::ConnectionError)?;
+    let version = buf[..len].trim_ascii();
+    if version.is_empty() {
+        error!("Invalid firmware version");
+        return Err(UpdateError::InvalidFirmware);
+    }
+    Vec::from_slice(version).map_err(|_| {
+        error!("Invalid firmware version");
+        UpdateError::InvalidFirmware
+    })
+}
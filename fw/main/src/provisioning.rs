@@ -0,0 +1,431 @@
+use core::cell::Cell;
+use core::fmt::Write;
+
+use cyw43::Control;
+
+use embassy_futures::join::join3;
+use embassy_futures::select::select;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{ConfigV4, IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
+
+use embedded_io_async::Write as _;
+
+use defmt::{Format, *};
+
+use heapless::String;
+
+use provisioning::{
+    decode_form_value, dns_reply, form_fields, parse_request, DhcpServer, HtmlEscaped, Method,
+    DHCP_CLIENT_PORT, DHCP_REPLY_LEN, DHCP_SERVER_PORT, DNS_PORT,
+};
+
+use crate::config::CONFIG;
+use crate::config_store::{self, ConfigError, StoredConfig};
+
+//Provisioning mode - the controller becomes a wifi access point, serving a config page where
+//the wifi network, device name and backend can be set. Entered when there are no wifi
+//credentials, when no network can be joined at boot (see wifi_task), with the provisioning
+//strap fitted at boot, or by holding the admin card on the reader. With the strap fitted, or
+//no wifi network configured and no admin card enrolled, the first card presented is enrolled as
+//the admin card. Otherwise - after a failed join, or from the admin card - the admin card has to
+//be presented before the settings can be saved, as anyone near the controller could join the
+//access point. Any other card is checked for access as usual.
+
+//The controller's address on its own network - clients are given addresses in this /24
+const AP_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+const AP_CHANNEL: u8 = 6;
+const AP_SSID_PREFIX: &str = "access-";
+const HTTP_PORT: u16 = 80;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_LEN: usize = 1024;
+const MAX_PAGE_LEN: usize = 4096;
+//With wifi networks configured, provisioning mode is left after this long without a config
+//page request, to retry them - e.g. after a power cut, if the router was slow to come back
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+type Page = String<MAX_PAGE_LEN>;
+
+//Why provisioning mode was entered
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub(crate) enum Entry {
+    Strap,        //Provisioning strap fitted at boot
+    Unconfigured, //No wifi network configured
+    JoinFailed,   //No configured network could be joined at boot
+    AdminCard,    //Admin card held on the reader
+}
+
+//Signal to leave normal operation for provisioning mode
+pub(crate) static PROVISIONING_SIGNAL: Signal<ThreadModeRawMutex, ()> = Signal::new();
+//Signalled for each config page request, to hold off IDLE_TIMEOUT
+static REQUEST_SIGNAL: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+static ACTIVE: BlockingMutex<CriticalSectionRawMutex, Cell<bool>> =
+    BlockingMutex::new(Cell::new(false));
+//Whether the next card presented is enrolled as the admin card
+static ENROLLING: BlockingMutex<CriticalSectionRawMutex, Cell<bool>> =
+    BlockingMutex::new(Cell::new(false));
+//Whether the settings may be saved
+static AUTHORISED: BlockingMutex<CriticalSectionRawMutex, Cell<bool>> =
+    BlockingMutex::new(Cell::new(false));
+
+#[derive(Debug, Format)]
+enum ServeError {
+    ConnectionError,
+    RequestTooLarge,
+    MalformedRequest,
+}
+
+pub(crate) fn check_config() {
+    assert!(
+        (8..=63).contains(&CONFIG.provisioning_ap_pw.len()),
+        "provisioning_ap_pw must be 8-63 characters"
+    );
+}
+
+//Whether the controller is in provisioning mode
+pub(crate) fn is_active() -> bool {
+    ACTIVE.lock(|active| active.get())
+}
+
+//Whether the next card presented should be enrolled as the admin card
+pub(crate) fn is_enrolling() -> bool {
+    ENROLLING.lock(|enrolling| enrolling.get())
+}
+
+//An admin card has been enrolled - any further cards are just checked for access
+pub(crate) fn enrolled() {
+    ENROLLING.lock(|enrolling| enrolling.set(false));
+}
+
+//The admin card has been presented - allow the settings to be saved. Returns false if not in
+//provisioning mode, or already allowed
+pub(crate) fn authorise() -> bool {
+    is_active() && !AUTHORISED.lock(|authorised| authorised.replace(true))
+}
+
+fn is_authorised() -> bool {
+    AUTHORISED.lock(|authorised| authorised.get())
+}
+
+//Start the access point and serve the config page. Saving the settings reboots the controller.
+//Returns (with the access point closed) if left idle, when there are networks to retry
+pub(crate) async fn run(control: &mut Control<'static>, stack: Stack<'static>, entry: Entry) {
+    //Only the strap - inside the enclosure - allows the admin card to be replaced. Without it,
+    //one can only be enrolled on a controller that has never been set up
+    let enrolling = match entry {
+        Entry::Strap => true,
+        Entry::Unconfigured => config_store::admin_card().is_none(),
+        Entry::JoinFailed | Entry::AdminCard => false,
+    };
+    ENROLLING.lock(|e| e.set(enrolling));
+    AUTHORISED.lock(|a| a.set(matches!(entry, Entry::Strap | Entry::Unconfigured)));
+    ACTIVE.lock(|active| active.set(true));
+
+    //The device name identifies the controller, trimmed to fit the 32 character SSID limit
+    let mut ssid: String<32> = String::from(AP_SSID_PREFIX);
+    for c in CONFIG.device_name.chars() {
+        if ssid.push(c).is_err() {
+            break;
+        }
+    }
+    control
+        .start_ap_wpa2(&ssid, CONFIG.provisioning_ap_pw, AP_CHANNEL)
+        .await;
+    stack.set_config_v4(ConfigV4::Static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::from(AP_ADDRESS), 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    }));
+    warn!(
+        "Provisioning mode ({}) - join wifi network {} and open any web page",
+        entry,
+        ssid.as_str()
+    );
+
+    //The servers never return
+    let servers = join3(dhcp_server(stack), dns_server(stack), http_server(stack));
+    match entry {
+        Entry::Unconfigured => servers.await.0,
+        _ => {
+            select(servers, idle()).await;
+            warn!("Provisioning mode idle - leaving to retry the wifi networks");
+        }
+    }
+    control.close_ap().await;
+    ACTIVE.lock(|active| active.set(false));
+    ENROLLING.lock(|e| e.set(false));
+    AUTHORISED.lock(|a| a.set(false));
+}
+
+//Returns once no config page has been requested for IDLE_TIMEOUT
+async fn idle() {
+    REQUEST_SIGNAL.reset();
+    while with_timeout(IDLE_TIMEOUT, REQUEST_SIGNAL.wait())
+        .await
+        .is_ok()
+    {}
+}
+
+async fn dhcp_server(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0x00u8; 2048];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0x00u8; 2048];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(DHCP_SERVER_PORT)
+        .expect("Unable to bind DHCP server");
+
+    let mut server = DhcpServer::new(AP_ADDRESS);
+    let mut request = [0x00u8; 576];
+    let mut reply = [0x00u8; DHCP_REPLY_LEN];
+    loop {
+        let len = match socket.recv_from(&mut request).await {
+            Ok((len, _)) => len,
+            Err(_) => continue,
+        };
+        if let Some(len) = server.handle(&request[..len], &mut reply) {
+            //Clients have no address yet, so can only receive broadcasts
+            let client =
+                IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), DHCP_CLIENT_PORT);
+            if socket.send_to(&reply[..len], client).await.is_err() {
+                warn!("Unable to send DHCP reply");
+            }
+        }
+    }
+}
+
+async fn dns_server(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0x00u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0x00u8; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(DNS_PORT).expect("Unable to bind DNS server");
+
+    let mut query = [0x00u8; 512];
+    let mut reply = [0x00u8; 512];
+    loop {
+        let (len, meta) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        if let Some(len) = dns_reply(&query[..len], AP_ADDRESS, &mut reply) {
+            if socket.send_to(&reply[..len], meta.endpoint).await.is_err() {
+                warn!("Unable to send DNS reply");
+            }
+        }
+    }
+}
+
+async fn http_server(stack: Stack<'static>) -> ! {
+    let mut rx_buffer = [0x00u8; 1024];
+    let mut tx_buffer = [0x00u8; 2048];
+    let mut request = [0x00u8; MAX_REQUEST_LEN];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(HTTP_TIMEOUT));
+        if socket.accept(HTTP_PORT).await.is_err() {
+            continue;
+        }
+        REQUEST_SIGNAL.signal(());
+        let reboot = match serve(&mut socket, &mut request).await {
+            Ok(reboot) => reboot,
+            Err(e) => {
+                warn!("Config page request failed - {}", e);
+                false
+            }
+        };
+        socket.close();
+        let _ = socket.flush().await;
+        if reboot {
+            warn!("Settings saved - rebooting");
+            Timer::after_millis(500).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+}
+
+//Answer one request. Returns true if new settings were saved
+async fn serve(socket: &mut TcpSocket<'_>, buf: &mut [u8]) -> Result<bool, ServeError> {
+    let mut len = 0usize;
+    loop {
+        match parse_request(&buf[..len]) {
+            Ok(Some(_)) => break,
+            Ok(None) if len == buf.len() => return Err(ServeError::RequestTooLarge),
+            Ok(None) => {}
+            Err(_) => return Err(ServeError::MalformedRequest),
+        }
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return Err(ServeError::ConnectionError),
+            Ok(read) => len += read,
+        }
+    }
+    let request = match parse_request(&buf[..len]) {
+        Ok(Some(request)) => request,
+        _ => return Err(ServeError::MalformedRequest),
+    };
+    debug!("Config page request {} {}", request.method, request.path);
+
+    //Every page is the config page, so phones show it as a captive portal
+    let mut page = Page::new();
+    let (status, saved) = match (request.method, request.path) {
+        (Method::Post, "/save") => match save(request.body).await {
+            Ok(_) => {
+                saved_page(&mut page);
+                ("200 OK", true)
+            }
+            Err(e) => {
+                warn!("Settings rejected - {}", e);
+                config_page(&mut page, Some(&e)).await;
+                ("400 Bad Request", false)
+            }
+        },
+        (Method::Get, _) => {
+            config_page(&mut page, None).await;
+            ("200 OK", false)
+        }
+        _ => ("405 Method Not Allowed", false),
+    };
+
+    let mut header_buf = [0x00u8; 160];
+    let header = format_no_std::show(
+        &mut header_buf,
+        format_args!(
+            "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            page.len()
+        ),
+    )
+    .expect("Unable to build HTTP header");
+    socket
+        .write_all(header.as_bytes())
+        .await
+        .map_err(|_| ServeError::ConnectionError)?;
+    socket
+        .write_all(page.as_bytes())
+        .await
+        .map_err(|_| ServeError::ConnectionError)?;
+    Ok(saved)
+}
+
+//Apply the submitted form to the stored settings, and save them
+async fn save(body: &[u8]) -> Result<(), ConfigError> {
+    if !is_authorised() {
+        return Err(ConfigError::NotAuthorised);
+    }
+    let mut stored = config_store::read_stored_or_default().await;
+    let mut open = false;
+    for (name, value) in form_fields(body) {
+        match name {
+            b"ssid" => stored.ssid = Some(field(value, ConfigError::InvalidSsid)?),
+            //Left blank to keep the current password
+            b"wifi_pw" if !value.is_empty() => {
+                stored.wifi_pw = Some(field(value, ConfigError::InvalidWifiPw)?)
+            }
+            b"open" => open = true,
+            //Left blank to use the compiled-in defaults
+            b"device_name" if value.is_empty() => stored.device_name = None,
+            b"device_name" => {
+                stored.device_name = Some(field(value, ConfigError::InvalidDeviceName)?)
+            }
+            b"url_endpoint" if value.is_empty() => stored.url_endpoint = None,
+            b"url_endpoint" => {
+                stored.url_endpoint = Some(field(value, ConfigError::InvalidUrlEndpoint)?)
+            }
+            _ => {}
+        }
+    }
+    if open {
+        stored.wifi_pw = Some(String::new());
+    }
+    if stored.ssid.is_none() {
+        return Err(ConfigError::InvalidSsid);
+    }
+    config_store::save(&stored).await
+}
+
+//A submitted value - undecodable or too long is invalid
+fn field<const N: usize>(value: &[u8], error: ConfigError) -> Result<String<N>, ConfigError> {
+    decode_form_value(value).map_err(|_| error)
+}
+
+//The form is filled in with the stored settings, falling back to those in use
+async fn config_page(page: &mut Page, error: Option<&ConfigError>) {
+    let stored: StoredConfig = config_store::read_stored_or_default().await;
+    let ssid = stored.ssid.as_deref().unwrap_or(CONFIG.ssid);
+    let device_name = stored.device_name.as_deref().unwrap_or(CONFIG.device_name);
+    let url_endpoint = stored
+        .url_endpoint
+        .as_deref()
+        .unwrap_or(CONFIG.url_endpoint);
+    let admin_card = match (config_store::admin_card(), is_enrolling()) {
+        (Some(_), true) => "An admin card is enrolled. Present a card to the reader to replace it.",
+        (Some(_), false) => {
+            "An admin card is enrolled. To replace it, fit the provisioning strap and restart."
+        }
+        (None, true) => "No admin card is enrolled. Present a card to the reader to enrol it.",
+        (None, false) => {
+            "No admin card is enrolled. To enrol one, fit the provisioning strap and restart."
+        }
+    };
+    let authorise = match (is_authorised(), config_store::admin_card()) {
+        (true, _) => "",
+        (false, Some(_)) => "<p><b>Present the admin card to the reader before saving.</b></p>",
+        (false, None) => "<p><b>Fit the provisioning strap to save settings.</b></p>",
+    };
+
+    //Pages are truncated if they don't fit - the write! results are deliberately ignored
+    let _ = page.push_str(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+         <title>Access controller setup</title></head><body><h1>Access controller setup</h1>",
+    );
+    let _ = page.push_str(authorise);
+    if let Some(error) = error {
+        let _ = write!(
+            page,
+            "<p><b>Not saved - {}</b></p>",
+            HtmlEscaped(error.as_str())
+        );
+    }
+    let _ = write!(
+        page,
+        "<form method=\"post\" action=\"/save\">\
+         <p><label>Wifi network<br><input name=\"ssid\" value=\"{}\" maxlength=\"32\" required></label></p>\
+         <p><label>Wifi password<br><input name=\"wifi_pw\" type=\"password\" maxlength=\"63\" \
+         placeholder=\"Unchanged\"></label></p>\
+         <p><label><input name=\"open\" type=\"checkbox\"> Open network (no password)</label></p>\
+         <p><label>Device name<br><input name=\"device_name\" value=\"{}\" maxlength=\"32\"></label></p>\
+         <p><label>Backend URL<br><input name=\"url_endpoint\" value=\"{}\" maxlength=\"64\"></label></p>\
+         <p><button>Save and restart</button></p></form><p>{}</p></body></html>",
+        HtmlEscaped(ssid),
+        HtmlEscaped(device_name),
+        HtmlEscaped(url_endpoint),
+        admin_card
+    );
+}
+
+fn saved_page(page: &mut Page) {
+    let _ = page.push_str(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+         <title>Access controller setup</title></head><body><h1>Settings saved</h1>\
+         <p>The controller is restarting, and will join the new wifi network.</p></body></html>",
+    );
+}
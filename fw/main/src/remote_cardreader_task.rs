@@ -11,6 +11,7 @@ use embassy_sync::signal::Signal;
 use heapless::Vec;
 use postcard::{from_bytes_cobs, to_vec_cobs};
 
+use crate::card_id::CardUid;
 use crate::main_task::{CardReaderEvent, CARDREADER_EVENT_SIGNAL};
 use crate::UartResources;
 use uart_protocol::{MainMessage, RemoteMessage};
@@ -51,18 +52,21 @@ pub async fn remote_cardreader_task(uart: UartResources) {
                     Ok(msg) => match msg {
                         RemoteMessage::SingleUid(data) => {
                             debug!("Single UID card - {}", data);
-                            CARDREADER_EVENT_SIGNAL
-                                .signal(CardReaderEvent::CardMD5(md5::compute(data)));
+                            CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardRead(
+                                CardUid::from_slice(&data).unwrap(),
+                            ));
                         }
                         RemoteMessage::DoubleUid(data) => {
                             debug!("Double UID card - {}", data);
-                            CARDREADER_EVENT_SIGNAL
-                                .signal(CardReaderEvent::CardMD5(md5::compute(data)));
+                            CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardRead(
+                                CardUid::from_slice(&data).unwrap(),
+                            ));
                         }
                         RemoteMessage::TripleUid(data) => {
                             debug!("Triple UID card - {}", data);
-                            CARDREADER_EVENT_SIGNAL
-                                .signal(CardReaderEvent::CardMD5(md5::compute(data)));
+                            CARDREADER_EVENT_SIGNAL.signal(CardReaderEvent::CardRead(
+                                CardUid::from_slice(&data).unwrap(),
+                            ));
                         }
                         RemoteMessage::ReadError => {
                             error!("Card read error");
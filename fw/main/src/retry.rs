@@ -0,0 +1,124 @@
+use core::cell::Cell;
+
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_time::Duration;
+
+use defmt::Format;
+
+use rand::RngCore;
+
+use reqwless::response::StatusCode;
+
+//Backoff settings for a retry policy
+pub(crate) struct RetryConfig {
+    pub base: Duration,     //Delay after the first failure - doubles with each further failure
+    pub cap: Duration,      //Longest delay between attempts
+    pub jitter_percent: u8, //Each delay is randomly varied by up to this much, either way
+}
+
+//Why an attempt failed - each kind backs off differently
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub(crate) enum FailureKind {
+    WifiNotConnected, //Retried at the base delay - nothing to back off from
+    Timeout,          //Backs off exponentially
+    ServerError,      //5xx (and 429 Too Many Requests) - backs off exponentially
+    ClientError,      //Other 4xx - retrying soon won't help, so waits the full cap
+    Other,            //Connection, flash or data errors - backs off exponentially
+}
+
+impl FailureKind {
+    pub(crate) fn from_status(status: StatusCode) -> Self {
+        match status.0 {
+            408 => FailureKind::Timeout,
+            429 | 500..=599 => FailureKind::ServerError,
+            400..=499 => FailureKind::ClientError,
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+//Snapshot of a retry policy - each task publishes its policy's status in a static below,
+//for diagnostics
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RetryStatus {
+    pub failures: u32, //Consecutive failures since the last success
+    pub last_failure: Option<FailureKind>,
+    pub delay: Duration, //Delay before the current retry
+}
+
+impl RetryStatus {
+    const fn new() -> Self {
+        Self {
+            failures: 0,
+            last_failure: None,
+            delay: Duration::from_ticks(0),
+        }
+    }
+}
+
+pub(crate) type SharedRetryStatus = BlockingMutex<CriticalSectionRawMutex, Cell<RetryStatus>>;
+
+pub(crate) static DB_SYNC_RETRY_STATUS: SharedRetryStatus =
+    BlockingMutex::new(Cell::new(RetryStatus::new()));
+pub(crate) static LOG_RETRY_STATUS: SharedRetryStatus =
+    BlockingMutex::new(Cell::new(RetryStatus::new()));
+pub(crate) static WIFI_RETRY_STATUS: SharedRetryStatus =
+    BlockingMutex::new(Cell::new(RetryStatus::new()));
+
+//Exponential backoff with jitter. Reset on success
+pub(crate) struct RetryPolicy {
+    config: &'static RetryConfig,
+    status: RetryStatus,
+    report: &'static SharedRetryStatus,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(config: &'static RetryConfig, report: &'static SharedRetryStatus) -> Self {
+        Self {
+            config,
+            status: RetryStatus::new(),
+            report,
+        }
+    }
+
+    pub(crate) fn success(&mut self) {
+        self.status = RetryStatus::new();
+        self.report.lock(|status| status.set(self.status));
+    }
+
+    //Record a failure, returning how long to wait before the next attempt
+    pub(crate) fn failure(&mut self, kind: FailureKind) -> Duration {
+        let backoff = match kind {
+            FailureKind::WifiNotConnected => self.config.base,
+            FailureKind::ClientError => self.config.cap,
+            FailureKind::Timeout | FailureKind::ServerError | FailureKind::Other => {
+                //base * 2^failures, without overflowing
+                let shift = self.status.failures.min(16);
+                Duration::from_ticks(self.config.base.as_ticks().saturating_mul(1 << shift))
+            }
+        };
+        if kind != FailureKind::WifiNotConnected {
+            self.status.failures = self.status.failures.saturating_add(1);
+        }
+        self.status.last_failure = Some(kind);
+        self.status.delay = jitter(backoff.min(self.config.cap), self.config.jitter_percent);
+        self.report.lock(|status| status.set(self.status));
+        self.status.delay
+    }
+
+    pub(crate) fn status(&self) -> RetryStatus {
+        self.status
+    }
+}
+
+//Vary delay randomly by up to +/- percent, so devices don't all retry in lockstep
+fn jitter(delay: Duration, percent: u8) -> Duration {
+    let range = delay.as_ticks() * percent.min(100) as u64 / 100;
+    if range == 0 {
+        return delay;
+    }
+    let offset = RoscRng.next_u64() % (2 * range + 1);
+    Duration::from_ticks(delay.as_ticks() - range + offset)
+}
@@ -0,0 +1,130 @@
+use defmt::*;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest as _, Sha256};
+
+use access_db::{decode_hex_bytes, RemoteVersion};
+
+use crate::CONFIG;
+
+//Response header carrying the server's signature, as 128 hex characters
+const SIGNATURE_HEADER: &str = "X-Signature";
+
+//The server signs the SHA-256 of everything that makes up the download - a domain tag, the
+//version(s) and the body exactly as sent - so the device can verify the (large) body as it
+//streams in. Full database:  SHA-256("db\0" || version || remote || body)
+//Delta:                      SHA-256("delta\0" || from version || 0x00 || to version || remote
+//                                     || body)
+//Firmware image:             SHA-256("fw\0" || version || image)
+//where remote is the rest of the DB version reply: 0x00 || card ids, comma separated || 0x00 ||
+//serial, u64 big-endian. Signing the serial is what stops an older database being replayed
+pub(crate) struct SignedDownload {
+    hasher: Sha256,
+    signature: Option<[u8; 64]>,
+    key: Option<&'static [u8; 32]>,
+}
+
+impl SignedDownload {
+    //Start verifying a full database download, given the response headers
+    pub(crate) fn database<'a>(
+        remote: &RemoteVersion,
+        headers: impl Iterator<Item = (&'a str, &'a [u8])>,
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"db\0");
+        hasher.update(remote.version);
+        update_remote(&mut hasher, remote);
+        Self {
+            hasher,
+            signature: find_signature(headers),
+            key: CONFIG.db_signing_key.as_ref(),
+        }
+    }
+
+    //Start verifying a delta download, given the response headers
+    pub(crate) fn delta<'a>(
+        from_version: &[u8],
+        remote: &RemoteVersion,
+        headers: impl Iterator<Item = (&'a str, &'a [u8])>,
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"delta\0");
+        hasher.update(from_version);
+        hasher.update(b"\0");
+        hasher.update(remote.version);
+        update_remote(&mut hasher, remote);
+        Self {
+            hasher,
+            signature: find_signature(headers),
+            key: CONFIG.db_signing_key.as_ref(),
+        }
+    }
+
+    //Start verifying a firmware image download, given the response headers
+    pub(crate) fn firmware<'a>(
+        version: &[u8],
+        headers: impl Iterator<Item = (&'a str, &'a [u8])>,
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"fw\0");
+        hasher.update(version);
+        Self {
+            hasher,
+            signature: find_signature(headers),
+            key: CONFIG.firmware_signing_key.as_ref(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, body: &[u8]) {
+        self.hasher.update(body);
+    }
+
+    //Check the signature covers what was received. Without a signing key configured, any
+    //download is accepted
+    pub(crate) fn verify(self) -> bool {
+        let Some(key) = self.key else {
+            return true;
+        };
+        let Ok(key) = VerifyingKey::from_bytes(key) else {
+            error!("Invalid signing key in config");
+            return false;
+        };
+        let Some(signature) = self.signature else {
+            error!("Download is not signed");
+            return false;
+        };
+        let digest = self.hasher.finalize();
+        key.verify_strict(&digest, &Signature::from_bytes(&signature)).is_ok()
+    }
+}
+
+//The card ids and serial from the DB version reply, which isn't itself signed
+fn update_remote(hasher: &mut Sha256, remote: &RemoteVersion) {
+    hasher.update(b"\0");
+    for (i, algorithm) in remote.card_ids.iter().enumerate() {
+        if i > 0 {
+            hasher.update(b",");
+        }
+        hasher.update(algorithm.as_str());
+    }
+    hasher.update(b"\0");
+    hasher.update(remote.serial.unwrap_or_default().to_be_bytes());
+}
+
+fn find_signature<'a>(
+    mut headers: impl Iterator<Item = (&'a str, &'a [u8])>,
+) -> Option<[u8; 64]> {
+    let (_, value) = headers.find(|(name, _)| name.eq_ignore_ascii_case(SIGNATURE_HEADER))?;
+    decode_hex_bytes(value.trim_ascii()).ok()
+}
+
+//Warn at startup if downloaded databases aren't verified
+pub(crate) fn check_config() {
+    match CONFIG.db_signing_key {
+        Some(_) => info!("Database downloads must be signed"),
+        None => warn!("No database signing key configured - downloads are not verified"),
+    }
+    if CONFIG.firmware_signing_key.is_none() {
+        info!("No firmware signing key configured - OTA updates disabled");
+    }
+}
@@ -0,0 +1,152 @@
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_rp::clocks::RoscRng;
+use embassy_time::{Duration, Instant, Timer};
+
+use defmt::{Format, *};
+
+use rand::RngCore;
+
+use crate::clock;
+use crate::CONFIG;
+
+const NTP_PORT: u16 = 123;
+const NTP_PACKET_LEN: usize = 48;
+//Seconds from the NTP epoch (1900) to the unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+//How long to wait before retrying after a failed sync
+const SNTP_RETRY_TIME: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Format)]
+pub(crate) enum SntpError {
+    WifiNotConnected,
+    DnsError,        //Unable to resolve the NTP server
+    ConnectionError, //Unable to send the request
+    Timeout,
+    InvalidResponse, //Not a server reply to our request, or the server is unsynchronised
+}
+
+#[embassy_executor::task]
+pub async fn sntp_task(stack: Stack<'static>) -> ! {
+    loop {
+        stack.wait_config_up().await;
+        match sync_clock(stack).await {
+            Ok(_) => {
+                Timer::after(CONFIG.sntp_sync_frequency).await;
+            }
+            Err(e) => {
+                warn!("SNTP sync failed ({}), retrying in {}s", e, SNTP_RETRY_TIME.as_secs());
+                Timer::after(SNTP_RETRY_TIME).await;
+            }
+        }
+    }
+}
+
+async fn sync_clock(stack: Stack<'static>) -> Result<(), SntpError> {
+    if !stack.is_config_up() {
+        return Err(SntpError::WifiNotConnected);
+    }
+
+    let server = match embassy_time::with_timeout(
+        CONFIG.http_timeout,
+        stack.dns_query(CONFIG.ntp_server, DnsQueryType::A),
+    )
+    .await
+    {
+        Ok(e) => e
+            .ok()
+            .and_then(|addrs| addrs.first().copied())
+            .ok_or(SntpError::DnsError)?,
+        Err(_) => {
+            return Err(SntpError::Timeout);
+        }
+    };
+    debug!("Requesting time from {} ({})", CONFIG.ntp_server, server);
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0x00u8; NTP_PACKET_LEN * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0x00u8; NTP_PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    //Any free local port
+    socket.bind(0).map_err(|_| SntpError::ConnectionError)?;
+
+    //SNTPv4 client request. The transmit timestamp is a random nonce, which the server
+    //echoes back as the originate timestamp - so stray or spoofed replies can be discarded
+    let mut nonce = [0x00u8; 8];
+    RoscRng.fill_bytes(&mut nonce);
+    let mut request = [0x00u8; NTP_PACKET_LEN];
+    request[0] = 0x23; //LI 0, version 4, mode 3 (client)
+    request[40..48].copy_from_slice(&nonce);
+
+    let sent = Instant::now();
+    socket
+        .send_to(&request, IpEndpoint::new(server, NTP_PORT))
+        .await
+        .map_err(|_| SntpError::ConnectionError)?;
+
+    let mut response = [0x00u8; NTP_PACKET_LEN];
+    let received = loop {
+        match embassy_time::with_timeout(CONFIG.http_timeout, socket.recv_from(&mut response))
+            .await
+        {
+            Ok(Ok((len, meta))) => {
+                if len == NTP_PACKET_LEN
+                    && meta.endpoint.addr == server
+                    && response[24..32] == nonce
+                {
+                    break Instant::now();
+                }
+                debug!("Discarding unexpected NTP packet");
+            }
+            Ok(Err(_)) => {
+                return Err(SntpError::ConnectionError);
+            }
+            Err(_) => {
+                return Err(SntpError::Timeout);
+            }
+        }
+    };
+
+    let mode = response[0] & 0x07;
+    let stratum = response[1];
+    if mode != 4 || stratum == 0 || stratum > 15 {
+        return Err(SntpError::InvalidResponse);
+    }
+
+    //Server receive and transmit times - the time the server held the request isn't network delay
+    let server_received = ntp_to_unix_micros(&response[32..40]);
+    let server_sent = ntp_to_unix_micros(&response[40..48]);
+    let round_trip = (received - sent).as_micros();
+    let delay = round_trip.saturating_sub(server_sent.saturating_sub(server_received));
+    let now = server_sent + delay / 2;
+
+    let previous = clock::unix_time();
+    clock::set_unix_time(now, received);
+    info!(
+        "Clock synced to unix time {} (previously {}), round trip {}ms",
+        now / 1_000_000,
+        previous,
+        round_trip / 1000
+    );
+    Ok(())
+}
+
+//Convert a 64 bit NTP timestamp (seconds since 1900, and a 32 bit fraction) to unix microseconds
+fn ntp_to_unix_micros(timestamp: &[u8]) -> u64 {
+    let seconds = u32::from_be_bytes([timestamp[0], timestamp[1], timestamp[2], timestamp[3]]);
+    let fraction = u32::from_be_bytes([timestamp[4], timestamp[5], timestamp[6], timestamp[7]]);
+    //NTP seconds wrap in 2036 - anything before 1970 must be in the following era
+    let seconds = match (seconds as u64).checked_sub(NTP_UNIX_OFFSET) {
+        Some(seconds) => seconds,
+        None => seconds as u64 + (1 << 32) - NTP_UNIX_OFFSET,
+    };
+    seconds * 1_000_000 + ((fraction as u64 * 1_000_000) >> 32)
+}
@@ -0,0 +1,52 @@
+use defmt::*;
+
+use embedded_tls::TlsError;
+
+use reqwless::client::TlsVerify;
+
+use crate::CONFIG;
+
+//Pre-shared key used to authenticate the backend (and this device to it). The backend must
+//be configured with the same identity and key - without one, the server isn't verified
+pub(crate) struct TlsPsk<'a> {
+    pub identity: &'a [u8],
+    pub key: &'a [u8],
+}
+
+//How the backend's identity is checked, for every TLS connection
+pub(crate) fn tls_verify() -> TlsVerify<'static> {
+    match &CONFIG.tls_psk {
+        Some(psk) => TlsVerify::Psk {
+            identity: psk.identity,
+            psk: psk.key,
+        },
+        None => TlsVerify::None,
+    }
+}
+
+//With a PSK configured, whether the server couldn't prove it holds the key - it rejected our
+//identity or key (alerting us), or we couldn't verify or decrypt its handshake. Any other
+//TLS error (e.g. the connection dropping mid-handshake) is an ordinary connection failure
+pub(crate) fn is_verification_failure(error: &reqwless::Error) -> bool {
+    CONFIG.tls_psk.is_some()
+        && matches!(
+            error,
+            reqwless::Error::Tls(
+                TlsError::HandshakeAborted(..)
+                    | TlsError::AbortHandshake(..)
+                    | TlsError::InvalidSignature
+                    | TlsError::CryptoError
+            )
+        )
+}
+
+//Warn at startup if the backend can be impersonated
+pub(crate) fn check_config() {
+    if !CONFIG.url_endpoint.starts_with("https://") {
+        warn!("Backend URL is not https - database and logs are unauthenticated");
+    } else if CONFIG.tls_psk.is_none() {
+        warn!("No TLS PSK configured - the backend server is not verified");
+    } else {
+        info!("Backend server verified by TLS PSK");
+    }
+}
@@ -0,0 +1,200 @@
+use core::cell::Cell;
+
+use cyw43::{Control, JoinOptions};
+
+use embassy_futures::select::{select, Either};
+use embassy_net::{Config as NetConfig, DhcpConfig, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_time::{Duration, Timer, WithTimeout};
+
+use defmt::{Format, *};
+
+use heapless::String;
+
+use crate::config::{WifiNetwork, CONFIG};
+use crate::provisioning::{self, Entry, PROVISIONING_SIGNAL};
+use crate::retry::{FailureKind, RetryPolicy, WIFI_RETRY_STATUS};
+
+//Connection manager - joins the first of the configured networks that's available, then
+//watches the link and the DHCP lease, rejoining (with backoff) whenever either is lost. Also
+//hands the radio over to provisioning mode when that's asked for, and takes it back if
+//provisioning mode is left idle.
+
+//How long a joined network has to give us an address before the next network is tried
+const DHCP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub(crate) enum WifiState {
+    Joining,
+    AwaitingDhcp, //Joined, waiting for an address
+    Connected,
+    Provisioning,
+}
+
+//Snapshot of the connection, for the other tasks
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WifiStatus {
+    pub state: WifiState,
+    pub ssid: Option<&'static str>, //The network joined, or being joined
+    pub drops: u32,                 //Times an established connection has been lost since boot
+}
+
+static WIFI_STATUS: BlockingMutex<CriticalSectionRawMutex, Cell<WifiStatus>> =
+    BlockingMutex::new(Cell::new(WifiStatus {
+        state: WifiState::Joining,
+        ssid: None,
+        drops: 0,
+    }));
+
+//The network stack's addressing - CONFIG.static_ipv4 if set, otherwise DHCP, asking for the
+//device name as our hostname so the controller shows up by name in the router
+pub(crate) fn stack_config() -> NetConfig {
+    if let Some(ip) = CONFIG.static_ipv4 {
+        let [a, b, c, d] = ip.address;
+        info!("Static address {}.{}.{}.{}/{}", a, b, c, d, ip.prefix_len);
+        return NetConfig::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::from(ip.address), ip.prefix_len),
+            gateway: ip.gateway.map(Ipv4Address::from),
+            dns_servers: ip
+                .dns_servers
+                .iter()
+                .map(|&server| Ipv4Address::from(server))
+                .collect(),
+        });
+    }
+    //Hostnames are letters, digits and '-' only
+    let mut hostname: String<32> = String::new();
+    for c in CONFIG.device_name.chars() {
+        let c = if c.is_ascii_alphanumeric() { c } else { '-' };
+        if hostname.push(c).is_err() {
+            break;
+        }
+    }
+    let mut dhcp = DhcpConfig::default();
+    dhcp.hostname = hostname.parse().ok();
+    NetConfig::dhcpv4(dhcp)
+}
+
+pub(crate) fn wifi_status() -> WifiStatus {
+    WIFI_STATUS.lock(|status| status.get())
+}
+
+fn set_state(state: WifiState, ssid: Option<&'static str>) {
+    WIFI_STATUS.lock(|status| {
+        status.set(WifiStatus {
+            state,
+            ssid,
+            ..status.get()
+        })
+    });
+}
+
+//Stays connected until provisioning mode is entered - straight away if provision is set
+#[embassy_executor::task]
+pub async fn wifi_task(
+    mut control: Control<'static>,
+    stack: Stack<'static>,
+    mut provision: Option<Entry>,
+) -> ! {
+    loop {
+        let entry = match provision.take() {
+            Some(entry) => entry,
+            None => {
+                //The admin card can be held on the reader at any time, connected or not
+                PROVISIONING_SIGNAL.reset();
+                let entry = match select(
+                    stay_connected(&mut control, stack),
+                    PROVISIONING_SIGNAL.wait(),
+                )
+                .await
+                {
+                    Either::First(()) => Entry::JoinFailed,
+                    Either::Second(()) => Entry::AdminCard,
+                };
+                control.leave().await;
+                entry
+            }
+        };
+        set_state(WifiState::Provisioning, None);
+        provisioning::run(&mut control, stack, entry).await;
+        //Left idle - back to the configured networks
+        stack.set_config_v4(stack_config().ipv4);
+        set_state(WifiState::Joining, None);
+    }
+}
+
+//Join and rejoin the configured networks. Only returns if none could be joined within
+//CONFIG.wifi_join_attempts passes - once connected, it keeps trying forever
+async fn stay_connected(control: &mut Control<'static>, stack: Stack<'static>) {
+    let mut retry = RetryPolicy::new(&CONFIG.wifi_retry, &WIFI_RETRY_STATUS);
+    let mut connected = false;
+    let mut failed_passes = 0u32;
+    loop {
+        if let Some(ssid) = join_any(control).await {
+            set_state(WifiState::AwaitingDhcp, Some(ssid));
+            match stack.wait_config_up().with_timeout(DHCP_TIMEOUT).await {
+                Ok(_) => {
+                    info!("Wifi ready - connected to {}", ssid);
+                    set_state(WifiState::Connected, Some(ssid));
+                    retry.success();
+                    connected = true;
+                    failed_passes = 0;
+
+                    select(stack.wait_link_down(), stack.wait_config_down()).await;
+                    warn!("Connection to {} lost - rejoining", ssid);
+                    WIFI_STATUS.lock(|status| {
+                        let mut dropped = status.get();
+                        dropped.drops += 1;
+                        status.set(dropped);
+                    });
+                    control.leave().await;
+                    //The network is usually back straight away, e.g. after roaming
+                    continue;
+                }
+                Err(_) => {
+                    warn!("No address from {} after {}s", ssid, DHCP_TIMEOUT.as_secs());
+                    control.leave().await;
+                }
+            }
+        }
+
+        failed_passes += 1;
+        if !connected && failed_passes == CONFIG.wifi_join_attempts {
+            error!(
+                "Unable to connect to any wifi network ({} attempts)",
+                failed_passes
+            );
+            return;
+        }
+        set_state(WifiState::Joining, None);
+        let delay = retry.failure(FailureKind::Other);
+        warn!(
+            "Unable to connect to any wifi network - retrying in {}s",
+            delay.as_secs()
+        );
+        Timer::after(delay).await;
+    }
+}
+
+//Try each configured network in turn, returning the one joined
+async fn join_any(control: &mut Control<'static>) -> Option<&'static str> {
+    for WifiNetwork { ssid, pw } in CONFIG.wifi_networks() {
+        set_state(WifiState::Joining, Some(ssid));
+        let options = if pw.is_empty() {
+            JoinOptions::new_open()
+        } else {
+            JoinOptions::new(pw.as_bytes())
+        };
+        match control.join(ssid, options).await {
+            Ok(_) => {
+                info!("WiFi network {} joined, configuring stack", ssid);
+                return Some(ssid);
+            }
+            Err(err) => {
+                warn!("Failed to join {}, status {}", ssid, err.status);
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,352 @@
+//Minimal DHCP server (RFC 2131) for the provisioning access point. The controller is the
+//only server on the network, so it just hands each client the next free address in its /24,
+//with itself as the router and DNS server.
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+//Clients served at once - a further client takes over the oldest lease
+pub const MAX_LEASES: usize = 8;
+//Replies are padded to the minimum BOOTP message length
+pub const DHCP_REPLY_LEN: usize = 300;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_START: usize = 240;
+//Clients get host addresses from .10 up
+const FIRST_HOST: u8 = 10;
+const LEASE_SECS: u32 = 60 * 60;
+
+//Message types (option 53)
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+//Options
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+type HardwareAddress = [u8; 6];
+
+pub struct DhcpServer {
+    address: [u8; 4], //The server's own address - clients are given addresses in its /24
+    leases: [Option<HardwareAddress>; MAX_LEASES],
+    next_evict: usize,
+}
+
+impl DhcpServer {
+    pub fn new(address: [u8; 4]) -> Self {
+        Self {
+            address,
+            leases: [None; MAX_LEASES],
+            next_evict: 0,
+        }
+    }
+
+    //Handle a message from a client, writing any reply into reply (at least DHCP_REPLY_LEN
+    //bytes long). Returns the reply length - the reply is broadcast to the client port
+    pub fn handle(&mut self, request: &[u8], reply: &mut [u8]) -> Option<usize> {
+        if request.len() < OPTIONS_START
+            || request[0] != 1 //BOOTREQUEST
+            || request[1] != 1 //Ethernet
+            || request[2] != 6
+            || request[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let mut client = [0x00u8; 6];
+        client.copy_from_slice(&request[28..34]);
+
+        let mut message_type = None;
+        let mut requested_ip = None;
+        let mut server_id = None;
+        let mut options = &request[OPTIONS_START..];
+        while let Some((&code, rest)) = options.split_first() {
+            match code {
+                OPTION_PAD => options = rest,
+                OPTION_END => break,
+                _ => {
+                    let (&len, rest) = rest.split_first()?;
+                    let value = rest.get(..len as usize)?;
+                    match (code, value) {
+                        (OPTION_MESSAGE_TYPE, &[value]) => message_type = Some(value),
+                        (OPTION_REQUESTED_IP, &[a, b, c, d]) => requested_ip = Some([a, b, c, d]),
+                        (OPTION_SERVER_ID, &[a, b, c, d]) => server_id = Some([a, b, c, d]),
+                        _ => {}
+                    }
+                    options = &rest[len as usize..];
+                }
+            }
+        }
+
+        let reply_type = match message_type? {
+            DISCOVER => OFFER,
+            REQUEST => {
+                //The client accepted another server's offer
+                if server_id.is_some_and(|id| id != self.address) {
+                    return None;
+                }
+                //Renewing clients send their address in ciaddr rather than option 50
+                let requested = requested_ip.or(match request[12..16] {
+                    [0, 0, 0, 0] => None,
+                    [a, b, c, d] => Some([a, b, c, d]),
+                    _ => None,
+                });
+                match (self.find(&client), requested) {
+                    (Some(index), Some(requested)) if requested != self.host(index) => NAK,
+                    (None, Some(_)) => NAK,
+                    _ => ACK,
+                }
+            }
+            RELEASE => {
+                if let Some(index) = self.find(&client) {
+                    self.leases[index] = None;
+                }
+                return None;
+            }
+            _ => return None,
+        };
+        let address = match reply_type {
+            NAK => [0x00; 4],
+            _ => {
+                let index = self.lease(client);
+                self.host(index)
+            }
+        };
+
+        let reply = reply.get_mut(..DHCP_REPLY_LEN)?;
+        reply.fill(0x00);
+        reply[0] = 2; //BOOTREPLY
+        reply[1] = 1;
+        reply[2] = 6;
+        reply[4..8].copy_from_slice(&request[4..8]); //Transaction ID
+        reply[10..12].copy_from_slice(&request[10..12]); //Flags
+        reply[16..20].copy_from_slice(&address); //yiaddr
+        reply[20..24].copy_from_slice(&self.address); //siaddr
+        reply[28..44].copy_from_slice(&request[28..44]); //chaddr
+        reply[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut len = OPTIONS_START;
+        let mut option = |code: u8, value: &[u8]| {
+            reply[len] = code;
+            reply[len + 1] = value.len() as u8;
+            reply[len + 2..len + 2 + value.len()].copy_from_slice(value);
+            len += 2 + value.len();
+        };
+        option(OPTION_MESSAGE_TYPE, &[reply_type]);
+        option(OPTION_SERVER_ID, &self.address);
+        if reply_type != NAK {
+            option(OPTION_LEASE_TIME, &LEASE_SECS.to_be_bytes());
+            option(OPTION_SUBNET_MASK, &[255, 255, 255, 0]);
+            option(OPTION_ROUTER, &self.address);
+            option(OPTION_DNS, &self.address);
+        }
+        reply[len] = OPTION_END;
+        Some(DHCP_REPLY_LEN)
+    }
+
+    fn find(&self, client: &HardwareAddress) -> Option<usize> {
+        self.leases
+            .iter()
+            .position(|lease| lease.as_ref() == Some(client))
+    }
+
+    //The client's lease, allocating one if it has none
+    fn lease(&mut self, client: HardwareAddress) -> usize {
+        if let Some(index) = self.find(&client) {
+            return index;
+        }
+        let index = match self.leases.iter().position(|lease| lease.is_none()) {
+            Some(index) => index,
+            None => {
+                let index = self.next_evict;
+                self.next_evict = (self.next_evict + 1) % MAX_LEASES;
+                index
+            }
+        };
+        self.leases[index] = Some(client);
+        index
+    }
+
+    fn host(&self, index: usize) -> [u8; 4] {
+        let [a, b, c, _] = self.address;
+        [a, b, c, FIRST_HOST + index as u8]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: [u8; 4] = [192, 168, 4, 1];
+
+    fn message(client: u8, message_type: u8, options: &[u8]) -> [u8; 300] {
+        let mut message = [0x00u8; 300];
+        message[0] = 1;
+        message[1] = 1;
+        message[2] = 6;
+        message[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        message[10] = 0x80; //Broadcast flag
+        message[28..34].copy_from_slice(&[0x02, 0, 0, 0, 0, client]);
+        message[236..240].copy_from_slice(&MAGIC_COOKIE);
+        message[240..243].copy_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+        message[243..243 + options.len()].copy_from_slice(options);
+        message[243 + options.len()] = OPTION_END;
+        message
+    }
+
+    //Send a message, returning the reply type and offered address
+    fn exchange(server: &mut DhcpServer, message: &[u8]) -> Option<(u8, [u8; 4])> {
+        let mut reply = [0x00u8; DHCP_REPLY_LEN];
+        let len = server.handle(message, &mut reply)?;
+        assert_eq!(len, DHCP_REPLY_LEN);
+        assert_eq!(reply[0], 2);
+        assert_eq!(reply[4..8], message[4..8]);
+        assert_eq!(reply[10..12], message[10..12]);
+        assert_eq!(reply[28..44], message[28..44]);
+        assert_eq!(reply[240..242], [OPTION_MESSAGE_TYPE, 1]);
+        Some((reply[242], reply[16..20].try_into().unwrap()))
+    }
+
+    #[test]
+    fn offers_and_acknowledges() {
+        let mut server = DhcpServer::new(SERVER);
+        assert_eq!(
+            exchange(&mut server, &message(1, DISCOVER, &[])),
+            Some((OFFER, [192, 168, 4, 10]))
+        );
+        let request = message(
+            1,
+            REQUEST,
+            &[
+                OPTION_REQUESTED_IP,
+                4,
+                192,
+                168,
+                4,
+                10,
+                OPTION_SERVER_ID,
+                4,
+                192,
+                168,
+                4,
+                1,
+            ],
+        );
+        assert_eq!(
+            exchange(&mut server, &request),
+            Some((ACK, [192, 168, 4, 10]))
+        );
+        //A second client gets the next address
+        assert_eq!(
+            exchange(&mut server, &message(2, DISCOVER, &[])),
+            Some((OFFER, [192, 168, 4, 11]))
+        );
+    }
+
+    #[test]
+    fn reply_carries_network_options() {
+        let mut server = DhcpServer::new(SERVER);
+        let mut reply = [0x00u8; DHCP_REPLY_LEN];
+        server
+            .handle(&message(1, DISCOVER, &[]), &mut reply)
+            .unwrap();
+        assert_eq!(reply[236..240], MAGIC_COOKIE);
+        let options = &reply[240..];
+        let find = |code: u8| {
+            options
+                .windows(6)
+                .find(|window| window[0] == code && window[1] == 4)
+                .map(|window| [window[2], window[3], window[4], window[5]])
+        };
+        assert_eq!(find(OPTION_SERVER_ID), Some(SERVER));
+        assert_eq!(find(OPTION_ROUTER), Some(SERVER));
+        assert_eq!(find(OPTION_DNS), Some(SERVER));
+        assert_eq!(find(OPTION_SUBNET_MASK), Some([255, 255, 255, 0]));
+    }
+
+    #[test]
+    fn ignores_requests_for_other_servers() {
+        let mut server = DhcpServer::new(SERVER);
+        let request = message(1, REQUEST, &[OPTION_SERVER_ID, 4, 192, 168, 4, 2]);
+        assert_eq!(exchange(&mut server, &request), None);
+    }
+
+    #[test]
+    fn refuses_unknown_addresses() {
+        let mut server = DhcpServer::new(SERVER);
+        //A client still holding an address from another network
+        let request = message(1, REQUEST, &[OPTION_REQUESTED_IP, 4, 10, 0, 0, 5]);
+        assert_eq!(exchange(&mut server, &request), Some((NAK, [0, 0, 0, 0])));
+
+        exchange(&mut server, &message(1, DISCOVER, &[])).unwrap();
+        let request = message(1, REQUEST, &[OPTION_REQUESTED_IP, 4, 192, 168, 4, 12]);
+        assert_eq!(exchange(&mut server, &request), Some((NAK, [0, 0, 0, 0])));
+    }
+
+    #[test]
+    fn reuses_leases() {
+        let mut server = DhcpServer::new(SERVER);
+        exchange(&mut server, &message(1, DISCOVER, &[])).unwrap();
+        exchange(&mut server, &message(2, DISCOVER, &[])).unwrap();
+        assert_eq!(
+            exchange(&mut server, &message(1, DISCOVER, &[])),
+            Some((OFFER, [192, 168, 4, 10]))
+        );
+        //A released address goes to the next new client
+        assert_eq!(exchange(&mut server, &message(1, RELEASE, &[])), None);
+        assert_eq!(
+            exchange(&mut server, &message(3, DISCOVER, &[])),
+            Some((OFFER, [192, 168, 4, 10]))
+        );
+    }
+
+    #[test]
+    fn evicts_oldest_lease_when_full() {
+        let mut server = DhcpServer::new(SERVER);
+        for client in 0..MAX_LEASES as u8 {
+            exchange(&mut server, &message(client, DISCOVER, &[])).unwrap();
+        }
+        assert_eq!(
+            exchange(&mut server, &message(100, DISCOVER, &[])),
+            Some((OFFER, [192, 168, 4, 10]))
+        );
+        assert_eq!(
+            exchange(&mut server, &message(101, DISCOVER, &[])),
+            Some((OFFER, [192, 168, 4, 11]))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        let mut server = DhcpServer::new(SERVER);
+        let mut reply = [0x00u8; DHCP_REPLY_LEN];
+        let discover = message(1, DISCOVER, &[]);
+        assert_eq!(server.handle(&discover[..239], &mut reply), None);
+
+        let mut reply_message = discover;
+        reply_message[0] = 2;
+        assert_eq!(server.handle(&reply_message, &mut reply), None);
+
+        let mut bad_cookie = discover;
+        bad_cookie[236] = 0;
+        assert_eq!(server.handle(&bad_cookie, &mut reply), None);
+
+        //Option running off the end of the message
+        let mut truncated = discover;
+        truncated[243..245].copy_from_slice(&[OPTION_REQUESTED_IP, 200]);
+        assert_eq!(server.handle(&truncated, &mut reply), None);
+
+        let mut no_type = discover;
+        no_type[240] = OPTION_END;
+        assert_eq!(server.handle(&no_type, &mut reply), None);
+    }
+}
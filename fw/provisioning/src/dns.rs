@@ -0,0 +1,135 @@
+//Captive portal DNS server - every A query is answered with the controller's own address, so
+//whatever a phone or laptop tries to open, it lands on the config page.
+
+pub const DNS_PORT: u16 = 53;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const TTL_SECS: u32 = 60;
+//Name pointer to the question, type, class, TTL, length and address
+const ANSWER_LEN: usize = 16;
+
+//Answer a query, writing the reply into reply. Returns the reply length, or None if the
+//message isn't a query we can answer (or reply is too small)
+pub fn dns_reply(query: &[u8], address: [u8; 4], reply: &mut [u8]) -> Option<usize> {
+    let header = query.get(..HEADER_LEN)?;
+    let is_response = header[2] & 0x80 != 0;
+    let opcode = (header[2] >> 3) & 0x0f;
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    if is_response || opcode != 0 || questions != 1 {
+        return None;
+    }
+
+    //Walk the question's name - a series of length prefixed labels, ending with a zero length
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        //Queries don't compress the question name
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(pos + 2)?, *query.get(pos + 3)?]);
+    let question_end = pos + 4;
+    let answer = qtype == TYPE_A && qclass == CLASS_IN;
+
+    let len = question_end + if answer { ANSWER_LEN } else { 0 };
+    let reply = reply.get_mut(..len)?;
+    //The reply repeats the header and question. Any additional records (e.g. EDNS) are dropped
+    reply[..question_end].copy_from_slice(&query[..question_end]);
+    reply[2] = 0x84 | (query[2] & 0x01); //Response, authoritative, recursion desired as asked
+    reply[3] = 0x80; //Recursion available, no error
+    reply[6..12].copy_from_slice(&[0, answer as u8, 0, 0, 0, 0]);
+    if answer {
+        let record = &mut reply[question_end..];
+        record[..2].copy_from_slice(&[0xc0, HEADER_LEN as u8]); //Pointer to the question name
+        record[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        record[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        record[6..10].copy_from_slice(&TTL_SECS.to_be_bytes());
+        record[10..12].copy_from_slice(&4u16.to_be_bytes());
+        record[12..16].copy_from_slice(&address);
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: [u8; 4] = [192, 168, 4, 1];
+
+    //Query for example.com, with the given type
+    fn query(qtype: u16) -> heapless::Vec<u8, 64> {
+        let mut query = heapless::Vec::new();
+        query
+            .extend_from_slice(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        query.extend_from_slice(b"\x07example\x03com\x00").unwrap();
+        query.extend_from_slice(&qtype.to_be_bytes()).unwrap();
+        query.extend_from_slice(&CLASS_IN.to_be_bytes()).unwrap();
+        query
+    }
+
+    #[test]
+    fn answers_a_queries() {
+        let query = query(TYPE_A);
+        let mut reply = [0x00u8; 512];
+        let len = dns_reply(&query, ADDRESS, &mut reply).unwrap();
+        assert_eq!(len, query.len() + ANSWER_LEN);
+        assert_eq!(reply[..2], [0x12, 0x34]);
+        assert_eq!(reply[2..4], [0x85, 0x80]);
+        assert_eq!(reply[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(reply[12..query.len()], query[12..]);
+        assert_eq!(reply[len - 4..len], ADDRESS);
+    }
+
+    #[test]
+    fn answers_other_queries_with_no_records() {
+        let query = query(28); //AAAA
+        let mut reply = [0x00u8; 512];
+        let len = dns_reply(&query, ADDRESS, &mut reply).unwrap();
+        assert_eq!(len, query.len());
+        assert_eq!(reply[6..8], [0, 0]);
+    }
+
+    #[test]
+    fn drops_additional_records() {
+        let mut query = query(TYPE_A);
+        query[11] = 1;
+        query
+            .extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        let mut reply = [0x00u8; 512];
+        let len = dns_reply(&query, ADDRESS, &mut reply).unwrap();
+        assert_eq!(len, query.len() - 11 + ANSWER_LEN);
+        assert_eq!(reply[10..12], [0, 0]);
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        let mut reply = [0x00u8; 512];
+        let mut response = query(TYPE_A);
+        response[2] |= 0x80;
+        assert_eq!(dns_reply(&response, ADDRESS, &mut reply), None);
+
+        let mut two_questions = query(TYPE_A);
+        two_questions[5] = 2;
+        assert_eq!(dns_reply(&two_questions, ADDRESS, &mut reply), None);
+
+        let query = query(TYPE_A);
+        assert_eq!(
+            dns_reply(&query[..query.len() - 1], ADDRESS, &mut reply),
+            None
+        );
+        assert_eq!(dns_reply(&query[..8], ADDRESS, &mut reply), None);
+        //Reply buffer too small
+        assert_eq!(dns_reply(&query, ADDRESS, &mut reply[..20]), None);
+    }
+}
@@ -0,0 +1,227 @@
+//Just enough HTTP/1.1 for the config page - a request line, a Content-Length header, and a
+//form encoded (application/x-www-form-urlencoded) body.
+
+use core::fmt;
+
+use heapless::{String, Vec};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Method {
+    Get,
+    Post,
+    Other,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: Method,
+    pub path: &'a str, //Without any query string
+    pub body: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HttpError {
+    Malformed,
+}
+
+//Parse a request from the bytes received so far - Ok(None) if the headers or body are
+//still incomplete
+pub fn parse_request(buf: &[u8]) -> Result<Option<Request<'_>>, HttpError> {
+    let header_len = match buf.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None => return Ok(None),
+    };
+    let head = core::str::from_utf8(&buf[..header_len]).map_err(|_| HttpError::Malformed)?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = match request_line.next() {
+        Some("GET") => Method::Get,
+        Some("POST") => Method::Post,
+        Some(_) => Method::Other,
+        None => return Err(HttpError::Malformed),
+    };
+    let target = request_line.next().ok_or(HttpError::Malformed)?;
+    if !request_line
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/"))
+    {
+        return Err(HttpError::Malformed);
+    }
+    let path = target.split('?').next().unwrap_or(target);
+
+    let mut content_length = 0usize;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| HttpError::Malformed)?;
+            }
+        }
+    }
+    let body = match buf[header_len..].get(..content_length) {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+    Ok(Some(Request { method, path, body }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FormError {
+    Malformed, //Invalid %-escape, or not UTF-8
+    TooLong,
+}
+
+//The name=value fields of a form encoded body, still encoded
+pub fn form_fields(body: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    body.split(|&byte| byte == b'&')
+        .filter(|field| !field.is_empty())
+        .map(|field| match field.iter().position(|&byte| byte == b'=') {
+            Some(pos) => (&field[..pos], &field[pos + 1..]),
+            None => (field, &field[field.len()..]),
+        })
+}
+
+//Decode a form value - '+' is a space, and %XX a byte
+pub fn decode_form_value<const N: usize>(value: &[u8]) -> Result<String<N>, FormError> {
+    let mut bytes: Vec<u8, N> = Vec::new();
+    let mut input = value.iter();
+    while let Some(&byte) = input.next() {
+        let byte = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let high = input.next().and_then(|&digit| hex_digit(digit));
+                let low = input.next().and_then(|&digit| hex_digit(digit));
+                match (high, low) {
+                    (Some(high), Some(low)) => high << 4 | low,
+                    _ => return Err(FormError::Malformed),
+                }
+            }
+            byte => byte,
+        };
+        bytes.push(byte).map_err(|_| FormError::TooLong)?;
+    }
+    let value = core::str::from_utf8(&bytes).map_err(|_| FormError::Malformed)?;
+    let mut string = String::new();
+    string.push_str(value).map_err(|_| FormError::TooLong)?;
+    Ok(string)
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+//Displays text escaped for HTML content and attribute values
+pub struct HtmlEscaped<'a>(pub &'a str);
+
+impl fmt::Display for HtmlEscaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => fmt::Write::write_char(f, c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn parses_get() {
+        let request = parse_request(b"GET /generate_204?x=1 HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(
+            request,
+            Ok(Some(Request {
+                method: Method::Get,
+                path: "/generate_204",
+                body: b"",
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_post_body() {
+        let raw = b"POST /save HTTP/1.1\r\ncontent-LENGTH: 9\r\n\r\nssid=abcdEXTRA";
+        let request = parse_request(raw).unwrap().unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/save");
+        assert_eq!(request.body, b"ssid=abcd");
+    }
+
+    #[test]
+    fn waits_for_complete_request() {
+        assert_eq!(parse_request(b"GET / HTTP/1.1\r\nHost: a\r\n"), Ok(None));
+        assert_eq!(
+            parse_request(b"POST /save HTTP/1.1\r\nContent-Length: 10\r\n\r\nssid="),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(parse_request(b"GET /\r\n\r\n"), Err(HttpError::Malformed));
+        assert_eq!(
+            parse_request(b"GET / FTP\r\n\r\n"),
+            Err(HttpError::Malformed)
+        );
+        assert_eq!(
+            parse_request(b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n"),
+            Err(HttpError::Malformed)
+        );
+        assert_eq!(
+            parse_request(b"GET /\xff HTTP/1.1\r\n\r\n"),
+            Err(HttpError::Malformed)
+        );
+        assert_eq!(
+            parse_request(b"PUT / HTTP/1.1\r\n\r\n").map(|r| r.map(|r| r.method)),
+            Ok(Some(Method::Other))
+        );
+    }
+
+    #[test]
+    fn splits_form_fields() {
+        let mut fields = form_fields(b"ssid=My+Net&wifi_pw=&open&&device_name=lathe");
+        assert_eq!(fields.next(), Some((&b"ssid"[..], &b"My+Net"[..])));
+        assert_eq!(fields.next(), Some((&b"wifi_pw"[..], &b""[..])));
+        assert_eq!(fields.next(), Some((&b"open"[..], &b""[..])));
+        assert_eq!(fields.next(), Some((&b"device_name"[..], &b"lathe"[..])));
+        assert_eq!(fields.next(), None);
+    }
+
+    #[test]
+    fn decodes_form_values() {
+        assert_eq!(
+            decode_form_value::<32>(b"My+Net%21%c3%a9").as_deref(),
+            Ok("My Net!\u{e9}")
+        );
+        assert_eq!(
+            decode_form_value::<32>(b"https%3A%2F%2Fexample.org").as_deref(),
+            Ok("https://example.org")
+        );
+        assert_eq!(decode_form_value::<32>(b"%4"), Err(FormError::Malformed));
+        assert_eq!(decode_form_value::<32>(b"%zz"), Err(FormError::Malformed));
+        assert_eq!(decode_form_value::<32>(b"%ff"), Err(FormError::Malformed));
+        assert_eq!(decode_form_value::<4>(b"abcde"), Err(FormError::TooLong));
+    }
+
+    #[test]
+    fn escapes_html() {
+        let mut out: String<64> = String::new();
+        write!(out, "{}", HtmlEscaped("<a href=\"x\">Tom & Jerry's</a>")).unwrap();
+        assert_eq!(
+            out,
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,17 @@
+#![no_std]
+
+//Protocols served by the controller's wifi provisioning mode - a DHCP server and a DNS
+//server that points every name at the controller (so phones show the config page as a
+//captive portal), and the HTTP parsing for the config page itself.
+//Nothing in here touches hardware, so `cargo test` runs it on the host.
+
+mod dhcp;
+mod dns;
+mod http;
+
+pub use dhcp::{DhcpServer, DHCP_CLIENT_PORT, DHCP_REPLY_LEN, DHCP_SERVER_PORT, MAX_LEASES};
+pub use dns::{dns_reply, DNS_PORT};
+pub use http::{
+    decode_form_value, form_fields, parse_request, FormError, HtmlEscaped, HttpError, Method,
+    Request,
+};
@@ -18,7 +18,9 @@ use embassy_rp::{
     uart::{Async, Config as UartConfig, InterruptHandler, Uart, UartRx},
     watchdog::Watchdog,
 };
-use embassy_time::{Delay, Duration, Timer};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Delay, Duration, Timer};
 
 use assign_resources::assign_resources;
 use embedded_hal_bus::spi::ExclusiveDevice;
@@ -82,10 +84,27 @@ pub async fn watchdog_task(r: WatchdogResources) -> ! {
     }
 }
 
+//Messages from the main unit, for led_task
+static LED_SIGNAL: Signal<ThreadModeRawMutex, MainMessage> = Signal::new();
+//How often the LEDs toggle while warning that a session is about to end
+const WARNING_FLASH_PERIOD: Duration = Duration::from_millis(250);
+//...and while waiting for the second card in two-person mode
+const AWAITING_SECOND_FLASH_PERIOD: Duration = Duration::from_millis(500);
+
 #[embassy_executor::task]
-async fn led_task(
-    mut uart_rx: UartRx<'static, UART0, Async>, leds: StatusLedResources
-) -> ! {
+async fn message_task(mut uart_rx: UartRx<'static, UART0, Async>) -> ! {
+    loop {
+        match read_message(&mut uart_rx).await {
+            Ok(message) => LED_SIGNAL.signal(message),
+            Err(e) => {
+                error!("Message task encountered UART read error: {}", e);
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn led_task(leds: StatusLedResources) -> ! {
 
     let mut green_led = Output::new(leds.green_led, Level::High);
     let mut red_led = Output::new(leds.red_led, Level::High);
@@ -98,22 +117,49 @@ async fn led_task(
     red_led.toggle();
 
     //This function 'owns' the two IOs as externally mounted red/green LEDs (LEDs connected between 3v3 and the GPIO, so low->on)
+    //Flash period while flashing, and whether the red LED flashes too
+    let mut flashing = None;
+    let mut flash_red = false;
     loop {
-        match read_message(&mut uart_rx).await {
-            Ok(message) => match message {
-                AccessGranted => {
-                    green_led.set_low();
-                }
-                AccessDenied => {
-                    red_led.set_low();
-                }
-                AwaitingCard => {
-                    green_led.set_high();
-                    red_led.set_high();
+        let message = if let Some(period) = flashing {
+            match with_timeout(period, LED_SIGNAL.wait()).await {
+                Ok(message) => message,
+                Err(_) => {
+                    green_led.toggle();
+                    if flash_red {
+                        red_led.toggle();
+                    }
+                    continue;
                 }
-            },
-            Err(e) => {
-                error!("LED task encountered UART read error: {}", e);
+            }
+        } else {
+            LED_SIGNAL.wait().await
+        };
+        flashing = None;
+        match message {
+            AccessGranted => {
+                green_led.set_low();
+                red_led.set_high();
+            }
+            AccessDenied => {
+                green_led.set_high();
+                red_led.set_low();
+            }
+            AwaitingCard => {
+                green_led.set_high();
+                red_led.set_high();
+            }
+            SessionExpiring => {
+                green_led.set_low();
+                red_led.set_low();
+                flashing = Some(WARNING_FLASH_PERIOD);
+                flash_red = true;
+            }
+            AwaitingSecondCard => {
+                green_led.set_low();
+                red_led.set_high();
+                flashing = Some(AWAITING_SECOND_FLASH_PERIOD);
+                flash_red = false;
             }
         }
     }
@@ -167,8 +213,10 @@ async fn main(spawner: Spawner) -> ! {
     //Split the UART
     let (mut uart_tx, uart_rx) = uart.split();
 
-    //Spawn the status LED task, which owns the two GPIO ACC pins and the Rx half of the UART
-    spawner.must_spawn(led_task(uart_rx, resources.status_leds));
+    //Spawn the status LED task, which owns the two GPIO ACC pins, and the task that passes it
+    //messages from the Rx half of the UART
+    spawner.must_spawn(led_task(resources.status_leds));
+    spawner.must_spawn(message_task(uart_rx));
 
     //This could be better - the newer embassy-rp watchdog is able to tell us if the reset is watchdog-origi
     debug!("Sending JustReset to controller");
@@ -17,9 +17,11 @@ pub enum RemoteMessage {
 
 //Messages from main -> remote unit
 //Main purpose of these is to allow the remote unit to show a status LED to the outside user
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 pub enum MainMessage {
-    AccessGranted, //Put green LED on
-    AccessDenied,  //Put red LED on
-    AwaitingCard,  //No LED on, awating read
+    AccessGranted,      //Put green LED on
+    AccessDenied,       //Put red LED on
+    AwaitingCard,       //No LED on, awating read
+    SessionExpiring,    //Flash the LEDs - the session is about to be ended
+    AwaitingSecondCard, //Flash the green LED - two-person mode, waiting for the second card
 }